@@ -0,0 +1,64 @@
+use std::{fmt::Write as _, fs, path::Path, time::Duration};
+
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion, Throughput};
+use paygine::Engine;
+use tempfile::TempDir;
+
+/// Rows of every generated input.
+const ROWS: u64 = 200_000;
+
+/// Clients the generated records are spread over.
+const CLIENTS: u64 = 5_000;
+
+/// Writes `ROWS` records to `path`: only deposits, or with `disputes` 40% deposits followed by
+/// dispute and resolve pairs of earlier deposits.
+fn generate(path: &Path, disputes: bool) {
+    let mut csv = String::from("type,client,tx,amount\n");
+    let deposits = if disputes { ROWS * 2 / 5 } else { ROWS };
+    for tx in 1..=deposits {
+        let cents = tx * 7919 % 100_000;
+        writeln!(
+            csv,
+            "deposit,{},{tx},{}.{:02}",
+            tx % CLIENTS,
+            cents / 100,
+            cents % 100
+        )
+        .unwrap();
+    }
+    // Disputes and resolves cycle through the deposits, so some are disputed again after a resolve.
+    for i in 0..(ROWS - deposits) / 2 {
+        let tx = i * 2_654_435_761 % deposits + 1;
+        let client = tx % CLIENTS;
+        writeln!(csv, "dispute,{client},{tx},").unwrap();
+        writeln!(csv, "resolve,{client},{tx},").unwrap();
+    }
+    fs::write(path, csv).unwrap();
+}
+
+fn process(c: &mut Criterion) {
+    let dir = TempDir::new().unwrap();
+    let mut group = c.benchmark_group("process");
+    group.throughput(Throughput::Elements(ROWS));
+    group
+        .sample_size(20)
+        .measurement_time(Duration::from_secs(10));
+    for (name, disputes) in [("deposits", false), ("disputes", true)] {
+        let path = dir.path().join(format!("{name}.csv"));
+        generate(&path, disputes);
+        group.bench_function(name, |b| {
+            b.iter_batched(
+                || Engine::builder().input(&path).build().unwrap(),
+                |mut engine| {
+                    engine.process().unwrap();
+                    engine
+                },
+                BatchSize::LargeInput,
+            )
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, process);
+criterion_main!(benches);
@@ -0,0 +1,80 @@
+//! Applies a CSV file read through `tokio::fs::File` with `Engine::process_stream`, printing
+//! every balance update, and checks the result against the sync engine.
+//!
+//! Run with `cargo run --example tokio_stream --features tokio [input.csv]`, a small generated
+//! input is used without an argument.
+
+use std::{env, path::PathBuf};
+
+use anyhow::{anyhow, Result};
+use csv_async::{AsyncReaderBuilder, Trim};
+use futures_util::StreamExt;
+use paygine::{money::Money, Engine, Transaction};
+use tempfile::TempDir;
+
+const SAMPLE: &str = "type,client,tx,amount
+deposit,1,1,10.0
+deposit,2,2,5.5
+withdrawal,1,3,2.25
+dispute,1,1,
+resolve,1,1,
+dispute,2,2,
+chargeback,2,2,
+deposit,3,4,1.0001
+";
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() -> Result<()> {
+    let dir = TempDir::new()?;
+    let path = match env::args_os().nth(1) {
+        Some(path) => PathBuf::from(path),
+        None => {
+            let path = dir.path().join("sample.csv");
+            tokio::fs::write(&path, SAMPLE).await?;
+            path
+        }
+    };
+
+    let file = tokio::fs::File::open(&path).await?;
+    let mut reader = AsyncReaderBuilder::new()
+        .trim(Trim::All)
+        .create_deserializer(file);
+    // A record which doesn't parse fails the example, the engine only sees transactions.
+    let transactions = reader
+        .deserialize::<Transaction>()
+        .map(|record| record.expect("invalid record"));
+    let mut engine = Engine::builder().build()?;
+    let mut updates = engine.process_stream(Box::pin(transactions));
+    while let Some(update) = updates.next().await {
+        let update = update?;
+        println!(
+            "line {}: client #{} available {} held {} locked {}",
+            update.line,
+            update.client,
+            update.balances.available,
+            update.balances.held,
+            update.balances.locked
+        );
+    }
+    drop(updates);
+
+    let mut sync = Engine::builder().input(&path).build()?;
+    sync.process()?;
+    if engine.clients().len() != sync.clients().len() {
+        return Err(anyhow!(
+            "{} clients from the stream, {} from the sync engine",
+            engine.clients().len(),
+            sync.clients().len()
+        ));
+    }
+    for (id, client) in sync.clients() {
+        let streamed = engine.clients().get(id);
+        if !streamed.is_some_and(|streamed| streamed.approx_eq(client, Money::ZERO)) {
+            return Err(anyhow!(
+                "client #{id}: {streamed:?} from the stream, {client:?} from the sync engine"
+            ));
+        }
+    }
+    println!("{} clients match the sync engine", sync.clients().len());
+    Ok(())
+}
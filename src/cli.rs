@@ -0,0 +1,712 @@
+use std::{
+    env::args_os,
+    fmt::Display,
+    fs,
+    path::{Path, PathBuf},
+    process,
+    str::FromStr,
+};
+
+use anyhow::{anyhow, Context, Error, Result};
+use clap::{error::ErrorKind, CommandFactory, FromArgMatches, Parser};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+
+use crate::logging::LogFormat;
+use paygine::{
+    config::{
+        self, AccountingModel, Config, DuplicateDispute, EarlySettlement, InputFormat,
+        LockedPolicy, ResolveHeld, TotalMismatch, TrimMode, Truncation, TxIdScope,
+    },
+    engine::{is_gzip, STDIN},
+    money::{Money, MAX_PRECISION},
+    output::{HeldNegative, OutputCompression, OutputFormat, Rounding},
+    transaction::{HeaderAliases, TypeMap},
+};
+
+/// Command line options, optionally preloaded from a `--config` TOML file.
+///
+/// Engine options are flattened into the same table, so a config file reads like the flags
+/// without their leading dashes.
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct Args {
+    #[serde(flatten)]
+    pub engine: Config,
+    pub held_detail: Option<PathBuf>,
+    pub rejects_replay: Option<PathBuf>,
+    pub rejects: Option<PathBuf>,
+    pub events: Option<PathBuf>,
+    pub check: bool,
+    pub anomalies: Option<PathBuf>,
+    pub dispute_ratio_threshold: Option<Decimal>,
+    pub group_by_locked: Option<PathBuf>,
+    pub quiet: bool,
+    pub log_format: LogFormat,
+    #[serde(deserialize_with = "config::one_or_many")]
+    pub output: Vec<PathBuf>,
+    pub output_compress: Option<OutputCompression>,
+    #[serde(alias = "output-format")]
+    pub format: OutputFormat,
+    pub byte_range: Option<ByteRange>,
+    pub jobs: Option<usize>,
+    pub verify_against: Option<PathBuf>,
+    pub max_output_bytes: Option<u64>,
+    pub rounding_report: bool,
+    pub convert: Option<Decimal>,
+    pub allow_empty_result: bool,
+    pub redact_clients: Vec<u16>,
+    pub balance_extremes: bool,
+    pub stats: bool,
+    pub report_open_disputes: bool,
+    pub fix_output_invariant: bool,
+    pub clamp_negative_output: bool,
+    pub json_numbers_as_strings: bool,
+    pub batch_report: Option<PathBuf>,
+    /// Where to write the JSON totals of the run.
+    pub summary: Option<PathBuf>,
+    pub fail_on_locked: bool,
+    pub held_negative: HeldNegative,
+    pub rounding: Rounding,
+    pub fsync: bool,
+    pub precision: Option<u32>,
+    /// Where to write the output columns as JSON, `-` prints them instead of processing.
+    pub schema: Option<PathBuf>,
+    /// Where to save the engine state after processing, see `Engine::save_state`.
+    pub save_state: Option<PathBuf>,
+    /// Where to write the seed and config hash of the run, not part of the hash itself.
+    #[serde(skip_serializing)]
+    pub manifest: Option<PathBuf>,
+}
+
+impl Args {
+    /// FNV-1a hash of the effective options, stable across runs and builds.
+    pub fn config_hash(&self) -> Result<u64> {
+        let bytes = serde_json::to_vec(self)?;
+        Ok(bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &byte| {
+            (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+        }))
+    }
+}
+
+/// `START..END` byte offsets of the input to process.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(try_from = "String")]
+pub struct ByteRange {
+    pub start: u64,
+    pub end: u64,
+}
+
+impl FromStr for ByteRange {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (start, end) = s
+            .split_once("..")
+            .ok_or_else(|| anyhow!("expected START..END"))?;
+        Ok(Self {
+            start: start.parse()?,
+            end: end.parse()?,
+        })
+    }
+}
+
+impl TryFrom<String> for ByteRange {
+    type Error = Error;
+
+    fn try_from(s: String) -> Result<Self> {
+        s.parse()
+    }
+}
+
+/// Source of the `--help` epilogue, so the commands are documented in one place.
+const README: &str = include_str!("../README.md");
+
+/// Command line flags, each overriding the same option of the `--config` file.
+#[derive(Debug, Parser)]
+#[command(
+    name = "paygine",
+    version,
+    about = "Applies CSV transactions and prints the resulting client balances.",
+    override_usage = "paygine [OPTIONS] [INPUT]...\n       paygine <COMMAND> [ARGS]..."
+)]
+struct Cli {
+    /// CSV inputs, processed in order as a single stream. Stdin if none is given.
+    #[arg(value_name = "INPUT")]
+    inputs: Vec<PathBuf>,
+    /// Read options from a TOML file, keyed by flag name without the dashes.
+    #[arg(long, value_name = "PATH")]
+    config: Option<PathBuf>,
+    /// Translate custom `type` tokens into built-in kinds, e.g. `cr=deposit, dr=withdrawal`.
+    #[arg(long, value_name = "FILE")]
+    type_map: Option<PathBuf>,
+    /// Deduct a fixed fee from available funds on every successful chargeback.
+    #[arg(long, value_name = "AMOUNT")]
+    chargeback_fee: Option<Money>,
+    /// Start from client balances in the output format.
+    #[arg(long, value_name = "PATH")]
+    opening_balances: Option<PathBuf>,
+    /// Resume from a state saved with `--save-state`.
+    #[arg(long, value_name = "PATH")]
+    load_state: Option<PathBuf>,
+    /// Save the state after every `N` applied records.
+    #[arg(long, value_name = "N")]
+    snapshot_every: Option<u64>,
+    /// Directory of the `--snapshot-every` states.
+    #[arg(long, value_name = "DIR")]
+    snapshot_dir: Option<PathBuf>,
+    /// Keep only the txs of the last `N` ids in saved states.
+    #[arg(long, value_name = "N")]
+    state_retention: Option<u32>,
+    /// Handling of imported rows where `available + held != total`.
+    #[arg(long, value_name = "reject|recompute|warn")]
+    on_total_mismatch: Option<TotalMismatch>,
+    /// Treat client id `0` as invalid and skip its records.
+    #[arg(long)]
+    reject_client_zero: bool,
+    /// Treat tx id `0` as invalid and skip deposits, withdrawals and transfers using it.
+    #[arg(long)]
+    reject_tx_zero: bool,
+    /// Client ids which never hold an account, e.g. `0,65535`.
+    #[arg(long, value_name = "IDS", value_delimiter = ',')]
+    reserved_clients: Vec<u16>,
+    /// Fail on records with an empty or unknown `type` instead of skipping them.
+    #[arg(long)]
+    strict_kinds: bool,
+    /// Read dispute, resolve and chargeback records from a separate file.
+    #[arg(long, value_name = "PATH")]
+    controls: Option<PathBuf>,
+    /// Fail on dispute lifecycle violations.
+    #[arg(long)]
+    strict_protocol: bool,
+    /// Let an `unfreeze` record unlock a client.
+    #[arg(long)]
+    allow_unfreeze: bool,
+    /// What becomes of the records of a locked client.
+    #[arg(long, value_name = "reject|queue")]
+    locked_policy: Option<LockedPolicy>,
+    /// What becomes of a resolve or chargeback of a tx which isn't known yet.
+    #[arg(long, value_name = "defer|reject")]
+    early_settlement: Option<EarlySettlement>,
+    /// Process each tx with probability `P`.
+    #[arg(long, value_name = "P")]
+    sample_rate: Option<f64>,
+    /// Seed of `--sample-rate`.
+    #[arg(long, value_name = "N")]
+    seed: Option<u64>,
+    /// Handling of a final record with missing fields.
+    #[arg(long, value_name = "warn|error")]
+    on_truncation: Option<Truncation>,
+    /// Handling of a resolve larger than the held funds.
+    #[arg(
+        long = "dispute-requires-sufficient-held",
+        value_name = "strict|partial|force"
+    )]
+    resolve_held: Option<ResolveHeld>,
+    /// What a client's total is made of.
+    #[arg(long, value_name = "standard|alt")]
+    accounting_model: Option<AccountingModel>,
+    /// Handling of a dispute of a tx which is already disputed.
+    #[arg(long, value_name = "reject|accumulate")]
+    duplicate_dispute: Option<DuplicateDispute>,
+    /// Whether tx ids are unique across all clients or only within a client.
+    #[arg(long, value_name = "global|per-client")]
+    tx_id_scope: Option<TxIdScope>,
+    /// Scope tx ids further by the value of an input column.
+    #[arg(long, value_name = "COLUMN")]
+    tx_id_epoch: Option<String>,
+    /// Keep only the `N` most recent deposits and withdrawals in memory.
+    #[arg(long, value_name = "N")]
+    tx_cache_size: Option<usize>,
+    /// Reject records of new clients once `N` clients exist.
+    #[arg(long, value_name = "N")]
+    max_clients: Option<usize>,
+    /// Reject a new dispute of a client which has `N` disputes open.
+    #[arg(long = "max-open-disputes-per-client", value_name = "N")]
+    max_open_disputes: Option<usize>,
+    /// Reject a dispute which would hold more than `PCT` percent of its tx.
+    #[arg(long, value_name = "PCT")]
+    max_dispute_pct: Option<u32>,
+    /// Whitespace trimming of CSV headers and fields.
+    #[arg(long, value_name = "none|headers|fields|all")]
+    trim: Option<TrimMode>,
+    /// Print a failing record with the `N` CSV records around it.
+    #[arg(long, value_name = "N")]
+    first_error_context: Option<usize>,
+    /// Read the inputs twice, first indexing every deposit and withdrawal.
+    #[arg(long)]
+    two_pass: bool,
+    /// Go on past records which would fail the run, skipping them.
+    #[arg(long)]
+    keep_going: bool,
+    /// Check the clients of every record after it is processed.
+    #[arg(long)]
+    paranoid: bool,
+    /// Write a CSV row for every client an applied record changed.
+    #[arg(long, value_name = "PATH")]
+    audit: Option<PathBuf>,
+    /// Draw the rows read and rows per second of each CSV input on stderr.
+    #[arg(long)]
+    progress: bool,
+    /// The CSV inputs have no header row.
+    #[arg(long)]
+    no_header: bool,
+    /// Field separator of the CSV inputs.
+    #[arg(long, value_name = "CHAR")]
+    delimiter: Option<char>,
+    /// Read input columns under other names, e.g. `kind=type`.
+    #[arg(long, value_name = "ALIAS=FIELD,...")]
+    header_aliases: Option<HeaderAliases>,
+    /// Drop the first `N` columns of the CSV input.
+    #[arg(long, value_name = "N")]
+    skip_columns: Option<usize>,
+    /// Size of the blocks CSV inputs are read in.
+    #[arg(long, value_name = "BYTES")]
+    read_buffer: Option<usize>,
+    /// Process the files listed in `PATH`, one per line.
+    #[arg(long, value_name = "PATH")]
+    file_list: Option<PathBuf>,
+    /// Register the deposits and withdrawals of `PATH` as disputable.
+    #[arg(long, value_name = "PATH")]
+    dispute_amounts: Option<PathBuf>,
+    /// Skip any operation which would leave available funds below `AMOUNT`.
+    #[arg(long, value_name = "AMOUNT")]
+    balance_floor: Option<Money>,
+    /// Skip any operation which would leave available funds above `AMOUNT`.
+    #[arg(long, value_name = "AMOUNT")]
+    balance_ceiling: Option<Money>,
+    /// Skip a deposit, withdrawal or transfer of more than `AMOUNT`.
+    #[arg(long, value_name = "AMOUNT")]
+    max_amount: Option<Money>,
+    /// Read a deposit with a negative amount as a withdrawal.
+    #[arg(long)]
+    negative_deposit_as_withdrawal: bool,
+    /// Buffer the whole input and apply it client by client.
+    #[arg(long)]
+    group_by_client: bool,
+    /// Read the input as CSV or as a single JSON array of objects.
+    #[arg(long, value_name = "csv|json-array")]
+    input_format: Option<InputFormat>,
+    /// Read the records from the rows of a Postgres query.
+    #[arg(long, value_name = "CONNECTION")]
+    postgres: Option<String>,
+    /// Query of `--postgres`.
+    #[arg(long, value_name = "SQL")]
+    query: Option<String>,
+    /// Write each open dispute at the end of the run.
+    #[arg(long, value_name = "PATH")]
+    held_detail: Option<PathBuf>,
+    /// Additionally write `active.csv` and `locked.csv` to `DIR`.
+    #[arg(long, value_name = "DIR")]
+    group_by_locked: Option<PathBuf>,
+    /// Write per-batch record counts and volumes.
+    #[arg(long, value_name = "PATH")]
+    batch_report: Option<PathBuf>,
+    /// Write the totals of the run as one JSON object.
+    #[arg(long, value_name = "PATH")]
+    summary: Option<PathBuf>,
+    /// Write rejected rows as `line,reason,record`.
+    #[arg(long, value_name = "PATH")]
+    rejects: Option<PathBuf>,
+    /// Write clients whose disputes per deposit exceed `--dispute-ratio-threshold`.
+    #[arg(long, value_name = "PATH")]
+    anomalies: Option<PathBuf>,
+    /// Disputes per deposit above which `--anomalies` lists a client.
+    #[arg(long, value_name = "RATIO")]
+    dispute_ratio_threshold: Option<Decimal>,
+    /// Validate the input without writing any output.
+    #[arg(long)]
+    check: bool,
+    /// Write rejects, warnings and lifecycle events as JSON lines.
+    #[arg(long, value_name = "PATH")]
+    events: Option<PathBuf>,
+    /// Write rejected rows in the input schema.
+    #[arg(long, value_name = "PATH")]
+    rejects_replay: Option<PathBuf>,
+    /// Write client rows to a file instead of stdout. Can be repeated.
+    #[arg(long, value_name = "PATH")]
+    output: Vec<PathBuf>,
+    /// Format of outputs without a known extension.
+    #[arg(long, alias = "output-format", value_name = "csv|json|parquet")]
+    format: Option<OutputFormat>,
+    /// Accepted for older scripts, clients are always written in ascending id order.
+    #[arg(long)]
+    #[allow(dead_code)]
+    sort_output: bool,
+    /// Compress the `--output` file.
+    #[arg(long, value_name = "gz")]
+    output_compress: Option<OutputCompression>,
+    /// Process only records starting within the given byte range.
+    #[arg(long, value_name = "START..END")]
+    byte_range: Option<ByteRange>,
+    /// Apply records on `N` threads.
+    #[arg(long, value_name = "N")]
+    jobs: Option<usize>,
+    /// Compare the result with an expected output file.
+    #[arg(long, value_name = "PATH")]
+    verify_against: Option<PathBuf>,
+    /// Split the `--output` file into parts of at most `B` bytes.
+    #[arg(long, value_name = "B")]
+    max_output_bytes: Option<u64>,
+    /// Print the value discarded by output rounding.
+    #[arg(long)]
+    rounding_report: bool,
+    /// Multiply every output balance by `RATE`.
+    #[arg(long, value_name = "RATE")]
+    convert: Option<Decimal>,
+    /// Accept a run where records were read but none could be applied.
+    #[arg(long)]
+    allow_empty_result: bool,
+    /// Leave a client out of the output. Can be repeated.
+    #[arg(long = "redact-client", value_name = "ID")]
+    redact_clients: Vec<u16>,
+    /// Add `available_min` and `available_max` columns.
+    #[arg(long)]
+    balance_extremes: bool,
+    /// Add an `open_disputes` column.
+    #[arg(long)]
+    stats: bool,
+    /// Add the `open_disputes` and `disputed_held` columns.
+    #[arg(long)]
+    report_open_disputes: bool,
+    /// Force the output `total` to `available + held`.
+    #[arg(long)]
+    fix_output_invariant: bool,
+    /// Show negative balances of locked clients as zero.
+    #[arg(long)]
+    clamp_negative_output: bool,
+    /// Quote the balances of JSON outputs.
+    #[arg(long)]
+    json_numbers_as_strings: bool,
+    /// Exit with an error if any client is locked at the end.
+    #[arg(long)]
+    fail_on_locked: bool,
+    /// Presentation of a negative held balance.
+    #[arg(long, value_name = "show|clamp|error")]
+    held_negative: Option<HeldNegative>,
+    /// How output balances are rounded to `--precision`.
+    #[arg(long, value_name = "truncate|half-up|half-even")]
+    rounding: Option<Rounding>,
+    /// Save the engine state after processing.
+    #[arg(long, value_name = "PATH")]
+    save_state: Option<PathBuf>,
+    /// Write the seed and config hash of the run.
+    #[arg(long, value_name = "PATH")]
+    manifest: Option<PathBuf>,
+    /// Sync every `--output` file to disk before exiting.
+    #[arg(long)]
+    fsync: bool,
+    /// Decimal places of output balances.
+    #[arg(long, value_name = "N")]
+    precision: Option<u32>,
+    /// Write the output columns as JSON, `-` prints them instead of processing.
+    #[arg(long, value_name = "PATH")]
+    schema: Option<PathBuf>,
+    /// Don't print the end-of-run summary line.
+    #[arg(long)]
+    quiet: bool,
+    /// Format of the log on stderr.
+    #[arg(long, value_name = "human|json")]
+    log_format: Option<LogFormat>,
+}
+
+/// Parses the command line on top of the `--config` file, if any.
+///
+/// Usage errors, including invalid combinations of options, print the usage and exit.
+pub fn parse_args() -> Result<Args> {
+    let mut command = Cli::command().after_help(epilogue());
+    let cli = command
+        .try_get_matches_from_mut(args_os())
+        .and_then(|matches| Cli::from_arg_matches(&matches))
+        .unwrap_or_else(|e| exit(e));
+    let mut parsed = match &cli.config {
+        Some(path) => {
+            let content = fs::read_to_string(path)
+                .with_context(|| format!("can't read config {}", path.display()))?;
+            toml::from_str(&content)
+                .with_context(|| format!("invalid config {}", path.display()))?
+        }
+        None => Args::default(),
+    };
+    cli.apply(&mut parsed)?;
+    if let Err(message) = validate(&parsed) {
+        exit(command.error(ErrorKind::ArgumentConflict, message));
+    }
+
+    parsed.engine.record_events = parsed.events.is_some() || parsed.check;
+    parsed.engine.check = parsed.check;
+    if parsed.engine.input.is_empty()
+        && parsed.engine.file_list.is_none()
+        && parsed.engine.postgres.is_none()
+    {
+        info!("No input given, reading from stdin");
+        parsed.engine.input.push(PathBuf::from(STDIN));
+    }
+    Ok(parsed)
+}
+
+/// Prints a clap error or the help it carries, exiting with `1` as `2` means rejected records.
+fn exit(error: clap::Error) -> ! {
+    let _ = error.print();
+    process::exit(if error.use_stderr() { 1 } else { 0 })
+}
+
+/// Overrides the option with the flag, if given.
+fn set<T>(option: &mut T, flag: Option<T>) {
+    if let Some(value) = flag {
+        *option = value;
+    }
+}
+
+/// Overrides the optional option with the flag, if given.
+fn set_some<T>(option: &mut Option<T>, flag: Option<T>) {
+    if flag.is_some() {
+        *option = flag;
+    }
+}
+
+impl Cli {
+    /// Applies the flags on top of the options of the config file.
+    fn apply(self, parsed: &mut Args) -> Result<()> {
+        let engine = &mut parsed.engine;
+        if !self.inputs.is_empty() {
+            engine.input = self.inputs;
+        }
+        if let Some(path) = self.type_map {
+            engine.type_map = TypeMap::from_path(&path)?;
+        }
+        set_some(&mut engine.chargeback_fee, self.chargeback_fee);
+        set_some(&mut engine.opening_balances, self.opening_balances);
+        set_some(&mut engine.load_state, self.load_state);
+        set_some(&mut engine.snapshot_every, self.snapshot_every);
+        set(&mut engine.snapshot_dir, self.snapshot_dir);
+        set_some(&mut engine.state_retention, self.state_retention);
+        set(&mut engine.on_total_mismatch, self.on_total_mismatch);
+        engine.reject_client_zero |= self.reject_client_zero;
+        engine.reject_tx_zero |= self.reject_tx_zero;
+        engine.reserved_clients.extend(self.reserved_clients);
+        engine.strict_kinds |= self.strict_kinds;
+        set_some(&mut engine.controls, self.controls);
+        engine.strict_protocol |= self.strict_protocol;
+        engine.allow_unfreeze |= self.allow_unfreeze;
+        set(&mut engine.locked_policy, self.locked_policy);
+        set(&mut engine.early_settlement, self.early_settlement);
+        set_some(&mut engine.sample_rate, self.sample_rate);
+        set(&mut engine.seed, self.seed);
+        set(&mut engine.on_truncation, self.on_truncation);
+        set(&mut engine.resolve_held, self.resolve_held);
+        set(&mut engine.accounting_model, self.accounting_model);
+        set(&mut engine.duplicate_dispute, self.duplicate_dispute);
+        set(&mut engine.tx_id_scope, self.tx_id_scope);
+        set_some(&mut engine.tx_id_epoch, self.tx_id_epoch);
+        set_some(&mut engine.tx_cache_size, self.tx_cache_size);
+        set_some(&mut engine.max_clients, self.max_clients);
+        set_some(&mut engine.max_open_disputes, self.max_open_disputes);
+        set_some(&mut engine.max_dispute_pct, self.max_dispute_pct);
+        set(&mut engine.trim, self.trim);
+        set_some(&mut engine.first_error_context, self.first_error_context);
+        engine.two_pass |= self.two_pass;
+        engine.keep_going |= self.keep_going;
+        engine.paranoid |= self.paranoid;
+        set_some(&mut engine.audit, self.audit);
+        engine.progress |= self.progress;
+        engine.no_header |= self.no_header;
+        set_some(&mut engine.delimiter, self.delimiter);
+        set(&mut engine.header_aliases, self.header_aliases);
+        set(&mut engine.skip_columns, self.skip_columns);
+        set_some(&mut engine.read_buffer, self.read_buffer);
+        set_some(&mut engine.file_list, self.file_list);
+        set_some(&mut engine.dispute_amounts, self.dispute_amounts);
+        set_some(&mut engine.balance_floor, self.balance_floor);
+        set_some(&mut engine.balance_ceiling, self.balance_ceiling);
+        set_some(&mut engine.max_amount, self.max_amount);
+        engine.negative_deposit_as_withdrawal |= self.negative_deposit_as_withdrawal;
+        engine.group_by_client |= self.group_by_client;
+        set(&mut engine.input_format, self.input_format);
+        set_some(&mut engine.postgres, self.postgres);
+        set_some(&mut engine.query, self.query);
+        set_some(&mut parsed.held_detail, self.held_detail);
+        set_some(&mut parsed.group_by_locked, self.group_by_locked);
+        set_some(&mut parsed.batch_report, self.batch_report);
+        set_some(&mut parsed.summary, self.summary);
+        set_some(&mut parsed.rejects, self.rejects);
+        set_some(&mut parsed.anomalies, self.anomalies);
+        set_some(
+            &mut parsed.dispute_ratio_threshold,
+            self.dispute_ratio_threshold,
+        );
+        parsed.check |= self.check;
+        set_some(&mut parsed.events, self.events);
+        set_some(&mut parsed.rejects_replay, self.rejects_replay);
+        parsed.output.extend(self.output);
+        set(&mut parsed.format, self.format);
+        set_some(&mut parsed.output_compress, self.output_compress);
+        set_some(&mut parsed.byte_range, self.byte_range);
+        set_some(&mut parsed.jobs, self.jobs);
+        set_some(&mut parsed.verify_against, self.verify_against);
+        set_some(&mut parsed.max_output_bytes, self.max_output_bytes);
+        parsed.rounding_report |= self.rounding_report;
+        set_some(&mut parsed.convert, self.convert);
+        parsed.allow_empty_result |= self.allow_empty_result;
+        parsed.redact_clients.extend(self.redact_clients);
+        parsed.balance_extremes |= self.balance_extremes;
+        parsed.stats |= self.stats;
+        parsed.report_open_disputes |= self.report_open_disputes;
+        parsed.fix_output_invariant |= self.fix_output_invariant;
+        parsed.clamp_negative_output |= self.clamp_negative_output;
+        parsed.json_numbers_as_strings |= self.json_numbers_as_strings;
+        parsed.fail_on_locked |= self.fail_on_locked;
+        set(&mut parsed.held_negative, self.held_negative);
+        set(&mut parsed.rounding, self.rounding);
+        set_some(&mut parsed.save_state, self.save_state);
+        set_some(&mut parsed.manifest, self.manifest);
+        parsed.fsync |= self.fsync;
+        set_some(&mut parsed.precision, self.precision);
+        set_some(&mut parsed.schema, self.schema);
+        parsed.quiet |= self.quiet;
+        set(&mut parsed.log_format, self.log_format);
+        Ok(())
+    }
+}
+
+/// Checks the combination of options, once the flags are applied on top of the config file.
+fn validate(parsed: &Args) -> Result<(), String> {
+    let engine = &parsed.engine;
+    if parsed.anomalies.is_some() != parsed.dispute_ratio_threshold.is_some() {
+        return Err("--anomalies and --dispute-ratio-threshold must be given together".into());
+    }
+    if parsed.output_compress.is_some() && parsed.output.is_empty() {
+        return Err("--output-compress requires --output".into());
+    }
+    if parsed.max_output_bytes.is_some() {
+        if parsed.output.is_empty() {
+            return Err("--max-output-bytes requires --output".into());
+        }
+        if parsed.output_compress.is_some() {
+            return Err("--max-output-bytes can't be combined with --output-compress".into());
+        }
+    }
+    if parsed.format == OutputFormat::Parquet {
+        if parsed.output.is_empty() {
+            return Err("--format parquet requires --output".into());
+        }
+        if parsed.output_compress.is_some() || parsed.max_output_bytes.is_some() {
+            return Err(
+                "--format parquet can't be combined with --output-compress or --max-output-bytes"
+                    .into(),
+            );
+        }
+    }
+    if let (Some(floor), Some(ceiling)) = (engine.balance_floor, engine.balance_ceiling) {
+        if floor > ceiling {
+            return Err(format!(
+                "--balance-floor {floor} is above --balance-ceiling {ceiling}"
+            ));
+        }
+    }
+    if engine.audit.is_some() && !parsed.redact_clients.is_empty() {
+        return Err("--audit can't be combined with --redact-client".into());
+    }
+    if engine.read_buffer == Some(0) {
+        return Err("--read-buffer must be positive".into());
+    }
+    if engine.max_amount.is_some_and(|max| max <= Money::ZERO) {
+        return Err("--max-amount must be positive".into());
+    }
+    if engine.snapshot_every == Some(0) {
+        return Err("--snapshot-every must be positive".into());
+    }
+    if engine.tx_cache_size == Some(0) {
+        return Err("--tx-cache-size must be positive".into());
+    }
+    if engine.postgres.is_some() != engine.query.is_some() {
+        return Err("--postgres and --query must be given together".into());
+    }
+    if engine.postgres.is_some() {
+        if !engine.input.is_empty() || engine.file_list.is_some() {
+            return Err("--postgres can't be combined with input files or --file-list".into());
+        }
+        if parsed.jobs.is_some() || parsed.byte_range.is_some() {
+            return Err("--postgres can't be combined with --jobs or --byte-range".into());
+        }
+    }
+    if engine.first_error_context.is_some() && (parsed.jobs.is_some() || engine.group_by_client) {
+        return Err(
+            "--first-error-context can't be combined with --jobs or --group-by-client".into(),
+        );
+    }
+    if let Some(jobs) = parsed.jobs {
+        if jobs == 0 {
+            return Err("--jobs must be positive".into());
+        }
+        if parsed.byte_range.is_some() {
+            return Err("--jobs can't be combined with --byte-range".into());
+        }
+    }
+    let seekable = engine.input_format == InputFormat::Csv
+        && engine.postgres.is_none()
+        && !engine.input.is_empty()
+        && engine.input.iter().all(|path| path != Path::new(STDIN));
+    // Seeking needs a single plain CSV file, the range being offsets into its bytes.
+    if parsed.byte_range.is_some() {
+        if engine.input.len() != 1 || engine.file_list.is_some() {
+            return Err("--byte-range requires a single input file".into());
+        }
+        if !seekable || is_gzip(&engine.input[0]) {
+            return Err("--byte-range requires a plain CSV file, not stdin or gzip".into());
+        }
+        if engine.two_pass {
+            return Err("--byte-range can't be combined with --two-pass".into());
+        }
+    }
+    if engine.two_pass && !seekable && engine.file_list.is_none() {
+        return Err("--two-pass requires CSV input files, not stdin".into());
+    }
+    if parsed.precision.is_some_and(|p| p > MAX_PRECISION) {
+        return Err(format!("--precision can't exceed {MAX_PRECISION}"));
+    }
+    if engine
+        .sample_rate
+        .is_some_and(|rate| !(0.0..=1.0).contains(&rate))
+    {
+        return Err("--sample-rate must be within 0.0..1.0".into());
+    }
+    if engine
+        .max_dispute_pct
+        .is_some_and(|pct| !(1..=100).contains(&pct))
+    {
+        return Err("--max-dispute-pct must be within 1..100".into());
+    }
+    if !engine.input.is_empty() && engine.file_list.is_some() {
+        return Err("input paths can't be combined with --file-list".into());
+    }
+    Ok(())
+}
+
+/// Commands and exit codes sections of the README, shown after the options.
+fn epilogue() -> String {
+    let section = |name: &str| {
+        let heading = format!("## {name}\n");
+        let start = README
+            .find(&heading)
+            .map_or(README.len(), |i| i + heading.len());
+        let rest = &README[start..];
+        rest[..rest.find("\n## ").map_or(rest.len(), |end| end + 1)].to_owned()
+    };
+    format!(
+        "Commands:\n{}\nExit codes:\n{}",
+        section("Commands"),
+        section("Exit codes")
+    )
+}
+
+pub fn flag_value<T>(flag: &str, value: Option<String>) -> Result<T>
+where
+    T: FromStr,
+    T::Err: Display,
+{
+    let value = value.ok_or_else(|| anyhow!("{flag} requires a value"))?;
+    value
+        .parse()
+        .map_err(|e| anyhow!("invalid {flag} value {value}: {e}"))
+}
@@ -1,100 +1,518 @@
-use anyhow::{anyhow, Context, Result};
-use serde::{Serialize, Serializer};
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
 
-use crate::MaybeError;
+use crate::{
+    config::{AccountingModel, ResolveHeld},
+    error::EngineError,
+    money::Money,
+    policy::FundsPolicy,
+};
 
-#[derive(Debug, Serialize, Default)]
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
 pub struct Client {
+    /// `client` as in the output, so it can be read back as opening balances.
+    #[serde(alias = "client")]
     id: u16,
-    #[serde(serialize_with = "serialize_with_precision")]
-    available: f64,
-    #[serde(serialize_with = "serialize_with_precision")]
-    held: f64,
-    #[serde(serialize_with = "serialize_with_precision")]
-    total: f64,
+    available: Money,
+    held: Money,
+    total: Money,
     locked: bool,
+    /// Lowest and highest available balance seen, once the balance changed.
+    #[serde(skip)]
+    available_extremes: Option<(Money, Money)>,
+}
+
+/// Balances of a client at one point of processing, see [`crate::Engine::client_snapshot`].
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct ClientSnapshot {
+    pub available: Money,
+    pub held: Money,
+    pub total: Money,
+    pub locked: bool,
 }
 
 impl Client {
     pub fn new(id: u16) -> Self {
         Self {
             id,
+            available_extremes: Some((Money::ZERO, Money::ZERO)),
             ..Default::default()
         }
     }
 
-    fn check_lock(&self) -> Result<(), MaybeError> {
-        if self.locked {
-            warn!("Client #{}: is locked", self.id);
-            Err(None)
-        } else {
-            Ok(())
+    /// A client with the given balances, e.g. restored from elsewhere.
+    ///
+    /// Fails unless `available + held` matches `total`, the [`AccountingModel::Standard`]
+    /// invariant.
+    pub fn from_parts(
+        id: u16,
+        available: Money,
+        held: Money,
+        total: Money,
+        locked: bool,
+    ) -> Result<Self> {
+        let client = Self {
+            id,
+            available,
+            held,
+            total,
+            locked,
+            available_extremes: None,
+        };
+        if !client.is_consistent(AccountingModel::Standard) {
+            return Err(anyhow!(
+                "Client #{id}: total {total} doesn't match available {available} + held {held}"
+            ));
         }
+        Ok(client)
     }
 
-    fn can_reduce_balance(&self, amount: f64) -> Result<()> {
-        if self.available < amount || self.total < amount {
-            Err(anyhow!("Client #{}: insufficient funds", self.id))
-        } else {
-            Ok(())
+    pub fn id(&self) -> u16 {
+        self.id
+    }
+
+    pub fn available(&self) -> Money {
+        self.available
+    }
+
+    pub fn held(&self) -> Money {
+        self.held
+    }
+
+    pub fn total(&self) -> Money {
+        self.total
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.locked
+    }
+
+    pub fn snapshot(&self) -> ClientSnapshot {
+        ClientSnapshot {
+            available: self.available,
+            held: self.held,
+            total: self.total,
+            locked: self.locked,
         }
     }
 
-    fn can_reduce_held(&self, amount: f64) -> Result<()> {
-        if self.held < amount {
-            debug!("held: {}, amount: {amount}", self.held);
-            Err(anyhow!("Client #{}: insufficient funds held", self.id))
+    /// Whether `total` matches the balances `model` makes it of, `available + held` by default.
+    pub fn is_consistent(&self, model: AccountingModel) -> bool {
+        self.expected_total(model).ok() == Some(self.total)
+    }
+
+    /// Whether balances match `other` within `epsilon` and the locked flags are equal.
+    ///
+    /// Meant for comparing results which went through different representations, e.g. a
+    /// round trip through the output format.
+    pub fn approx_eq(&self, other: &Client, epsilon: Money) -> bool {
+        let close = |a: Money, b: Money| a.checked_sub(b).is_ok_and(|d| d.abs() <= epsilon);
+        self.id == other.id
+            && self.locked == other.locked
+            && close(self.available, other.available)
+            && close(self.held, other.held)
+            && close(self.total, other.total)
+    }
+
+    pub fn recompute_total(&mut self, model: AccountingModel) -> Result<()> {
+        self.total = self.expected_total(model)?;
+        Ok(())
+    }
+
+    fn expected_total(&self, model: AccountingModel) -> Result<Money> {
+        match model {
+            AccountingModel::Standard => Ok(self.available.checked_add(self.held)?),
+            AccountingModel::Alt => Ok(self.available),
+        }
+    }
+
+    /// An active client becomes locked, until [`Client::unlock`].
+    ///
+    /// Locking a locked client is skipped like any other operation on it.
+    pub fn lock(&mut self) -> Result<(), EngineError> {
+        self.check_lock()?;
+        self.locked = true;
+        Ok(())
+    }
+
+    /// A locked client becomes active again, once an operator cleared the chargeback behind it.
+    ///
+    /// Unlocking an active client fails, there is no lock to clear.
+    pub fn unlock(&mut self) -> Result<(), EngineError> {
+        if !self.locked {
+            return Err(EngineError::NotLocked { client: self.id });
+        }
+        self.locked = false;
+        Ok(())
+    }
+
+    fn check_lock(&self) -> Result<(), EngineError> {
+        if self.locked {
+            warn!(client = self.id; "Client #{}: is locked", self.id);
+            Err(EngineError::AccountLocked { client: self.id })
         } else {
             Ok(())
         }
     }
 
-    pub fn deposit(&mut self, amount: f64) -> Result<(), MaybeError> {
+    /// Skips an operation which would leave `available` funds the policy doesn't allow.
+    fn check_available(
+        &self,
+        available: Money,
+        policy: &dyn FundsPolicy,
+    ) -> Result<(), EngineError> {
+        policy.can_hold(self, available).map_err(|e| {
+            warn!(client = self.id; "{e}, skipping the operation");
+            EngineError::OutOfBounds(e)
+        })
+    }
+
+    pub fn deposit(&mut self, amount: Money, policy: &dyn FundsPolicy) -> Result<(), EngineError> {
         self.check_lock()?;
-        self.available += amount;
-        self.total += amount;
+        let available = self.available.checked_add(amount)?;
+        let total = self.total.checked_add(amount)?;
+        self.check_available(available, policy)?;
+        self.total = total;
+        self.available = available;
+        self.track_available();
         Ok(())
     }
 
-    pub fn withdrawal(&mut self, amount: f64) -> Result<(), MaybeError> {
+    pub fn withdrawal(
+        &mut self,
+        amount: Money,
+        policy: &dyn FundsPolicy,
+    ) -> Result<(), EngineError> {
         self.check_lock()?;
-        self.can_reduce_balance(amount)?;
-        self.available -= amount;
-        self.total -= amount;
+        policy
+            .can_withdraw(self, amount)
+            .map_err(EngineError::InsufficientFunds)?;
+        let available = self.available.checked_sub(amount)?;
+        let total = self.total.checked_sub(amount)?;
+        self.check_available(available, policy)?;
+        self.total = total;
+        self.available = available;
+        self.track_available();
         Ok(())
     }
 
-    pub fn dispute_deposit(&mut self, amount: f64) -> Result<(), MaybeError> {
+    /// Moves disputed funds from available to held, `model` decides whether they leave the total.
+    ///
+    /// A dispute the available funds can't cover is skipped, leaving the client untouched.
+    pub fn dispute_deposit(
+        &mut self,
+        amount: Money,
+        policy: &dyn FundsPolicy,
+        model: AccountingModel,
+    ) -> Result<(), EngineError> {
         self.check_lock()?;
-        self.can_reduce_balance(amount)?;
-        self.available -= amount;
-        self.held += amount;
+        if let Err(e) = policy.can_withdraw(self, amount) {
+            warn!(
+                client = self.id;
+                "{e}: dispute of {amount} exceeds available {}, skipping it",
+                self.available
+            );
+            return Err(EngineError::DisputeExceedsAvailable {
+                client: self.id,
+                amount,
+            });
+        }
+        let available = self.available.checked_sub(amount)?;
+        let held = self.held.checked_add(amount)?;
+        let total = match model {
+            AccountingModel::Standard => self.total,
+            AccountingModel::Alt => self.total.checked_sub(amount)?,
+        };
+        self.check_available(available, policy)?;
+        self.total = total;
+        self.held = held;
+        self.available = available;
+        self.track_available();
         Ok(())
     }
 
-    pub fn resolve_deposit(&mut self, amount: f64) -> Result<(), MaybeError> {
+    /// Releases disputed funds back to available, `mode` decides on a held shortfall.
+    pub fn resolve_deposit(
+        &mut self,
+        amount: Money,
+        policy: &dyn FundsPolicy,
+        mode: ResolveHeld,
+        model: AccountingModel,
+    ) -> Result<(), EngineError> {
         self.check_lock()?;
-        self.can_reduce_held(amount)
-            .with_context(|| "can't reduce held funds to resolve")?;
-        self.available += amount;
-        self.held -= amount;
+        let amount = self.resolvable(amount, policy, mode)?;
+        let available = self.available.checked_add(amount)?;
+        let held = self.held.checked_sub(amount)?;
+        let total = match model {
+            AccountingModel::Standard => self.total,
+            AccountingModel::Alt => self.total.checked_add(amount)?,
+        };
+        self.check_available(available, policy)?;
+        self.total = total;
+        self.held = held;
+        self.available = available;
+        self.track_available();
         Ok(())
     }
 
-    pub fn chargeback(&mut self, amount: f64) -> Result<(), MaybeError> {
+    /// Holds the amount of a disputed withdrawal while available stays as it is.
+    pub fn dispute_withdrawal(
+        &mut self,
+        amount: Money,
+        model: AccountingModel,
+    ) -> Result<(), EngineError> {
         self.check_lock()?;
-        self.can_reduce_held(amount)
-            .with_context(|| "can't reduce held funds for chargeback")?;
-        self.held -= amount;
-        self.total -= amount;
-        self.locked = true;
+        let held = self.held.checked_add(amount)?;
+        let total = match model {
+            AccountingModel::Standard => self.total.checked_add(amount)?,
+            AccountingModel::Alt => self.total,
+        };
+        self.total = total;
+        self.held = held;
+        Ok(())
+    }
+
+    /// Confirms a disputed withdrawal, dropping its held amount again.
+    pub fn resolve_withdrawal(
+        &mut self,
+        amount: Money,
+        policy: &dyn FundsPolicy,
+        mode: ResolveHeld,
+        model: AccountingModel,
+    ) -> Result<(), EngineError> {
+        self.check_lock()?;
+        let amount = self.resolvable(amount, policy, mode)?;
+        let held = self.held.checked_sub(amount)?;
+        if model == AccountingModel::Standard {
+            self.total = self.total.checked_sub(amount)?;
+        }
+        self.held = held;
         Ok(())
     }
+
+    /// Amount a resolve releases from held funds.
+    fn resolvable(
+        &self,
+        amount: Money,
+        policy: &dyn FundsPolicy,
+        mode: ResolveHeld,
+    ) -> Result<Money, EngineError> {
+        match (policy.can_release_held(self, amount), mode) {
+            (Ok(()), _) => Ok(amount),
+            (Err(e), ResolveHeld::Strict) => {
+                Err(e.context("can't reduce held funds to resolve").into())
+            }
+            (Err(_), ResolveHeld::Partial) => {
+                warn!(
+                    client = self.id;
+                    "Client #{}: resolve of {amount} exceeds held {}, releasing held only",
+                    self.id, self.held
+                );
+                Ok(self.held)
+            }
+            (Err(_), ResolveHeld::Force) => {
+                warn!(
+                    client = self.id;
+                    "Client #{}: resolve of {amount} exceeds held {}, forcing it",
+                    self.id, self.held
+                );
+                Ok(amount)
+            }
+        }
+    }
+
+    /// Deducts a fee from available funds, bypassing the lock and funds checks.
+    ///
+    /// The fee follows a chargeback, which locked the client already, so it isn't skipped
+    /// when it takes available below zero or below the funds policy's floor.
+    pub fn charge_fee(&mut self, fee: Money) -> Result<()> {
+        let available = self.available.checked_sub(fee)?;
+        self.total = self.total.checked_sub(fee)?;
+        self.available = available;
+        self.track_available();
+        Ok(())
+    }
+
+    /// Lowest and highest available balance observed during processing.
+    pub fn available_extremes(&self) -> (Money, Money) {
+        self.available_extremes
+            .unwrap_or((self.available, self.available))
+    }
+
+    pub fn track_available(&mut self) {
+        let (min, max) = self.available_extremes();
+        self.available_extremes = Some((min.min(self.available), max.max(self.available)));
+    }
+
+    /// Reverses disputed funds and locks the client.
+    ///
+    /// Under [`AccountingModel::Alt`] the dispute took them out of the total already.
+    pub fn chargeback(
+        &mut self,
+        amount: Money,
+        policy: &dyn FundsPolicy,
+        model: AccountingModel,
+    ) -> Result<(), EngineError> {
+        self.check_lock()?;
+        let amount = self.chargeable(amount, policy);
+        let held = self.held.checked_sub(amount)?;
+        if model == AccountingModel::Standard {
+            self.total = self.total.checked_sub(amount)?;
+        }
+        self.held = held;
+        self.lock()?;
+        self.track_available();
+        Ok(())
+    }
+
+    /// Reverses a disputed withdrawal, returning its held amount to available, and locks the
+    /// client.
+    pub fn chargeback_withdrawal(
+        &mut self,
+        amount: Money,
+        policy: &dyn FundsPolicy,
+        model: AccountingModel,
+    ) -> Result<(), EngineError> {
+        self.check_lock()?;
+        let amount = self.chargeable(amount, policy);
+        let held = self.held.checked_sub(amount)?;
+        let available = self.available.checked_add(amount)?;
+        let total = match model {
+            AccountingModel::Standard => self.total,
+            AccountingModel::Alt => self.total.checked_add(amount)?,
+        };
+        self.check_available(available, policy)?;
+        self.total = total;
+        self.available = available;
+        self.held = held;
+        self.lock()?;
+        self.track_available();
+        Ok(())
+    }
+
+    /// Amount a chargeback releases from held funds.
+    ///
+    /// If held funds fell short of the disputed amount, only what is held is released,
+    /// so the dispute can still be closed.
+    fn chargeable(&self, amount: Money, policy: &dyn FundsPolicy) -> Money {
+        if policy.can_release_held(self, amount).is_err() {
+            warn!(
+                client = self.id;
+                "Client #{}: chargeback of {amount} exceeds held {}, releasing held only",
+                self.id, self.held
+            );
+            self.held
+        } else {
+            amount
+        }
+    }
 }
 
-fn serialize_with_precision<S>(x: &f64, s: S) -> Result<S::Ok, S::Error>
-where
-    S: Serializer,
-{
-    s.serialize_f64((x * 1000.0).trunc() / 1000.0)
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::policy::StrictFunds;
+
+    fn money(text: &str) -> Money {
+        text.parse().unwrap()
+    }
+
+    /// A client holding a dispute of 5 out of its 10 deposited.
+    fn disputed() -> Client {
+        let mut client = Client::new(1);
+        client.deposit(money("10"), &StrictFunds).unwrap();
+        client
+            .dispute_deposit(money("5"), &StrictFunds, AccountingModel::Standard)
+            .unwrap();
+        client
+    }
+
+    #[test]
+    fn active_client_locks_and_unlocks() {
+        let mut client = Client::new(1);
+
+        client.lock().unwrap();
+        assert!(client.is_locked());
+        client.unlock().unwrap();
+        assert!(!client.is_locked());
+        client.lock().unwrap();
+        assert!(client.is_locked());
+    }
+
+    #[test]
+    fn locking_a_locked_client_fails() {
+        let mut client = Client::new(1);
+        client.lock().unwrap();
+
+        let result = client.lock();
+
+        assert!(matches!(
+            result,
+            Err(EngineError::AccountLocked { client: 1 })
+        ));
+        assert!(client.is_locked());
+    }
+
+    #[test]
+    fn unlocking_an_active_client_fails() {
+        let mut client = Client::new(1);
+
+        let result = client.unlock();
+
+        assert!(matches!(result, Err(EngineError::NotLocked { client: 1 })));
+        assert!(!client.is_locked());
+        // Unlocked once, there is no lock left to clear either.
+        client.lock().unwrap();
+        client.unlock().unwrap();
+        assert!(matches!(
+            client.unlock(),
+            Err(EngineError::NotLocked { client: 1 })
+        ));
+    }
+
+    #[test]
+    fn chargeback_locks_the_client() {
+        let mut client = disputed();
+
+        client
+            .chargeback(money("5"), &StrictFunds, AccountingModel::Standard)
+            .unwrap();
+
+        assert!(client.is_locked());
+        assert_eq!(client.total(), money("5"));
+    }
+
+    #[test]
+    fn chargeback_of_a_locked_client_fails() {
+        let mut client = disputed();
+        client.lock().unwrap();
+        let before = client.snapshot();
+
+        let result = client.chargeback(money("5"), &StrictFunds, AccountingModel::Standard);
+
+        assert!(matches!(
+            result,
+            Err(EngineError::AccountLocked { client: 1 })
+        ));
+        assert_eq!(client.snapshot(), before);
+    }
+
+    #[test]
+    fn unlocked_client_can_be_charged_back_again() {
+        let mut client = disputed();
+        client
+            .chargeback(money("5"), &StrictFunds, AccountingModel::Standard)
+            .unwrap();
+        client.unlock().unwrap();
+        client
+            .dispute_deposit(money("2"), &StrictFunds, AccountingModel::Standard)
+            .unwrap();
+
+        client
+            .chargeback(money("2"), &StrictFunds, AccountingModel::Standard)
+            .unwrap();
+
+        assert!(client.is_locked());
+        assert_eq!(client.total(), money("3"));
+    }
 }
@@ -0,0 +1,72 @@
+use std::{fs::File, path::Path, sync::Arc};
+
+use anyhow::{Context, Result};
+use arrow_array::{ArrayRef, BooleanArray, Decimal128Array, RecordBatch, UInt16Array, UInt64Array};
+use arrow_schema::{DataType, Field, Schema};
+use parquet::arrow::ArrowWriter;
+
+use crate::{money::Money, output::ClientRow};
+
+/// Digits of the balance columns, enough for any [`Money`].
+const DECIMAL_PRECISION: u8 = 38;
+
+/// Writes client rows as a Parquet file with decimal balance columns of `precision` places.
+pub fn write_parquet(path: &Path, rows: &[ClientRow], precision: u32, fsync: bool) -> Result<()> {
+    let scale = precision as i8;
+    let decimal = DataType::Decimal128(DECIMAL_PRECISION, scale);
+    let balances = |balance: fn(&ClientRow) -> Option<Money>| -> Result<ArrayRef> {
+        let column = rows
+            .iter()
+            .map(|row| balance(row).map(|m| m.scaled(precision)))
+            .collect::<Decimal128Array>()
+            .with_precision_and_scale(DECIMAL_PRECISION, scale)?;
+        Ok(Arc::new(column))
+    };
+
+    let mut fields = vec![
+        Field::new("client", DataType::UInt16, false),
+        Field::new("available", decimal.clone(), false),
+        Field::new("held", decimal.clone(), false),
+        Field::new("total", decimal.clone(), false),
+        Field::new("locked", DataType::Boolean, false),
+    ];
+    let mut columns: Vec<ArrayRef> = vec![
+        Arc::new(rows.iter().map(|row| row.client).collect::<UInt16Array>()),
+        balances(|row| Some(row.available))?,
+        balances(|row| Some(row.held))?,
+        balances(|row| Some(row.total))?,
+        Arc::new(
+            rows.iter()
+                .map(|row| Some(row.locked))
+                .collect::<BooleanArray>(),
+        ),
+    ];
+    if rows.iter().any(|row| row.available_min.is_some()) {
+        fields.push(Field::new("available_min", decimal.clone(), true));
+        fields.push(Field::new("available_max", decimal.clone(), true));
+        columns.push(balances(|row| row.available_min)?);
+        columns.push(balances(|row| row.available_max)?);
+    }
+    if rows.iter().any(|row| row.open_disputes.is_some()) {
+        fields.push(Field::new("open_disputes", DataType::UInt64, true));
+        columns.push(Arc::new(
+            rows.iter()
+                .map(|row| row.open_disputes)
+                .collect::<UInt64Array>(),
+        ));
+    }
+    if rows.iter().any(|row| row.disputed_held.is_some()) {
+        fields.push(Field::new("disputed_held", decimal, true));
+        columns.push(balances(|row| row.disputed_held)?);
+    }
+
+    let batch = RecordBatch::try_new(Arc::new(Schema::new(fields)), columns)?;
+    let file = File::create(path).with_context(|| format!("can't create {}", path.display()))?;
+    let mut writer = ArrowWriter::try_new(file, batch.schema(), None)?;
+    writer.write(&batch)?;
+    let file = writer.into_inner()?;
+    if fsync {
+        file.sync_all()?;
+    }
+    Ok(())
+}
@@ -0,0 +1,396 @@
+use std::{path::PathBuf, str::FromStr};
+
+use anyhow::{anyhow, Result};
+use csv::Trim;
+use serde::{Deserialize, Deserializer, Serialize};
+
+use crate::{
+    money::{self, Money},
+    policy::{SharedPolicy, Validator},
+    transaction::{HeaderAliases, TypeMap},
+};
+
+/// Engine options, assembled by [`crate::engine::EngineBuilder`].
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct Config {
+    /// Inputs processed in order as one stream.
+    #[serde(deserialize_with = "one_or_many")]
+    pub input: Vec<PathBuf>,
+    pub type_map: TypeMap,
+    /// Fee deducted from available funds on every successful chargeback, even where that takes
+    /// them negative.
+    #[serde(deserialize_with = "money::deserialize_number")]
+    pub chargeback_fee: Option<Money>,
+    #[serde(skip)]
+    pub funds_policy: SharedPolicy,
+    /// Client balances to start from, in the output format.
+    pub opening_balances: Option<PathBuf>,
+    /// State saved by [`crate::Engine::save_state`] to go on from, instead of opening balances.
+    pub load_state: Option<PathBuf>,
+    /// Save the state to a numbered file in `snapshot_dir` after every this many applied
+    /// records, to go on from any of them with `load_state`.
+    pub snapshot_every: Option<u64>,
+    pub snapshot_dir: PathBuf,
+    /// Tx ids behind the highest applied one which stay disputable in saved states, older
+    /// txs without an open dispute are left out.
+    pub state_retention: Option<u32>,
+    pub on_total_mismatch: TotalMismatch,
+    /// Treat client id `0` as a "no client" sentinel and skip its records.
+    pub reject_client_zero: bool,
+    /// Skip deposits, withdrawals and transfers with tx id `0`.
+    pub reject_tx_zero: bool,
+    #[serde(skip)]
+    pub validator: Option<Validator>,
+    /// Client ids which never hold an account, e.g. system sentinels, whose records are skipped.
+    pub reserved_clients: Vec<u16>,
+    /// Fail on records with an empty or unknown `type` instead of skipping them.
+    pub strict_kinds: bool,
+    /// File with dispute, resolve and chargeback records, applied right after the tx they
+    /// reference.
+    pub controls: Option<PathBuf>,
+    /// Fail on dispute lifecycle violations instead of skipping the record.
+    pub strict_protocol: bool,
+    /// Apply `unfreeze` records, which clear the lock of their client, instead of rejecting them.
+    pub allow_unfreeze: bool,
+    pub locked_policy: LockedPolicy,
+    pub early_settlement: EarlySettlement,
+    pub input_format: InputFormat,
+    /// Connection string of a Postgres database whose `query` rows are read instead of the
+    /// inputs, needs the `postgres` feature.
+    pub postgres: Option<String>,
+    /// Query returning the records from `postgres`, with the CSV column names.
+    pub query: Option<String>,
+    /// Probability of a tx, with all of its dispute records, being processed at all.
+    pub sample_rate: Option<f64>,
+    /// Seed of the sampling, a fixed seed always selects the same transactions.
+    pub seed: u64,
+    pub on_truncation: Truncation,
+    pub resolve_held: ResolveHeld,
+    pub accounting_model: AccountingModel,
+    pub duplicate_dispute: DuplicateDispute,
+    pub tx_id_scope: TxIdScope,
+    /// Input column scoping tx ids further, an id may recur once per distinct value.
+    pub tx_id_epoch: Option<String>,
+    /// Applied txs kept in memory, older ones are moved to a temporary file.
+    pub tx_cache_size: Option<usize>,
+    /// Skip records of new clients once this many clients exist.
+    pub max_clients: Option<usize>,
+    /// Skip new disputes of a client which has this many open already.
+    pub max_open_disputes: Option<usize>,
+    /// Most of a tx, in percent, its disputes may hold together, 100 when unset.
+    pub max_dispute_pct: Option<u32>,
+    pub trim: TrimMode,
+    /// Bytes the CSV readers fill per read call, the csv crate's default when unset.
+    pub read_buffer: Option<usize>,
+    /// CSV inputs start with data, in `type,client,tx,amount` column order.
+    pub no_header: bool,
+    /// Field separator of CSV inputs, a comma when unset. Has to be an ASCII character.
+    pub delimiter: Option<char>,
+    /// Input column names read as one of the canonical ones, e.g. `client_id` as `client`.
+    pub header_aliases: HeaderAliases,
+    /// Leading input columns dropped before the header is mapped, e.g. a spreadsheet row number.
+    pub skip_columns: usize,
+    /// Records kept on either side of a record failing the run, for diagnosing it.
+    pub first_error_context: Option<usize>,
+    /// File listing inputs to process in order, instead of `input`.
+    pub file_list: Option<PathBuf>,
+    /// Deposits and withdrawals which disputes may reference, without applying them.
+    pub dispute_amounts: Option<PathBuf>,
+    /// Read the inputs twice, so disputes, resolves and chargebacks preceding their tx are
+    /// applied right after it.
+    pub two_pass: bool,
+    /// Draw rows read per second on stderr, with an ETA for input files of a known length.
+    pub progress: bool,
+    /// Buffer the whole input and apply it client by client, keeping each client's order.
+    pub group_by_client: bool,
+    /// Read a deposit with a negative amount as a withdrawal of its absolute value.
+    pub negative_deposit_as_withdrawal: bool,
+    /// Lowest available balance an operation may leave, see [`crate::policy::BalanceBounds`].
+    #[serde(deserialize_with = "money::deserialize_number")]
+    pub balance_floor: Option<Money>,
+    /// Highest available balance an operation may leave.
+    #[serde(deserialize_with = "money::deserialize_number")]
+    pub balance_ceiling: Option<Money>,
+    /// Largest amount of a single deposit, withdrawal or transfer, larger ones are skipped.
+    #[serde(deserialize_with = "money::deserialize_number")]
+    pub max_amount: Option<Money>,
+    /// Tx whose lifecycle is recorded, for the `explain-tx` command.
+    #[serde(skip)]
+    pub explain_tx: Option<u32>,
+    /// Whether rejects, warnings and locks are recorded as [`crate::engine::Event`]s.
+    #[serde(skip)]
+    pub record_events: bool,
+    /// File receiving an [`crate::engine::AuditRow`] per client change of every applied record.
+    pub audit: Option<PathBuf>,
+    /// Check the balances of the clients of every record for engine bugs, failing the run on
+    /// a violation, see [`crate::Engine::step`].
+    pub paranoid: bool,
+    /// Rejects records which would fail the run instead of stopping, to report every problem.
+    #[serde(skip)]
+    pub check: bool,
+    /// Collects the errors of records which would fail the run and goes on, see
+    /// [`crate::Engine::failures`].
+    pub keep_going: bool,
+}
+
+/// Accepts a single path as well as a list in the config file.
+pub fn one_or_many<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<PathBuf>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(PathBuf),
+        Many(Vec<PathBuf>),
+    }
+    Ok(match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(path) => vec![path],
+        OneOrMany::Many(paths) => paths,
+    })
+}
+
+/// What to do with an imported client whose `available + held` differs from `total`.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TotalMismatch {
+    #[default]
+    Reject,
+    Recompute,
+    Warn,
+}
+
+impl FromStr for TotalMismatch {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "reject" => Ok(Self::Reject),
+            "recompute" => Ok(Self::Recompute),
+            "warn" => Ok(Self::Warn),
+            _ => Err(anyhow!("unknown total mismatch mode: {s}")),
+        }
+    }
+}
+
+/// Layout of the transaction input.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum InputFormat {
+    #[default]
+    Csv,
+    /// A single JSON array of transaction objects.
+    JsonArray,
+}
+
+impl FromStr for InputFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "csv" => Ok(Self::Csv),
+            "json-array" => Ok(Self::JsonArray),
+            _ => Err(anyhow!("unknown input format: {s}")),
+        }
+    }
+}
+
+/// What to do when the input ends in a partial record, e.g. after an interrupted export.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Truncation {
+    #[default]
+    Warn,
+    Error,
+}
+
+impl FromStr for Truncation {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "warn" => Ok(Self::Warn),
+            "error" => Ok(Self::Error),
+            _ => Err(anyhow!("unknown truncation mode: {s}")),
+        }
+    }
+}
+
+/// How a resolve releases a disputed amount larger than the held funds.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ResolveHeld {
+    /// Fail the resolve.
+    #[default]
+    Strict,
+    /// Release only what is held.
+    Partial,
+    /// Release the full amount, driving held negative, for modeling.
+    Force,
+}
+
+impl FromStr for ResolveHeld {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "strict" => Ok(Self::Strict),
+            "partial" => Ok(Self::Partial),
+            "force" => Ok(Self::Force),
+            _ => Err(anyhow!("unknown resolve mode: {s}")),
+        }
+    }
+}
+
+/// What a client's total is made of, and so how disputes move it.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AccountingModel {
+    /// `total = available + held`: a deposit dispute moves funds from available to held and
+    /// leaves the total as it is.
+    #[default]
+    Standard,
+    /// `total = available`: held funds aren't counted, a deposit dispute takes them out of
+    /// available and the total alike, and a chargeback leaves the total as it is.
+    Alt,
+}
+
+impl FromStr for AccountingModel {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "standard" => Ok(Self::Standard),
+            "alt" => Ok(Self::Alt),
+            _ => Err(anyhow!("unknown accounting model: {s}")),
+        }
+    }
+}
+
+/// What becomes of the records of a locked client.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LockedPolicy {
+    /// Skip them.
+    #[default]
+    Reject,
+    /// Hold them back and apply them in order once an `unfreeze` of the client arrives.
+    Queue,
+}
+
+impl FromStr for LockedPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "reject" => Ok(Self::Reject),
+            "queue" => Ok(Self::Queue),
+            _ => Err(anyhow!("unknown locked policy: {s}")),
+        }
+    }
+}
+
+/// What becomes of a resolve or chargeback of a tx which isn't known yet.
+///
+/// Only unknown txs are covered: a settlement of a known tx without an open dispute is always
+/// rejected as a protocol violation, as deferring it could settle a much later dispute.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EarlySettlement {
+    /// Hold it back until the tx and a dispute of it arrive.
+    #[default]
+    Defer,
+    /// Skip it as referencing an unknown tx.
+    Reject,
+}
+
+impl FromStr for EarlySettlement {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "defer" => Ok(Self::Defer),
+            "reject" => Ok(Self::Reject),
+            _ => Err(anyhow!("unknown early settlement policy: {s}")),
+        }
+    }
+}
+
+/// How a dispute of a tx which is already disputed is handled.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DuplicateDispute {
+    /// Skip it as a protocol violation.
+    #[default]
+    Reject,
+    /// Hold its amount on top, up to the amount of the tx.
+    Accumulate,
+}
+
+impl FromStr for DuplicateDispute {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "reject" => Ok(Self::Reject),
+            "accumulate" => Ok(Self::Accumulate),
+            _ => Err(anyhow!("unknown duplicate dispute mode: {s}")),
+        }
+    }
+}
+
+/// Namespace in which tx ids must be unique and disputes are looked up.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum TxIdScope {
+    #[default]
+    Global,
+    /// Every client has its own tx ids.
+    PerClient,
+}
+
+impl FromStr for TxIdScope {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "global" => Ok(Self::Global),
+            "per-client" => Ok(Self::PerClient),
+            _ => Err(anyhow!("unknown tx id scope: {s}")),
+        }
+    }
+}
+
+/// Whitespace trimming of the CSV inputs, see [`csv::Trim`].
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TrimMode {
+    None,
+    Headers,
+    Fields,
+    #[default]
+    All,
+}
+
+impl From<TrimMode> for Trim {
+    fn from(mode: TrimMode) -> Self {
+        match mode {
+            TrimMode::None => Trim::None,
+            TrimMode::Headers => Trim::Headers,
+            TrimMode::Fields => Trim::Fields,
+            TrimMode::All => Trim::All,
+        }
+    }
+}
+
+impl FromStr for TrimMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "none" => Ok(Self::None),
+            "headers" => Ok(Self::Headers),
+            "fields" => Ok(Self::Fields),
+            "all" => Ok(Self::All),
+            _ => Err(anyhow!("unknown trim mode: {s}")),
+        }
+    }
+}
@@ -0,0 +1,47 @@
+use anyhow::{Context, Result};
+use csv::ByteRecord;
+use postgres::{Client, NoTls, SimpleQueryMessage};
+
+/// Rows fetched from the server at a time.
+const FETCH_SIZE: usize = 10_000;
+
+/// Calls `f` with the column names and every row of `query`, in the order the query returns
+/// them, as text records.
+///
+/// Rows are streamed through a cursor, so the result doesn't have to fit in memory. Values
+/// come as Postgres prints them, which keeps numerics exact, and `NULL` becomes empty.
+pub fn for_each_record(
+    url: &str,
+    query: &str,
+    mut f: impl FnMut(&ByteRecord, &ByteRecord) -> Result<()>,
+) -> Result<()> {
+    let mut client = Client::connect(url, NoTls).context("can't connect to postgres")?;
+    let mut transaction = client.transaction()?;
+    let query = query.trim().trim_end_matches(';');
+    transaction
+        .batch_execute(&format!(
+            "DECLARE paygine_input NO SCROLL CURSOR FOR {query}"
+        ))
+        .context("invalid input query")?;
+    let fetch = format!("FETCH {FETCH_SIZE} FROM paygine_input");
+    let mut headers = ByteRecord::new();
+    loop {
+        let mut rows = 0;
+        for message in transaction.simple_query(&fetch)? {
+            let SimpleQueryMessage::Row(row) = message else {
+                continue;
+            };
+            if headers.is_empty() {
+                headers = row.columns().iter().map(|column| column.name()).collect();
+            }
+            let record = (0..row.len())
+                .map(|i| row.get(i).unwrap_or_default())
+                .collect::<ByteRecord>();
+            f(&headers, &record)?;
+            rows += 1;
+        }
+        if rows < FETCH_SIZE {
+            return Ok(());
+        }
+    }
+}
@@ -1,81 +1,2288 @@
 use std::{
-    collections::{hash_map::Entry, HashMap},
-    fs::File,
+    collections::{hash_map::Entry, BTreeMap, HashMap, HashSet, VecDeque},
+    fmt::{self, Display},
+    fs::{self, File},
+    io::{self, BufRead, BufReader, Read, Seek, SeekFrom},
     path::{Path, PathBuf},
+    str::FromStr,
+    sync::mpsc::{self, Receiver, SyncSender},
+    thread,
 };
 
 use anyhow::{anyhow, Context, Error};
-use csv::{ByteRecord, Position, Reader, ReaderBuilder, Trim};
+use csv::{ByteRecord, Position, Reader, ReaderBuilder, Writer};
+use flate2::read::MultiGzDecoder;
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize, Serializer};
 
 use crate::{
-    client::Client,
-    transaction::{Kind, Transaction},
+    client::{Client, ClientSnapshot},
+    config::{
+        AccountingModel, Config, DuplicateDispute, EarlySettlement, InputFormat, LockedPolicy,
+        ResolveHeld, TotalMismatch, Truncation, TxIdScope,
+    },
+    error::EngineError,
+    json,
+    ledger::Ledger,
+    money::Money,
+    policy::{BalanceBounds, FundsPolicy, SharedPolicy, Validator},
+    progress::Progress,
+    state::State,
+    transaction::{Kind, Transaction, TypeMap},
 };
 
 type TxId = u32;
 
+/// Identity of a tx under the configured [`TxIdScope`], the client is set only per client, the
+/// epoch only with `tx_id_epoch`.
+pub(crate) type TxKey = (Option<u16>, u32, TxId);
+
+/// Maps and sets by [`TxKey`], looked up several times for every record.
+///
+/// Hashed with ahash, randomly seeded like the std hasher, but a fraction of its cost on
+/// small keys.
+pub(crate) type TxMap<V> = HashMap<TxKey, V, ahash::RandomState>;
+pub(crate) type TxSet = HashSet<TxKey, ahash::RandomState>;
+
+/// Input path which reads from stdin.
+pub const STDIN: &str = "-";
+
+/// Replaces the id of a redacted client in sidecar outputs.
+pub const REDACTED: &str = "REDACTED";
+
+/// Funds held for one open dispute.
+#[derive(Debug, Serialize)]
+pub struct HeldDetail {
+    /// `None` once redacted.
+    #[serde(serialize_with = "serialize_client")]
+    client: Option<u16>,
+    tx: TxId,
+    amount: Money,
+}
+
+impl HeldDetail {
+    pub fn client(&self) -> Option<u16> {
+        self.client
+    }
+
+    pub fn redact(&mut self) {
+        self.client = None;
+    }
+}
+
+/// A notable outcome of processing, one line of the `--events` log.
+#[derive(Debug, Clone, Serialize)]
+pub struct Event {
+    #[serde(rename = "type")]
+    pub kind: EventKind,
+    pub level: &'static str,
+    pub line: Option<u64>,
+    pub client: Option<u16>,
+    pub tx: Option<TxId>,
+    pub reason: String,
+    /// The raw record of a reject.
+    pub detail: Option<String>,
+}
+
+impl Event {
+    /// Drops the client and its record.
+    pub fn redact(&mut self) {
+        self.client = None;
+        self.detail = None;
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EventKind {
+    /// A record which was not applied.
+    Reject,
+    /// A record accepted without effect, e.g. a dispute naming another client's tx.
+    Warning,
+    /// A client locked by a chargeback.
+    Lock,
+    /// A client unlocked by an unfreeze.
+    Unlock,
+}
+
+/// Deposits and the disputes of them, per client.
+#[derive(Debug, Default, Clone, Copy, Serialize, Deserialize)]
+pub(crate) struct DisputeCounts {
+    deposits: u64,
+    disputes: u64,
+}
+
+/// Disputes of a client still open, see [`Engine::open_disputes`].
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct OpenDisputes {
+    pub count: u64,
+    /// Sum of the disputed amounts, the funds the disputes hold.
+    pub amount: Money,
+}
+
+/// A client disputing a suspicious share of its deposits.
+#[derive(Debug, Clone, Serialize)]
+pub struct Anomaly {
+    pub client: u16,
+    pub deposits: u64,
+    pub disputes: u64,
+    /// Disputes per deposit, rounded to 4 decimal places.
+    pub ratio: Decimal,
+}
+
+fn serialize_client<S: Serializer>(client: &Option<u16>, s: S) -> Result<S::Ok, S::Error> {
+    match client {
+        Some(id) => s.serialize_u16(*id),
+        None => s.serialize_str(REDACTED),
+    }
+}
+
+/// One record referencing the explained tx, with the client's balances right after it.
+#[derive(Debug, Clone, Serialize)]
+pub struct Step {
+    pub line: u64,
+    pub kind: Kind,
+    pub client: u16,
+    /// The amount of the record, or of the referenced tx for dispute records.
+    pub amount: Option<Money>,
+    pub applied: bool,
+    pub available: Money,
+    pub held: Money,
+}
+
+/// A client's balances after an applied record changed them, one row of the `--audit` file.
+#[derive(Debug, Clone, Serialize)]
+pub struct AuditRow {
+    pub line: u64,
+    pub tx: u32,
+    #[serde(rename = "type")]
+    pub kind: Kind,
+    pub client: u16,
+    pub available_delta: Money,
+    pub held_delta: Money,
+    pub available: Money,
+    pub held: Money,
+    pub total: Money,
+    pub locked: bool,
+}
+
+/// A record which was not applied, with its input line and why.
+#[derive(Debug, Clone, Serialize)]
+pub struct Rejection {
+    pub line: u64,
+    pub reason: String,
+    #[serde(serialize_with = "serialize_record")]
+    pub record: ByteRecord,
+}
+
+/// Joins the raw fields back into one column, lossily for invalid UTF-8.
+fn serialize_record<S: Serializer>(record: &ByteRecord, s: S) -> Result<S::Ok, S::Error> {
+    s.serialize_str(&join_fields(record))
+}
+
+fn join_fields(record: &ByteRecord) -> String {
+    let fields = record
+        .iter()
+        .map(String::from_utf8_lossy)
+        .collect::<Vec<_>>();
+    fields.join(",")
+}
+
+/// A record around the one which failed the run, see `first_error_context`.
+#[derive(Debug, Clone)]
+pub struct ContextRecord {
+    pub line: u64,
+    pub record: ByteRecord,
+    /// Whether this is the failing record itself.
+    pub failed: bool,
+}
+
+/// Prints the line and raw fields, the failing record marked with `>`.
+impl Display for ContextRecord {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let marker = if self.failed { '>' } else { ' ' };
+        write!(
+            f,
+            "{marker} {:>8}: {}",
+            self.line,
+            join_fields(&self.record)
+        )
+    }
+}
+
+/// A deposit or withdrawal as it was applied.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub(crate) struct Applied {
+    pub(crate) client: u16,
+    pub(crate) kind: Kind,
+    pub(crate) amount: Money,
+}
+
+/// Record counters accumulated while processing.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Stats {
+    pub records: u64,
+    pub applied: u64,
+    pub skipped: u64,
+    /// Records left out by `sample_rate`.
+    pub sampled_out: u64,
+    /// Deposits and withdrawals skipped for reusing a tx id, included in `skipped`.
+    pub duplicates: u64,
+    /// Records of each kind, applied or not.
+    pub deposits: u64,
+    pub withdrawals: u64,
+    pub disputes: u64,
+    pub resolves: u64,
+    pub chargebacks: u64,
+    pub unfreezes: u64,
+    pub transfers: u64,
+}
+
+impl Stats {
+    fn count(&mut self, kind: Kind) {
+        let counter = match kind {
+            Kind::Deposit => &mut self.deposits,
+            Kind::Withdrawal => &mut self.withdrawals,
+            Kind::Dispute => &mut self.disputes,
+            Kind::Resolve => &mut self.resolves,
+            Kind::Chargeback => &mut self.chargebacks,
+            Kind::Unfreeze => &mut self.unfreezes,
+            Kind::Transfer => &mut self.transfers,
+        };
+        *counter += 1;
+    }
+}
+
+/// Per-batch counters, records without a batch are counted under an empty name.
+#[derive(Debug, Default, Serialize)]
+pub struct BatchStats {
+    batch: String,
+    records: u64,
+    applied: u64,
+    /// Gross volume of applied deposits.
+    deposits: Money,
+    /// Gross volume of applied withdrawals.
+    withdrawals: Money,
+}
+
 #[derive(Debug)]
 pub struct Engine {
-    input: PathBuf,
+    /// Files processed in order as one stream.
+    inputs: Vec<PathBuf>,
     clients: HashMap<u16, Client>,
-    processed_transactions: HashMap<TxId, Position>,
-    disputed_transactions: HashMap<TxId, Transaction>,
+    /// Applied deposits and withdrawals, the source of amounts for disputes.
+    processed_transactions: Ledger,
+    /// Open disputes, holding the same compact entries as the ledger.
+    disputed_transactions: TxMap<Applied>,
+    /// Transactions whose last dispute was resolved.
+    resolved_transactions: TxSet,
+    /// Transactions reversed by a chargeback, which can't be disputed again.
+    charged_back_transactions: TxSet,
+    /// Resolves and chargebacks of txs not known yet, waiting for a dispute to open.
+    early_settlements: TxMap<(Transaction, Position, ByteRecord)>,
+    /// Dispute lifecycle records from a separate file, keyed by the tx they reference.
+    controls: TxMap<Vec<(Transaction, Position, ByteRecord)>>,
+    /// Deposits and withdrawals further down the inputs, indexed by a first pass.
+    upcoming: TxSet,
+    /// Records of locked clients held back until an unfreeze, with the `queue` locked policy.
+    locked_queue: HashMap<u16, Vec<(Transaction, Position, ByteRecord)>>,
+    /// Progress of the input being read, with `progress`.
+    progress: Option<Progress>,
+    headers: ByteRecord,
+    rejected: Vec<Rejection>,
+    fees_collected: Money,
+    stats: Stats,
+    batches: BTreeMap<String, BatchStats>,
+    /// Records held back by `group_by_client` until the whole input is read.
+    grouped: Vec<(ByteRecord, Position)>,
+    /// Lifecycle of the `explain_tx` transaction.
+    timeline: Vec<Step>,
+    /// Counts behind [`Self::anomalies`].
+    dispute_counts: HashMap<u16, DisputeCounts>,
+    /// Open disputes by client, only kept with `max_open_disputes`.
+    open_dispute_counts: HashMap<u16, usize>,
+    /// Rejects, warnings and locks in processing order, with `record_events`.
+    events: Vec<Event>,
+    /// Indices of the `tx_id_epoch` values seen.
+    epochs: HashMap<Vec<u8>, u32>,
+    /// Records around the one which failed the run, with `first_error_context`.
+    error_context: Vec<ContextRecord>,
+    /// Receives the balances after every applied record, with `audit`.
+    audit: Option<Writer<File>>,
+    /// Errors of records which would have failed the run, with `keep_going`.
+    failures: Vec<(u64, Error)>,
+    /// Set while records are handed to worker threads instead of being applied.
+    shards: Option<Shards>,
+    config: Config,
+}
+
+/// Collects engine configuration before the input is opened.
+#[derive(Debug, Default)]
+pub struct EngineBuilder {
+    config: Config,
+}
+
+impl From<Config> for EngineBuilder {
+    fn from(config: Config) -> Self {
+        Self { config }
+    }
+}
+
+impl EngineBuilder {
+    pub fn input(mut self, input: impl Into<PathBuf>) -> Self {
+        self.config.input.push(input.into());
+        self
+    }
+
+    pub fn type_map(mut self, type_map: TypeMap) -> Self {
+        self.config.type_map = type_map;
+        self
+    }
+
+    pub fn chargeback_fee(mut self, fee: Money) -> Self {
+        self.config.chargeback_fee = Some(fee);
+        self
+    }
+
+    pub fn funds_policy(mut self, policy: impl FundsPolicy + 'static) -> Self {
+        self.config.funds_policy = SharedPolicy::new(policy);
+        self
+    }
+
+    pub fn opening_balances(mut self, path: impl Into<PathBuf>) -> Self {
+        self.config.opening_balances = Some(path.into());
+        self
+    }
+
+    pub fn on_total_mismatch(mut self, mode: TotalMismatch) -> Self {
+        self.config.on_total_mismatch = mode;
+        self
+    }
+
+    pub fn reject_client_zero(mut self, reject: bool) -> Self {
+        self.config.reject_client_zero = reject;
+        self
+    }
+
+    pub fn reject_tx_zero(mut self, reject: bool) -> Self {
+        self.config.reject_tx_zero = reject;
+        self
+    }
+
+    /// Checks every record with `f` before applying it, a record it fails is skipped, e.g.
+    /// with [`EngineError::Invalid`], or fails the run if the error isn't recoverable.
+    pub fn validate(
+        mut self,
+        f: impl Fn(&Transaction) -> Result<(), EngineError> + Send + Sync + 'static,
+    ) -> Self {
+        self.config.validator = Some(Validator::new(f));
+        self
+    }
+
+    pub fn strict_kinds(mut self, strict: bool) -> Self {
+        self.config.strict_kinds = strict;
+        self
+    }
+
+    pub fn controls(mut self, path: impl Into<PathBuf>) -> Self {
+        self.config.controls = Some(path.into());
+        self
+    }
+
+    pub fn strict_protocol(mut self, strict: bool) -> Self {
+        self.config.strict_protocol = strict;
+        self
+    }
+
+    pub fn build(mut self) -> Result<Engine, Error> {
+        if let Some(delimiter) = self.config.delimiter.filter(|d| !d.is_ascii()) {
+            return Err(anyhow!("delimiter {delimiter:?} is not an ASCII character"));
+        }
+        if self.config.balance_floor.is_some() || self.config.balance_ceiling.is_some() {
+            self.config.funds_policy = SharedPolicy::new(BalanceBounds {
+                floor: self.config.balance_floor,
+                ceiling: self.config.balance_ceiling,
+                inner: self.config.funds_policy.clone(),
+            });
+        }
+        let inputs = match &self.config.file_list {
+            Some(path) => read_file_list(path)?,
+            None => self.config.input.clone(),
+        };
+        if self.config.load_state.is_some() && self.config.opening_balances.is_some() {
+            return Err(anyhow!(
+                "a saved state can't be combined with opening balances"
+            ));
+        }
+        let mut engine = Engine::empty(self.config, inputs);
+        if let Some(path) = &engine.config.audit {
+            let writer = Writer::from_path(path)
+                .with_context(|| format!("can't create {}", path.display()))?;
+            engine.audit = Some(writer);
+        }
+        if let Some(path) = engine.config.load_state.clone() {
+            engine.load_state(&path)?;
+        }
+        if let Some(path) = engine.config.opening_balances.clone() {
+            engine.load_opening_balances(&path)?;
+        }
+        if let Some(path) = engine.config.dispute_amounts.clone() {
+            engine.load_dispute_amounts(&path)?;
+        }
+        Ok(engine)
+    }
+}
+
+impl Engine {
+    fn empty(config: Config, inputs: Vec<PathBuf>) -> Self {
+        Self {
+            inputs,
+            clients: HashMap::default(),
+            processed_transactions: Ledger::new(config.tx_cache_size),
+            disputed_transactions: HashMap::default(),
+            resolved_transactions: HashSet::default(),
+            charged_back_transactions: HashSet::default(),
+            early_settlements: HashMap::default(),
+            controls: HashMap::default(),
+            upcoming: HashSet::default(),
+            progress: None,
+            headers: ByteRecord::new(),
+            rejected: Vec::new(),
+            fees_collected: Money::ZERO,
+            stats: Stats::default(),
+            batches: BTreeMap::default(),
+            grouped: Vec::new(),
+            timeline: Vec::new(),
+            dispute_counts: HashMap::default(),
+            open_dispute_counts: HashMap::default(),
+            locked_queue: HashMap::default(),
+            events: Vec::new(),
+            epochs: HashMap::default(),
+            error_context: Vec::new(),
+            failures: Vec::new(),
+            audit: None,
+            shards: None,
+            config,
+        }
+    }
+}
+
+/// Columns every CSV input has, in the order of an input without a header row.
+const REQUIRED_COLUMNS: [&str; 4] = ["type", "client", "tx", "amount"];
+
+/// Lays a transaction out as a record under [`json::HEADERS`].
+fn to_record(transaction: &Transaction) -> ByteRecord {
+    let amount = transaction
+        .amount
+        .map(|a| a.to_string())
+        .unwrap_or_default();
+    let client = transaction.client.to_string();
+    let id = transaction.id.to_string();
+    let batch = transaction.batch.as_deref().unwrap_or_default();
+    let dest = transaction.dest.map(|d| d.to_string()).unwrap_or_default();
+    ByteRecord::from(vec![
+        transaction.kind.name(),
+        &client,
+        &id,
+        &amount,
+        batch,
+        &dest,
+    ])
+}
+
+/// Why a record can't be read, naming the field and its text where the CSV reader knows it.
+///
+/// The position is left out, it is reported as the line already.
+fn invalid_record_reason(headers: &ByteRecord, raw_record: &ByteRecord, e: &csv::Error) -> String {
+    let csv::ErrorKind::Deserialize { err, .. } = e.kind() else {
+        return e.to_string();
+    };
+    let Some(field) = err.field().map(|i| i as usize) else {
+        return err.to_string();
+    };
+    let name = headers.get(field).map(String::from_utf8_lossy);
+    let text = raw_record.get(field).map(String::from_utf8_lossy);
+    format!(
+        "invalid {} {:?}: {}",
+        name.unwrap_or_default(),
+        text.unwrap_or_default(),
+        err.kind()
+    )
+}
+
+/// Whether `path` is read through gzip, judging by its extension.
+pub fn is_gzip(path: &Path) -> bool {
+    path.extension().is_some_and(|ext| ext == "gz")
+}
+
+/// Field of the `column` epoch of a raw record.
+fn epoch_value<'a>(
+    column: &str,
+    headers: &ByteRecord,
+    raw_record: &'a ByteRecord,
+) -> Result<&'a [u8], Error> {
+    headers
+        .iter()
+        .position(|h| h == column.as_bytes())
+        .and_then(|i| raw_record.get(i))
+        .ok_or_else(|| anyhow!("tx id epoch column {column} is missing"))
+}
+
+/// Client id of a raw record, if it is readable.
+fn record_client(headers: &ByteRecord, raw_record: &ByteRecord) -> Option<u16> {
+    record_field(headers, raw_record, "client")
+}
+
+fn record_field<T: FromStr>(
+    headers: &ByteRecord,
+    raw_record: &ByteRecord,
+    column: &str,
+) -> Option<T> {
+    let column = headers.iter().position(|h| h == column.as_bytes())?;
+    std::str::from_utf8(raw_record.get(column)?)
+        .ok()?
+        .parse()
+        .ok()
+}
+
+/// Records of one worker of [`Engine::process_parallel`], sent with the header to read them.
+type Chunk = (ByteRecord, Vec<(ByteRecord, Position)>);
+
+/// Records sent to a worker at once.
+const CHUNK_SIZE: usize = 1024;
+
+/// Distributes records to the workers of [`Engine::process_parallel`] by client id.
+#[derive(Debug)]
+struct Shards {
+    senders: Vec<SyncSender<Chunk>>,
+    pending: Vec<Vec<(ByteRecord, Position)>>,
+}
+
+impl Shards {
+    /// Worker owning `client`, records without a readable client go to the first one.
+    fn of(client: Option<u16>, jobs: usize) -> usize {
+        client.map_or(0, |id| usize::from(id) % jobs)
+    }
+
+    fn push(
+        &mut self,
+        headers: &ByteRecord,
+        raw_record: &ByteRecord,
+        position: Position,
+    ) -> Result<(), Error> {
+        if record_field::<u16>(headers, raw_record, "dest").is_some() {
+            // The destination may belong to another worker.
+            return Err(anyhow!(
+                "transfers can't be processed in parallel, at {}",
+                position.line()
+            ));
+        }
+        let shard = Self::of(record_client(headers, raw_record), self.senders.len());
+        self.pending[shard].push((raw_record.clone(), position));
+        if self.pending[shard].len() >= CHUNK_SIZE {
+            self.send(shard, headers)?;
+        }
+        Ok(())
+    }
+
+    fn send(&mut self, shard: usize, headers: &ByteRecord) -> Result<(), Error> {
+        let records = std::mem::take(&mut self.pending[shard]);
+        self.senders[shard]
+            .send((headers.clone(), records))
+            .map_err(|_| anyhow!("worker #{shard} stopped"))
+    }
+
+    fn flush(&mut self, headers: &ByteRecord) -> Result<(), Error> {
+        for shard in 0..self.senders.len() {
+            if !self.pending[shard].is_empty() {
+                self.send(shard, headers)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Reads input paths, one per line, skipping blank lines and `#` comments.
+///
+/// Relative paths are resolved against the directory of the list, a leading byte order mark
+/// is skipped.
+fn read_file_list(path: &Path) -> Result<Vec<PathBuf>, Error> {
+    let content = fs::read_to_string(path)
+        .with_context(|| format!("can't read file list {}", path.display()))?;
+    let dir = path.parent().unwrap_or(Path::new(""));
+    let inputs = content
+        .trim_start_matches('\u{feff}')
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| dir.join(line))
+        .collect::<Vec<_>>();
+    if inputs.is_empty() {
+        return Err(anyhow!("file list {} is empty", path.display()));
+    }
+    if let Some(missing) = inputs.iter().find(|input| !input.is_file()) {
+        return Err(anyhow!(
+            "input {} listed in {} doesn't exist",
+            missing.display(),
+            path.display()
+        ));
+    }
+    Ok(inputs)
+}
+
+impl Engine {
+    pub fn builder() -> EngineBuilder {
+        EngineBuilder::default()
+    }
+
+    pub fn new(input: PathBuf) -> Result<Self, Error> {
+        Self::builder().input(input).build()
+    }
+
+    /// Builds an engine with default options and applies the CSV records of `input`.
+    pub fn from_reader(input: impl Read) -> Result<Self, Error> {
+        let mut engine = Self::builder().build()?;
+        engine.process_reader(input)?;
+        Ok(engine)
+    }
+
+    /// Builds an engine with default options and applies the transactions of `receiver` until
+    /// every sender is dropped.
+    pub fn from_receiver(receiver: Receiver<Transaction>) -> Result<Self, Error> {
+        let mut engine = Self::builder().build()?;
+        engine.process_receiver(receiver)?;
+        Ok(engine)
+    }
+
+    /// Applies the transactions of `receiver` instead of the configured input files, the
+    /// n-th one counting as line n.
+    pub fn process_receiver(&mut self, receiver: Receiver<Transaction>) -> Result<(), Error> {
+        if let Some(path) = self.config.controls.clone() {
+            self.load_controls(&path)?;
+        }
+        // Sent transactions go through the same parsing as JSON elements.
+        self.headers = ByteRecord::from(json::HEADERS.to_vec());
+        for (index, transaction) in (1..).zip(receiver) {
+            let mut position = Position::new();
+            position.set_line(index).set_record(index - 1);
+            self.queue_record(&to_record(&transaction), position)?;
+        }
+        self.finish_records()?;
+        Ok(())
+    }
+
+    /// Prepares applying transactions one at a time from a stream, see
+    /// [`crate::stream::ClientUpdates`].
+    #[cfg_attr(not(feature = "tokio"), allow(dead_code))]
+    pub(crate) fn start_stream(&mut self) -> Result<(), Error> {
+        if self.config.group_by_client {
+            return Err(anyhow!(
+                "client updates can't be streamed when grouping by client"
+            ));
+        }
+        if let Some(path) = self.config.controls.clone() {
+            self.load_controls(&path)?;
+        }
+        self.headers = ByteRecord::from(json::HEADERS.to_vec());
+        Ok(())
+    }
+
+    /// Applies the `index`th streamed transaction, adding the clients it changed to `updates`.
+    #[cfg_attr(not(feature = "tokio"), allow(dead_code))]
+    pub(crate) fn stream_record(
+        &mut self,
+        transaction: &Transaction,
+        index: u64,
+        updates: &mut VecDeque<(u16, ClientSnapshot)>,
+    ) -> Result<(), Error> {
+        let before = self.touched_clients(transaction);
+        let mut position = Position::new();
+        position.set_line(index).set_record(index - 1);
+        self.queue_record(&to_record(transaction), position)?;
+        for (id, before) in before {
+            match self.client_snapshot(id) {
+                Some(after) if before != Some(after) => updates.push_back((id, after)),
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// CSV settings shared by every reader, so byte ranges and controls parse records exactly
+    /// like the full input.
+    fn reader_builder(config: &Config) -> ReaderBuilder {
+        let mut builder = ReaderBuilder::new();
+        builder.trim(config.trim.into());
+        if let Some(delimiter) = config.delimiter {
+            builder.delimiter(delimiter as u8);
+        }
+        if let Some(capacity) = config.read_buffer {
+            builder.buffer_capacity(capacity);
+        }
+        builder
+    }
+
+    pub fn reader(config: &Config, path: &Path) -> Result<Reader<Box<dyn Read>>, Error> {
+        Ok(Self::reader_builder(config).from_reader(Self::open_input(path)?))
+    }
+
+    /// Opens `path` for reading, or stdin for [`STDIN`], decompressing `.gz` files.
+    fn open_input(path: &Path) -> Result<Box<dyn Read>, Error> {
+        if path == Path::new(STDIN) {
+            return Ok(Box::new(io::stdin().lock()));
+        }
+        let file = File::open(path).with_context(|| format!("can't open {}", path.display()))?;
+        if is_gzip(path) {
+            return Ok(Box::new(MultiGzDecoder::new(BufReader::new(file))));
+        }
+        Ok(Box::new(file))
+    }
+
+    fn load_opening_balances(&mut self, path: &Path) -> Result<(), Error> {
+        let mut reader = Self::reader(&self.config, path)?;
+        for record in reader.deserialize::<Client>() {
+            let mut client =
+                record.with_context(|| format!("invalid opening balance in {}", path.display()))?;
+            if !client.is_consistent(self.config.accounting_model) {
+                match self.config.on_total_mismatch {
+                    TotalMismatch::Reject => {
+                        return Err(anyhow!(
+                            "Client #{}: opening total doesn't match its balances",
+                            client.id()
+                        ))
+                    }
+                    TotalMismatch::Recompute => {
+                        warn!(client = client.id(); "Client #{}: recomputing opening total", client.id());
+                        client.recompute_total(self.config.accounting_model)?;
+                    }
+                    TotalMismatch::Warn => {
+                        warn!(client = client.id(); "Client #{}: opening total doesn't match", client.id())
+                    }
+                }
+            }
+            client.track_available();
+            self.clients.insert(client.id(), client);
+        }
+        Ok(())
+    }
+
+    /// Goes on from the state saved by [`Self::save_state`].
+    fn load_state(&mut self, path: &Path) -> Result<(), Error> {
+        let state = State::load(path)?;
+        if state.tx_id_scope != self.config.tx_id_scope {
+            return Err(anyhow!(
+                "state in {} was saved with tx id scope {:?}",
+                path.display(),
+                state.tx_id_scope
+            ));
+        }
+        for mut client in state.clients {
+            client.track_available();
+            self.clients.insert(client.id(), client);
+        }
+        for (key, tx) in state.processed {
+            if !matches!(tx.kind, Kind::Deposit | Kind::Withdrawal | Kind::Transfer) {
+                return Err(anyhow!(
+                    "invalid state in {}: tx #{} is recorded as a {}",
+                    path.display(),
+                    key.2,
+                    tx.kind.name()
+                ));
+            }
+            self.processed_transactions.insert(key, tx)?;
+        }
+        self.disputed_transactions = state.disputed.into_iter().collect();
+        if self.config.max_open_disputes.is_some() {
+            for tx in self.disputed_transactions.values() {
+                *self.open_dispute_counts.entry(tx.client).or_default() += 1;
+            }
+        }
+        self.resolved_transactions = state.resolved.into_iter().collect();
+        self.charged_back_transactions = state.charged_back.into_iter().collect();
+        self.dispute_counts = state.dispute_counts.into_iter().collect();
+        self.epochs = state.epochs.into_iter().zip(0..).collect();
+        self.fees_collected = state.fees_collected;
+        info!(
+            "Loaded {} clients and {} open disputes from {}",
+            self.clients.len(),
+            self.disputed_transactions.len(),
+            path.display()
+        );
+        Ok(())
+    }
+
+    /// Saves the state after the record at `line`, numbered by the applied records so far.
+    fn save_snapshot(&self, line: u64) -> Result<(), Error> {
+        let path = self
+            .config
+            .snapshot_dir
+            .join(format!("snapshot-{:08}.state", self.stats.applied));
+        info!(line; "Snapshot after record {} at line {line}", self.stats.applied);
+        self.save_state(&path)
+    }
+
+    /// Saves clients, applied txs and disputes to `path`, for a later run to go on from with
+    /// `load_state`.
+    ///
+    /// Only what can still be disputed or settled is saved, see [`Config::state_retention`].
+    pub fn save_state(&self, path: &Path) -> Result<(), Error> {
+        let mut clients = self.clients.values().cloned().collect::<Vec<_>>();
+        clients.sort_unstable_by_key(Client::id);
+        let mut processed = self.processed_transactions.entries()?;
+        let applied = processed.len();
+        // Their ids stay taken through `charged_back`.
+        processed.retain(|(key, _)| !self.charged_back_transactions.contains(key));
+        if let Some(window) = self.config.state_retention {
+            let newest = processed.iter().map(|(key, _)| key.2).max();
+            let oldest = newest.unwrap_or_default().saturating_sub(window);
+            processed
+                .retain(|(key, _)| key.2 > oldest || self.disputed_transactions.contains_key(key));
+        }
+        debug!(
+            "left {} of {applied} applied txs out of the state",
+            applied - processed.len()
+        );
+        processed.sort_unstable_by_key(|(key, _)| *key);
+        let mut disputed = self
+            .disputed_transactions
+            .iter()
+            .map(|(key, tx)| (*key, *tx))
+            .collect::<Vec<_>>();
+        disputed.sort_unstable_by_key(|(key, _)| *key);
+        // Only txs which are still saved can be settled again, the others are unknown then.
+        let mut resolved = processed
+            .iter()
+            .map(|(key, _)| *key)
+            .filter(|key| self.resolved_transactions.contains(key))
+            .collect::<Vec<_>>();
+        resolved.sort_unstable();
+        let mut charged_back = self
+            .charged_back_transactions
+            .iter()
+            .copied()
+            .collect::<Vec<_>>();
+        charged_back.sort_unstable();
+        let mut dispute_counts = self
+            .dispute_counts
+            .iter()
+            .map(|(client, counts)| (*client, *counts))
+            .collect::<Vec<_>>();
+        dispute_counts.sort_unstable_by_key(|(client, _)| *client);
+        let mut epochs = vec![Vec::new(); self.epochs.len()];
+        for (value, &index) in &self.epochs {
+            epochs[index as usize] = value.clone();
+        }
+        let state = State {
+            tx_id_scope: self.config.tx_id_scope,
+            clients,
+            processed,
+            disputed,
+            resolved,
+            charged_back,
+            dispute_counts,
+            epochs,
+            fees_collected: self.fees_collected,
+        };
+        state.save(path)?;
+        info!("Saved state to {}", path.display());
+        Ok(())
+    }
+
+    /// Registers deposits and withdrawals from `path` as disputable, without applying them.
+    ///
+    /// Together with opening balances this replays a pure dispute stream against a snapshot.
+    fn load_dispute_amounts(&mut self, path: &Path) -> Result<(), Error> {
+        let mut reader = Self::reader(&self.config, path)?;
+        let headers = self.config.header_aliases.apply(reader.byte_headers()?);
+        for raw_record in reader.byte_records() {
+            let raw_record = raw_record?;
+            let mut transaction = self
+                .config
+                .type_map
+                .deserialize(&headers, &raw_record)
+                .with_context(|| format!("invalid dispute amount in {}", path.display()))?;
+            if let Some(column) = &self.config.tx_id_epoch {
+                let value = epoch_value(column, &headers, &raw_record)?;
+                transaction.epoch = self.intern_epoch(value);
+            }
+            if !matches!(transaction.kind, Kind::Deposit | Kind::Withdrawal) {
+                warn!(
+                    tx = transaction.id, client = transaction.client;
+                    "tx #{}: {:?} in {} ignored, only deposits and withdrawals are registered",
+                    transaction.id,
+                    transaction.kind,
+                    path.display()
+                );
+                continue;
+            }
+            match transaction.validate_amount() {
+                Ok(amount) => self.add_transaction(&transaction, amount)?,
+                // The amount is all a registered tx is for.
+                Err(e @ EngineError::MissingAmount { .. }) => return Err(e.into()),
+                Err(e) if e.is_recoverable() => continue,
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Ok(())
+    }
+
+    pub fn process(&mut self) -> Result<(), Error> {
+        #[cfg(feature = "tracing")]
+        let _run = tracing::info_span!("run", inputs = self.inputs.len()).entered();
+        let postgres = self.config.postgres.clone();
+        if postgres.is_some() {
+            if !self.inputs.is_empty() {
+                return Err(anyhow!(
+                    "a postgres input can't be combined with input files"
+                ));
+            }
+            if self.config.two_pass {
+                return Err(anyhow!("two passes require input files"));
+            }
+        } else if self.inputs.is_empty() {
+            return Err(anyhow!("input is not set"));
+        }
+        if let Some(path) = self.config.controls.clone() {
+            self.load_controls(&path)?;
+        }
+        if self.config.two_pass {
+            self.index_upcoming()?;
+        }
+        match postgres {
+            Some(url) => self.process_postgres(&url)?,
+            None => self.read_inputs()?,
+        }
+        self.finish_records()?;
+        Ok(())
+    }
+
+    /// First pass of `two_pass`, collecting the keys of every deposit and withdrawal.
+    fn index_upcoming(&mut self) -> Result<(), Error> {
+        if self.config.input_format != InputFormat::Csv {
+            return Err(anyhow!("two passes require CSV input"));
+        }
+        if self.inputs.iter().any(|input| input == Path::new(STDIN)) {
+            return Err(anyhow!("two passes can't read stdin"));
+        }
+        for input in self.inputs.clone() {
+            let mut reader = Self::reader_builder(&self.config)
+                .has_headers(!self.config.no_header)
+                .from_reader(Self::open_input(&input)?);
+            let headers = self.input_headers(&mut reader)?;
+            for raw_record in reader.byte_records() {
+                // Broken records are reported by the second pass.
+                let Ok(mut raw_record) = raw_record else {
+                    continue;
+                };
+                if self.config.skip_columns > 0 {
+                    raw_record = self.skip_columns(&raw_record);
+                }
+                let Ok(mut transaction) = self.config.type_map.deserialize(&headers, &raw_record)
+                else {
+                    continue;
+                };
+                if !matches!(transaction.kind, Kind::Deposit | Kind::Withdrawal) {
+                    continue;
+                }
+                if let Some(column) = &self.config.tx_id_epoch {
+                    let value = epoch_value(column, &headers, &raw_record)?;
+                    transaction.epoch = self.intern_epoch(value);
+                }
+                self.upcoming.insert(self.key(&transaction));
+            }
+        }
+        info!("Indexed {} deposits and withdrawals", self.upcoming.len());
+        Ok(())
+    }
+
+    /// Applies the inputs on `jobs` worker threads, each owning the clients hashed to it.
+    ///
+    /// Clients are independent, so balances match [`Self::process`], but duplicate tx ids are
+    /// only detected within a worker. Rejects are listed worker by worker.
+    pub fn process_parallel(&mut self, jobs: usize) -> Result<(), Error> {
+        #[cfg(feature = "tracing")]
+        let _run = tracing::info_span!("run", inputs = self.inputs.len(), jobs).entered();
+        if self.inputs.is_empty() {
+            return Err(anyhow!("input is not set"));
+        }
+        if jobs == 0 {
+            return Err(anyhow!("at least one job is required"));
+        }
+        if self.config.controls.is_some()
+            || self.config.group_by_client
+            || self.config.max_clients.is_some()
+            || self.config.explain_tx.is_some()
+            || self.config.tx_id_epoch.is_some()
+            || self.config.tx_cache_size.is_some()
+            || self.config.two_pass
+            || self.config.load_state.is_some()
+            || self.config.audit.is_some()
+            || self.config.snapshot_every.is_some()
+        {
+            return Err(anyhow!(
+                "parallel processing can't be combined with controls, grouping by client, a \
+                 client limit, tx id epochs, a tx cache size, two passes, a saved state, an \
+                 audit or snapshots"
+            ));
+        }
+        let mut workers = (0..jobs)
+            .map(|_| Engine::empty(self.config.clone(), Vec::new()))
+            .collect::<Vec<_>>();
+        for (id, client) in self.clients.drain() {
+            workers[Shards::of(Some(id), jobs)]
+                .clients
+                .insert(id, client);
+        }
+        for (key, tx) in self.processed_transactions.drain() {
+            let worker = &mut workers[Shards::of(Some(tx.client), jobs)];
+            worker.processed_transactions.insert(key, tx)?;
+        }
+
+        let (senders, receivers): (Vec<_>, Vec<_>) =
+            (0..jobs).map(|_| mpsc::sync_channel::<Chunk>(4)).unzip();
+        let (read, workers) = thread::scope(|scope| {
+            let handles = workers
+                .into_iter()
+                .zip(receivers)
+                .map(|(mut worker, receiver)| {
+                    scope.spawn(move || -> Result<Engine, Error> {
+                        for (headers, records) in receiver {
+                            worker.headers = headers;
+                            for (raw_record, position) in records {
+                                worker.process_record(&raw_record, position)?;
+                            }
+                        }
+                        Ok(worker)
+                    })
+                })
+                .collect::<Vec<_>>();
+            self.shards = Some(Shards {
+                senders,
+                pending: vec![Vec::new(); jobs],
+            });
+            let read = self.read_inputs().and_then(|()| {
+                let headers = self.headers.clone();
+                self.shards
+                    .as_mut()
+                    .expect("shards are set")
+                    .flush(&headers)
+            });
+            // Closes the channels, so the workers finish.
+            self.shards = None;
+            let workers = handles
+                .into_iter()
+                .map(|handle| handle.join().expect("worker panicked"))
+                .collect::<Result<Vec<_>, _>>();
+            (read, workers)
+        });
+        // A worker failing stops the reader too, its error is the cause.
+        for worker in workers? {
+            self.merge(worker)?;
+        }
+        read?;
+        self.finish_records()?;
+        Ok(())
+    }
+
+    /// Takes over the state of a worker of [`Self::process_parallel`].
+    fn merge(&mut self, worker: Engine) -> Result<(), Error> {
+        self.clients.extend(worker.clients);
+        self.processed_transactions
+            .extend(worker.processed_transactions);
+        self.disputed_transactions
+            .extend(worker.disputed_transactions);
+        self.resolved_transactions
+            .extend(worker.resolved_transactions);
+        self.charged_back_transactions
+            .extend(worker.charged_back_transactions);
+        self.early_settlements.extend(worker.early_settlements);
+        self.rejected.extend(worker.rejected);
+        self.failures.extend(worker.failures);
+        self.events.extend(worker.events);
+        self.dispute_counts.extend(worker.dispute_counts);
+        self.open_dispute_counts.extend(worker.open_dispute_counts);
+        self.fees_collected = self.fees_collected.checked_add(worker.fees_collected)?;
+        self.stats.records += worker.stats.records;
+        self.stats.applied += worker.stats.applied;
+        self.stats.skipped += worker.stats.skipped;
+        self.stats.sampled_out += worker.stats.sampled_out;
+        self.stats.duplicates += worker.stats.duplicates;
+        self.stats.deposits += worker.stats.deposits;
+        self.stats.withdrawals += worker.stats.withdrawals;
+        self.stats.disputes += worker.stats.disputes;
+        self.stats.resolves += worker.stats.resolves;
+        self.stats.chargebacks += worker.stats.chargebacks;
+        self.stats.unfreezes += worker.stats.unfreezes;
+        self.stats.transfers += worker.stats.transfers;
+        for (name, stats) in worker.batches {
+            let batch = Self::batch(&mut self.batches, &name);
+            batch.records += stats.records;
+            batch.applied += stats.applied;
+            batch.deposits = batch.deposits.checked_add(stats.deposits)?;
+            batch.withdrawals = batch.withdrawals.checked_add(stats.withdrawals)?;
+        }
+        Ok(())
+    }
+
+    /// Reads every input in order, queueing its records.
+    fn read_inputs(&mut self) -> Result<(), Error> {
+        for (i, input) in self.inputs.clone().iter().enumerate() {
+            info!("Processing {}", input.display());
+            if self.config.input_format == InputFormat::JsonArray {
+                self.process_json(input)?;
+                continue;
+            }
+            let mut reader = Self::reader_builder(&self.config)
+                .has_headers(!self.config.no_header)
+                .from_reader(Self::open_input(input)?);
+            let headers = self
+                .input_headers(&mut reader)
+                .with_context(|| format!("can't read {}", input.display()))?;
+            if i == 0 {
+                self.headers = headers;
+            } else if headers != self.headers {
+                // Rejects are replayed under a single header.
+                return Err(anyhow!(
+                    "header of {} differs from the first input",
+                    input.display()
+                ));
+            }
+            if self.config.progress {
+                // Decompressed bytes can't be compared to the file length.
+                let len = (input != Path::new(STDIN) && !is_gzip(input))
+                    .then(|| fs::metadata(input).ok().map(|metadata| metadata.len()))
+                    .flatten();
+                self.progress = Some(Progress::new(input.display().to_string(), len));
+            }
+            if self.process_records(&mut reader, 0, None)? == 0 {
+                // Not an error, but easily the wrong file.
+                info!("{}: 0 transactions processed", input.display());
+            }
+            if let Some(mut progress) = self.progress.take() {
+                progress.finish();
+            }
+        }
+        Ok(())
+    }
+
+    /// Applies the CSV records of `input` instead of the configured input files.
+    pub fn process_reader(&mut self, input: impl Read) -> Result<(), Error> {
+        if self.config.two_pass {
+            return Err(anyhow!("two passes require input files"));
+        }
+        if let Some(path) = self.config.controls.clone() {
+            self.load_controls(&path)?;
+        }
+        let mut reader = Self::reader_builder(&self.config)
+            .has_headers(!self.config.no_header)
+            .from_reader(input);
+        self.headers = self.input_headers(&mut reader)?;
+        if self.process_records(&mut reader, 0, None)? == 0 {
+            info!("0 transactions processed");
+        }
+        self.finish_records()?;
+        Ok(())
+    }
+
+    /// Processes only the records starting within bytes `[start, end)` of the input.
+    ///
+    /// `start` is moved forward to the next line boundary unless it already is one. Disputes
+    /// referencing transactions outside of the range are skipped.
+    pub fn process_range(&mut self, start: u64, end: u64) -> Result<(), Error> {
+        let [input] = self
+            .inputs
+            .clone()
+            .try_into()
+            .map_err(|_| anyhow!("byte ranges require a single input"))?;
+        #[cfg(feature = "tracing")]
+        let _run = tracing::info_span!("run", input = %input.display()).entered();
+        if self.config.input_format != InputFormat::Csv {
+            return Err(anyhow!("byte ranges require CSV input"));
+        }
+        if input == Path::new(STDIN) {
+            return Err(anyhow!("byte ranges can't be read from stdin"));
+        }
+        if is_gzip(&input) {
+            return Err(anyhow!("byte ranges can't be read from gzip input"));
+        }
+        if self.config.two_pass {
+            return Err(anyhow!("byte ranges can't be read in two passes"));
+        }
+        self.headers = self.input_headers(&mut Self::reader(&self.config, &input)?)?;
+
+        let mut file = BufReader::new(File::open(&input)?);
+        let mut offset = start;
+        if start > 0 {
+            file.seek(SeekFrom::Start(start - 1))?;
+            offset = start - 1 + file.read_until(b'\n', &mut Vec::new())? as u64;
+        }
+        let mut reader = Self::reader_builder(&self.config)
+            .has_headers(offset == 0 && !self.config.no_header)
+            .from_reader(file);
+        self.process_records(&mut reader, offset, Some(end))?;
+        self.finish_records()?;
+        Ok(())
+    }
+
+    /// Applies records of `reader`, which starts at byte `offset` of the input, returning how
+    /// many were read.
+    fn process_records<R: Read>(
+        &mut self,
+        reader: &mut Reader<R>,
+        offset: u64,
+        end: Option<u64>,
+    ) -> Result<u64, Error> {
+        let mut raw_record = ByteRecord::new();
+        let context = self.config.first_error_context.unwrap_or_default();
+        let mut before = VecDeque::with_capacity(context);
+        let mut records = 0;
+
+        loop {
+            match reader.read_byte_record(&mut raw_record) {
+                Ok(true) => {}
+                Ok(false) => break,
+                Err(e) => {
+                    self.check_truncation(reader, e)?;
+                    break;
+                }
+            }
+            let mut position = raw_record
+                .position()
+                .expect("record has not position")
+                .clone();
+            position.set_byte(position.byte() + offset);
+            if end.is_some_and(|end| position.byte() >= end) {
+                break;
+            }
+            records += 1;
+            if let Some(progress) = &mut self.progress {
+                progress.update(position.byte());
+            }
+            if self.config.skip_columns > 0 {
+                raw_record = self.skip_columns(&raw_record);
+            }
+            let line = position.line();
+            if let Err(e) = self.queue_record(&raw_record, position) {
+                if self.config.first_error_context.is_some() {
+                    self.collect_error_context(reader, before, &raw_record, line, context);
+                }
+                return Err(e);
+            }
+            if context > 0 {
+                if before.len() == context {
+                    before.pop_front();
+                }
+                before.push_back((line, raw_record.clone()));
+            }
+        }
+        Ok(records)
+    }
+
+    /// Header of an input with `skip_columns` dropped and `header_aliases` applied, or the
+    /// fixed columns with `no_header`.
+    ///
+    /// Fails before any record is read if the input is empty or a [`REQUIRED_COLUMNS`] one is
+    /// missing, extra columns are ignored.
+    fn input_headers<R: Read>(&self, reader: &mut Reader<R>) -> Result<ByteRecord, Error> {
+        if self.config.no_header {
+            return Ok(ByteRecord::from(REQUIRED_COLUMNS.to_vec()));
+        }
+        let headers = self
+            .config
+            .header_aliases
+            .apply(&self.skip_columns(reader.byte_headers()?));
+        let looks_like_data = !headers.iter().any(|h| h == b"type")
+            && headers
+                .get(0)
+                .and_then(|first| std::str::from_utf8(first).ok())
+                .is_some_and(|first| self.config.type_map.resolve(first.trim()).is_ok());
+        if headers.is_empty() {
+            return Err(anyhow!("input has no CSV header"));
+        }
+        let missing = REQUIRED_COLUMNS
+            .iter()
+            .find(|column| !headers.iter().any(|h| h == column.as_bytes()));
+        if let Some(column) = missing {
+            let hint = if looks_like_data {
+                ", the input seems to have no header row"
+            } else {
+                ""
+            };
+            return Err(anyhow!(
+                "missing required column: {column} (header: {}){hint}",
+                join_fields(&headers)
+            ));
+        }
+        Ok(headers)
+    }
+
+    /// Drops the leading `skip_columns` fields of an input record or header.
+    fn skip_columns(&self, record: &ByteRecord) -> ByteRecord {
+        record.iter().skip(self.config.skip_columns).collect()
+    }
+
+    /// Keeps the records `before` the failing one and reads up to `after` records past it.
+    fn collect_error_context<R: Read>(
+        &mut self,
+        reader: &mut Reader<R>,
+        before: VecDeque<(u64, ByteRecord)>,
+        failed: &ByteRecord,
+        line: u64,
+        after: usize,
+    ) {
+        let context = |line, record, failed| ContextRecord {
+            line,
+            record,
+            failed,
+        };
+        self.error_context = before
+            .into_iter()
+            .map(|(line, record)| context(line, record, false))
+            .collect();
+        self.error_context.push(context(line, failed.clone(), true));
+        let mut record = ByteRecord::new();
+        for _ in 0..after {
+            // The context is best effort, a read error just ends it.
+            if !reader.read_byte_record(&mut record).unwrap_or(false) {
+                break;
+            }
+            let line = record.position().map_or(0, Position::line);
+            self.error_context
+                .push(context(line, record.clone(), false));
+        }
+    }
+
+    /// Treats a record with missing fields at the very end of the input as a truncated file.
+    ///
+    /// Any other read error, or a short record followed by more input, stays fatal.
+    fn check_truncation<R: Read>(
+        &mut self,
+        reader: &mut Reader<R>,
+        error: csv::Error,
+    ) -> Result<(), Error> {
+        let csv::ErrorKind::UnequalLengths { pos, .. } = error.kind() else {
+            return Err(error.into());
+        };
+        let line = pos.as_ref().map_or(0, Position::line);
+        if reader.read_byte_record(&mut ByteRecord::new())? {
+            return Err(error.into());
+        }
+        let reason = format!("partial record at line {line}, the input is probably truncated");
+        match self.config.on_truncation {
+            Truncation::Error => Err(anyhow!(reason)),
+            Truncation::Warn => {
+                warn!(line = line; "{reason}");
+                self.stats.records += 1;
+                self.stats.skipped += 1;
+                Ok(())
+            }
+        }
+    }
+
+    /// Applies the elements of a JSON array input, mapped onto the CSV schema.
+    fn process_json(&mut self, input: &Path) -> Result<(), Error> {
+        self.headers = ByteRecord::from(json::HEADERS.to_vec());
+        let mut index = 0;
+        json::for_each_element(Self::open_input(input)?, input, |element| {
+            index += 1;
+            let mut position = Position::new();
+            position.set_line(index).set_record(index - 1);
+            self.queue_record(&json::to_record(&element), position)
+        })
+    }
+
+    /// Applies the rows of the configured query, the row number taking the place of the line.
+    #[cfg(feature = "postgres")]
+    fn process_postgres(&mut self, url: &str) -> Result<(), Error> {
+        let query = self
+            .config
+            .query
+            .clone()
+            .ok_or_else(|| anyhow!("a postgres input requires a query"))?;
+        // The connection string may carry a password.
+        info!("Processing the postgres query");
+        let mut index = 0;
+        crate::database::for_each_record(url, &query, |headers, record| {
+            if index == 0 {
+                let headers = self.config.header_aliases.apply(headers);
+                if let Some(column) = REQUIRED_COLUMNS
+                    .iter()
+                    .find(|column| !headers.iter().any(|h| h == column.as_bytes()))
+                {
+                    return Err(anyhow!(
+                        "missing required column: {column} (query columns: {})",
+                        join_fields(&headers)
+                    ));
+                }
+                self.headers = headers;
+            }
+            index += 1;
+            let mut position = Position::new();
+            position.set_line(index).set_record(index - 1);
+            self.queue_record(record, position)
+        })
+    }
+
+    #[cfg(not(feature = "postgres"))]
+    fn process_postgres(&mut self, _url: &str) -> Result<(), Error> {
+        Err(anyhow!(
+            "postgres input needs a build with the `postgres` feature"
+        ))
+    }
+
+    fn process_record(&mut self, raw_record: &ByteRecord, position: Position) -> Result<(), Error> {
+        self.stats.records += 1;
+        let mut transaction = match self.config.type_map.deserialize(&self.headers, raw_record) {
+            Ok(transaction) => transaction,
+            Err(e) => {
+                let reason = invalid_record_reason(&self.headers, raw_record, &e);
+                if !self.check_kind(raw_record, &position)? {
+                    warn!(
+                        line = position.line();
+                        "{reason} at line {}, skipping the record",
+                        position.line()
+                    );
+                }
+                self.stats.skipped += 1;
+                self.reject(raw_record, position.line(), reason);
+                return Ok(());
+            }
+        };
+        if let Some(column) = &self.config.tx_id_epoch {
+            let value = epoch_value(column, &self.headers, raw_record)?;
+            transaction.epoch = self.intern_epoch(value);
+        }
+        if !self.in_sample(transaction.id) {
+            self.stats.sampled_out += 1;
+            return Ok(());
+        }
+        if self.config.negative_deposit_as_withdrawal {
+            transaction.negative_deposit_as_withdrawal();
+        }
+        let key = self.key(&transaction);
+        if self.upcoming.is_empty() {
+            // Only filled by `two_pass`, saves hashing every key for nothing otherwise.
+        } else if matches!(transaction.kind, Kind::Deposit | Kind::Withdrawal) {
+            self.upcoming.remove(&key);
+        } else if self.upcoming.contains(&key) {
+            // Applied right after its tx like a control, and counted then.
+            debug!(
+                "tx #{}: {:?} at {} precedes its tx, deferring",
+                transaction.id,
+                transaction.kind,
+                position.line()
+            );
+            self.stats.records -= 1;
+            self.controls
+                .entry(key)
+                .or_default()
+                .push((transaction, position, raw_record.clone()));
+            return Ok(());
+        }
+        self.apply_record(&transaction, position, raw_record)
+    }
+
+    /// Applies a record, then whatever waited for it: the controls of its tx, the settlement
+    /// deferred until its dispute opened, or the records queued while its client was locked.
+    fn apply_record(
+        &mut self,
+        transaction: &Transaction,
+        position: Position,
+        raw_record: &ByteRecord,
+    ) -> Result<(), Error> {
+        if self.queue_locked(transaction, &position, raw_record)
+            || self.defer_settlement(transaction, &position, raw_record)?
+        {
+            return Ok(());
+        }
+        self.apply(transaction, position, raw_record)?;
+        if !self.controls.is_empty() && matches!(transaction.kind, Kind::Deposit | Kind::Withdrawal)
+        {
+            self.apply_controls(self.key(transaction))?;
+        }
+        if !self.early_settlements.is_empty() && matches!(transaction.kind, Kind::Dispute) {
+            self.apply_settlement(self.key(transaction))?;
+        }
+        if matches!(transaction.kind, Kind::Unfreeze) && !self.locked_queue.is_empty() {
+            self.replay_locked(transaction.client)?;
+        }
+        Ok(())
+    }
+
+    /// Holds back a record of a locked client with the `queue` locked policy, returning whether
+    /// it did.
+    fn queue_locked(
+        &mut self,
+        transaction: &Transaction,
+        position: &Position,
+        raw_record: &ByteRecord,
+    ) -> bool {
+        if self.config.locked_policy != LockedPolicy::Queue
+            || matches!(transaction.kind, Kind::Unfreeze)
+            || !self
+                .clients
+                .get(&transaction.client)
+                .is_some_and(Client::is_locked)
+        {
+            return false;
+        }
+        debug!(
+            "tx #{}: {:?} at {} targets locked client #{}, queueing it",
+            transaction.id,
+            transaction.kind,
+            position.line(),
+            transaction.client
+        );
+        // Counted once it is replayed or rejected.
+        self.stats.records -= 1;
+        self.locked_queue
+            .entry(transaction.client)
+            .or_default()
+            .push((transaction.clone(), position.clone(), raw_record.clone()));
+        true
+    }
+
+    /// Holds back a resolve or chargeback of a tx not known yet with the `defer` early
+    /// settlement policy, returning whether it did.
+    ///
+    /// Only one settlement waits per tx, a second one is applied and rejected as unknown.
+    fn defer_settlement(
+        &mut self,
+        transaction: &Transaction,
+        position: &Position,
+        raw_record: &ByteRecord,
+    ) -> Result<bool, Error> {
+        if self.config.early_settlement != EarlySettlement::Defer
+            || !matches!(transaction.kind, Kind::Resolve | Kind::Chargeback)
+        {
+            return Ok(false);
+        }
+        let key = self.key(transaction);
+        if self.processed_transactions.get(&key)?.is_some() {
+            return Ok(false);
+        }
+        let Entry::Vacant(e) = self.early_settlements.entry(key) else {
+            return Ok(false);
+        };
+        warn!(
+            tx = transaction.id, client = transaction.client, line = position.line();
+            "tx #{}: {} at {} references an unknown tx, deferring it until a dispute opens",
+            transaction.id,
+            transaction.kind.name(),
+            position.line()
+        );
+        // Counted once it is applied or rejected.
+        self.stats.records -= 1;
+        e.insert((transaction.clone(), position.clone(), raw_record.clone()));
+        Ok(true)
+    }
+
+    /// Applies the settlement deferred for `key`, once a dispute of its tx opened.
+    fn apply_settlement(&mut self, key: TxKey) -> Result<(), Error> {
+        if !self.disputed_transactions.contains_key(&key) {
+            return Ok(());
+        }
+        let Some((transaction, position, raw_record)) = self.early_settlements.remove(&key) else {
+            return Ok(());
+        };
+        debug!(
+            "applying deferred {:?} for tx #{}",
+            transaction.kind, transaction.id
+        );
+        self.stats.records += 1;
+        self.apply_record(&transaction, position, &raw_record)
+    }
 
-    reader: Reader<File>,
-}
+    /// Applies the records queued for `client` in input order, once it was unfrozen.
+    ///
+    /// A replayed record locking the client again queues the rest anew.
+    fn replay_locked(&mut self, client: u16) -> Result<(), Error> {
+        if self.clients.get(&client).is_some_and(Client::is_locked) {
+            return Ok(());
+        }
+        let queued = self.locked_queue.remove(&client).unwrap_or_default();
+        if !queued.is_empty() {
+            info!(client; "Client #{client}: replaying {} queued records", queued.len());
+        }
+        for (transaction, position, raw_record) in queued {
+            self.stats.records += 1;
+            self.apply_record(&transaction, position, &raw_record)?;
+        }
+        Ok(())
+    }
 
-impl Engine {
-    pub fn new(input: PathBuf) -> Result<Self, Error> {
-        let reader = Self::reader(&input)?;
-        let engine = Self {
-            input,
-            reader,
-            clients: HashMap::default(),
-            processed_transactions: HashMap::default(),
-            disputed_transactions: HashMap::default(),
+    /// Whether tx `id` belongs to the configured sample.
+    ///
+    /// The decision depends on the id only, so a dispute follows the tx it references.
+    fn in_sample(&self, id: TxId) -> bool {
+        let Some(rate) = self.config.sample_rate else {
+            return true;
         };
-        Ok(engine)
+        // splitmix64 finalizer, an even spread even for sequential ids.
+        let mut x = self.config.seed ^ u64::from(id);
+        x = x.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        x = (x ^ (x >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        x = (x ^ (x >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        x ^= x >> 31;
+        (x as f64 / u64::MAX as f64) < rate
     }
 
-    pub fn reader(path: &Path) -> Result<Reader<File>, Error> {
-        ReaderBuilder::new()
-            .trim(Trim::All)
-            .from_path(path)
-            .with_context(|| format!("can't initialize reader from path {}", path.display()))
+    /// Applies a record now, or once the input is read with `group_by_client`.
+    fn queue_record(&mut self, raw_record: &ByteRecord, position: Position) -> Result<(), Error> {
+        if let Some(shards) = &mut self.shards {
+            return shards.push(&self.headers, raw_record, position);
+        }
+        if self.config.group_by_client {
+            self.grouped.push((raw_record.clone(), position));
+            Ok(())
+        } else {
+            self.process_record(raw_record, position)
+        }
     }
 
-    pub fn process(mut self) -> Result<HashMap<u16, Client>, Error> {
-        let mut reader = Self::reader(&self.input)?;
-        let mut raw_record = ByteRecord::new();
-        let headers = reader.byte_headers()?.clone();
+    /// Applies the held back records client by client, each client's records in input order.
+    ///
+    /// Records without a readable client id go last, they are rejected anyway.
+    fn process_grouped(&mut self) -> Result<(), Error> {
+        let mut grouped = std::mem::take(&mut self.grouped);
+        grouped.sort_by_cached_key(|(raw_record, _)| {
+            let client = record_client(&self.headers, raw_record);
+            (client.is_none(), client)
+        });
+        for (raw_record, position) in grouped {
+            self.process_record(&raw_record, position)?;
+        }
+        Ok(())
+    }
+
+    /// Reports whatever is left pending once the input is exhausted.
+    pub(crate) fn finish_records(&mut self) -> Result<(), Error> {
+        self.process_grouped()?;
+        // Leftovers are reported in key order, so the rejects and the log are reproducible.
+        let mut orphans = std::mem::take(&mut self.controls)
+            .into_iter()
+            .collect::<Vec<_>>();
+        orphans.sort_by_key(|(id, _)| *id);
+        for (transaction, position, raw_record) in orphans.into_iter().flat_map(|(_, c)| c) {
+            self.stats.records += 1;
+            self.stats.skipped += 1;
+            self.reject(
+                &raw_record,
+                position.line(),
+                "control references an unknown tx".to_owned(),
+            );
+            warn!(
+                tx = transaction.id, client = transaction.client, line = position.line();
+                "tx #{}: control {:?} at {} references an unknown tx",
+                transaction.id,
+                transaction.kind,
+                position.line()
+            );
+        }
+        let mut queued = std::mem::take(&mut self.locked_queue)
+            .into_iter()
+            .collect::<Vec<_>>();
+        queued.sort_by_key(|(client, _)| *client);
+        for (transaction, position, raw_record) in queued.into_iter().flat_map(|(_, q)| q) {
+            self.stats.records += 1;
+            self.stats.skipped += 1;
+            let reason = format!(
+                "queued for locked client #{}, which was never unfrozen",
+                transaction.client
+            );
+            warn!(
+                tx = transaction.id, client = transaction.client, line = position.line();
+                "tx #{}: {:?} at {} {reason}",
+                transaction.id,
+                transaction.kind,
+                position.line()
+            );
+            self.reject(&raw_record, position.line(), reason);
+        }
+        let mut unmatched = std::mem::take(&mut self.early_settlements)
+            .into_iter()
+            .collect::<Vec<_>>();
+        unmatched.sort_by_key(|(id, _)| *id);
+        for (_, (transaction, position, raw_record)) in unmatched {
+            self.stats.records += 1;
+            self.stats.skipped += 1;
+            let reason = "deferred until a dispute which never came".to_owned();
+            warn!(
+                tx = transaction.id, client = transaction.client, line = position.line();
+                "tx #{}: {:?} at {} {reason}",
+                transaction.id,
+                transaction.kind,
+                position.line()
+            );
+            self.reject(&raw_record, position.line(), reason);
+        }
+        if let Some(audit) = &mut self.audit {
+            audit.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Balances of the clients a transaction may change, before it is applied.
+    fn touched_clients(&self, transaction: &Transaction) -> Vec<(u16, Option<ClientSnapshot>)> {
+        let mut clients = vec![transaction.client];
+        if let (Kind::Transfer, Some(dest)) = (transaction.kind, transaction.dest) {
+            clients.push(dest);
+        }
+        clients
+            .into_iter()
+            .map(|id| (id, self.client_snapshot(id)))
+            .collect()
+    }
+
+    /// Writes an [`AuditRow`] for every client whose balances differ from `before`.
+    fn write_audit(
+        &mut self,
+        transaction: &Transaction,
+        line: u64,
+        before: &[(u16, Option<ClientSnapshot>)],
+    ) -> Result<(), Error> {
+        for &(id, before) in before {
+            let Some(after) = self.client_snapshot(id) else {
+                continue;
+            };
+            if before == Some(after) {
+                continue;
+            }
+            let before = before.unwrap_or_default();
+            let row = AuditRow {
+                line,
+                tx: transaction.id,
+                kind: transaction.kind,
+                client: id,
+                available_delta: after.available.checked_sub(before.available)?,
+                held_delta: after.held.checked_sub(before.held)?,
+                available: after.available,
+                held: after.held,
+                total: after.total,
+                locked: after.locked,
+            };
+            if let Some(audit) = &mut self.audit {
+                audit.serialize(row)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks what every record has to keep true of the clients it touched, with `paranoid`.
+    ///
+    /// A violation is an engine bug rather than bad input, so it fails the run. Checks which
+    /// options legitimately relax, e.g. a negative `balance_floor`, are left out then.
+    fn check_invariants(
+        &self,
+        transaction: &Transaction,
+        line: u64,
+        before: &[(u16, Option<ClientSnapshot>)],
+        applied: bool,
+    ) -> Result<(), Error> {
+        for &(id, before) in before {
+            let before = before.unwrap_or_default();
+            let after = self.client_snapshot(id).unwrap_or_default();
+            let drift = |c: ClientSnapshot| {
+                let counted = match self.config.accounting_model {
+                    AccountingModel::Standard => c.available.checked_add(c.held)?,
+                    AccountingModel::Alt => c.available,
+                };
+                c.total.checked_sub(counted)
+            };
+            let violation = if !applied {
+                (after != before).then_some("a skipped record changed the balances")
+            } else if drift(after)? != drift(before)? {
+                Some("total no longer matches the balances it is made of")
+            } else if after.held < Money::ZERO
+                && before.held >= Money::ZERO
+                && self.config.resolve_held != ResolveHeld::Force
+            {
+                Some("held funds went negative")
+            } else if !after.locked
+                && after.available < Money::ZERO
+                && before.available >= Money::ZERO
+                && self.config.balance_floor.is_none()
+            {
+                Some("available funds of an active client went negative")
+            } else if before.locked
+                && !matches!(transaction.kind, Kind::Unfreeze)
+                && after != before
+            {
+                Some("the balances of a locked client changed")
+            } else {
+                None
+            };
+            if let Some(violation) = violation {
+                return Err(anyhow!(
+                    "invariant violated by tx #{} ({:?}) at line {line} for client #{id}: \
+                     {violation}, {before:?} became {after:?}",
+                    transaction.id,
+                    transaction.kind
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    fn record_step(
+        &mut self,
+        transaction: &Transaction,
+        line: u64,
+        applied: bool,
+    ) -> Result<(), Error> {
+        let amount = match transaction.kind {
+            Kind::Deposit | Kind::Withdrawal | Kind::Transfer => transaction.amount,
+            Kind::Unfreeze => None,
+            _ => self
+                .processed_transactions
+                .get(&self.key(transaction))?
+                .map(|tx| tx.amount),
+        };
+        let client = self.clients.get(&transaction.client);
+        self.timeline.push(Step {
+            line,
+            kind: transaction.kind,
+            client: transaction.client,
+            amount,
+            applied,
+            available: client.map(Client::available).unwrap_or_default(),
+            held: client.map(Client::held).unwrap_or_default(),
+        });
+        Ok(())
+    }
+
+    fn reject(&mut self, raw_record: &ByteRecord, line: u64, reason: String) {
+        if self.config.record_events {
+            self.events.push(Event {
+                kind: EventKind::Reject,
+                level: "warn",
+                line: Some(line),
+                client: record_client(&self.headers, raw_record),
+                tx: record_field(&self.headers, raw_record, "tx"),
+                reason: reason.clone(),
+                detail: Some(join_fields(raw_record)),
+            });
+        }
+        self.rejected.push(Rejection {
+            line,
+            reason,
+            record: raw_record.clone(),
+        });
+    }
+
+    /// Logs a record which is accepted without effect.
+    fn warning(&mut self, transaction: &Transaction, line: u64, reason: String) {
+        warn!(tx = transaction.id, client = transaction.client, line = line; "{reason}");
+        if self.config.record_events {
+            self.events.push(Event {
+                kind: EventKind::Warning,
+                level: "warn",
+                line: Some(line),
+                client: Some(transaction.client),
+                tx: Some(transaction.id),
+                reason,
+                detail: None,
+            });
+        }
+    }
+
+    /// Warns about a lifecycle record naming another client than its tx, which it leaves as is.
+    fn client_mismatch(&mut self, transaction: &Transaction, tx: Applied, position: &Position) {
+        let reason = format!(
+            "tx #{}: {:?} at {} names client #{}, the tx belongs to client #{}",
+            transaction.id,
+            transaction.kind,
+            position.line(),
+            transaction.client,
+            tx.client
+        );
+        self.warning(transaction, position.line(), reason);
+    }
+
+    /// Applies one transaction, counted as the next record, for driving the engine from the
+    /// caller's own loop.
+    ///
+    /// A transaction which is not applied is returned as the error, whether it is recoverable
+    /// or not, and balances stay as they were. Sampling, controls and deferred settlements only
+    /// apply to inputs.
+    pub fn step(&mut self, transaction: &Transaction) -> Result<(), EngineError> {
+        self.stats.records += 1;
+        let mut position = Position::new();
+        position
+            .set_line(self.stats.records)
+            .set_record(self.stats.records - 1);
+        let outcome = self.step_at(transaction, position);
+        match outcome {
+            Ok(()) => self.stats.applied += 1,
+            Err(_) => self.stats.skipped += 1,
+        }
+        outcome
+    }
+
+    /// Counts and applies a transaction read at `position`, see [`Self::step`].
+    fn step_at(
+        &mut self,
+        transaction: &Transaction,
+        position: Position,
+    ) -> Result<(), EngineError> {
+        info!("{transaction:?}");
+        self.stats.count(transaction.kind);
+        let line = position.line();
+        let before = (self.audit.is_some() || self.config.paranoid)
+            .then(|| self.touched_clients(transaction));
+        let outcome = self.process_transaction(transaction, position);
+        if let Some(before) = &before {
+            if self.config.paranoid
+                && outcome
+                    .as_ref()
+                    .map_or_else(EngineError::is_recoverable, |()| true)
+            {
+                self.check_invariants(transaction, line, before, outcome.is_ok())?;
+            }
+            if outcome.is_ok() && self.audit.is_some() {
+                self.write_audit(transaction, line, before)?;
+            }
+        }
+        if self.config.explain_tx == Some(transaction.id) {
+            self.record_step(transaction, line, outcome.is_ok())?;
+        }
+        outcome
+    }
+
+    fn apply(
+        &mut self,
+        transaction: &Transaction,
+        position: Position,
+        raw_record: &ByteRecord,
+    ) -> Result<(), Error> {
+        let line = position.line();
+        let outcome = self.step_at(transaction, position);
+        let name = transaction.batch.as_deref().unwrap_or_default();
+        let batch = Self::batch(&mut self.batches, name);
+        batch.records += 1;
+        match outcome {
+            Err(e) if e.is_recoverable() || self.config.check => {
+                self.stats.skipped += 1;
+                self.reject(
+                    raw_record,
+                    line,
+                    format!("{:?} not applied: {e:#}", transaction.kind),
+                );
+            }
+            Err(e) if self.config.keep_going => {
+                self.stats.skipped += 1;
+                self.reject(
+                    raw_record,
+                    line,
+                    format!("{:?} not applied: {e:#}", transaction.kind),
+                );
+                self.failures.push((line, e.into()));
+            }
+            Err(e) => return Err(e.into()),
+            Ok(()) => {
+                self.stats.applied += 1;
+                batch.applied += 1;
+                match transaction.kind {
+                    Kind::Deposit => {
+                        batch.deposits = batch.deposits.checked_add(transaction.get_amount()?)?
+                    }
+                    Kind::Withdrawal => {
+                        batch.withdrawals =
+                            batch.withdrawals.checked_add(transaction.get_amount()?)?
+                    }
+                    _ => {}
+                }
+                if let Some(every) = self.config.snapshot_every {
+                    if self.stats.applied.is_multiple_of(every) {
+                        self.save_snapshot(line)?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn batch<'a>(batches: &'a mut BTreeMap<String, BatchStats>, name: &str) -> &'a mut BatchStats {
+        // Avoids allocating the name for every record of a known batch.
+        if !batches.contains_key(name) {
+            let stats = BatchStats {
+                batch: name.to_owned(),
+                ..BatchStats::default()
+            };
+            batches.insert(name.to_owned(), stats);
+        }
+        batches.get_mut(name).expect("batch is inserted")
+    }
 
+    /// Reads dispute lifecycle records from the controls file, grouped by the tx they reference.
+    fn load_controls(&mut self, path: &Path) -> Result<(), Error> {
+        let mut reader = Self::reader(&self.config, path)?;
+        let headers = self.config.header_aliases.apply(reader.byte_headers()?);
+        let mut raw_record = ByteRecord::new();
         while reader.read_byte_record(&mut raw_record)? {
-            if let Ok(transaction) = raw_record.deserialize::<Transaction>(Some(&headers)) {
-                info!("{transaction:?}");
-                let position = raw_record
-                    .position()
-                    .expect("record has not position")
-                    .clone();
-                if let Err(Some(e)) = self.process_transaction(&transaction, position) {
-                    return Err(e);
+            let position = raw_record
+                .position()
+                .expect("record has not position")
+                .clone();
+            match self.config.type_map.deserialize(&headers, &raw_record) {
+                Ok(mut transaction)
+                    if matches!(
+                        transaction.kind,
+                        Kind::Dispute | Kind::Resolve | Kind::Chargeback
+                    ) =>
+                {
+                    if let Some(column) = &self.config.tx_id_epoch {
+                        let value = epoch_value(column, &headers, &raw_record)?;
+                        transaction.epoch = self.intern_epoch(value);
+                    }
+                    let key = self.key(&transaction);
+                    self.controls.entry(key).or_default().push((
+                        transaction,
+                        position,
+                        raw_record.clone(),
+                    ));
+                }
+                _ => {
+                    warn!(
+                        line = position.line();
+                        "invalid control record at {} of {}",
+                        position.line(),
+                        path.display()
+                    );
+                    self.stats.records += 1;
+                    self.stats.skipped += 1;
                 }
             }
         }
+        Ok(())
+    }
+
+    /// Applies control records referencing `id`, right after the referenced tx.
+    fn apply_controls(&mut self, key: TxKey) -> Result<(), Error> {
+        for (transaction, position, raw_record) in self.controls.remove(&key).unwrap_or_default() {
+            self.stats.records += 1;
+            self.apply(&transaction, position, &raw_record)?;
+        }
+        Ok(())
+    }
+
+    /// Reports a record which failed to deserialize because of its `type` field, returning
+    /// whether it did.
+    ///
+    /// An empty type usually means misaligned columns upstream. Fatal with `strict_kinds`.
+    fn check_kind(&self, record: &ByteRecord, position: &Position) -> Result<bool, Error> {
+        let Some(token) = self
+            .headers
+            .iter()
+            .position(|h| h == b"type")
+            .and_then(|i| record.get(i))
+        else {
+            return Ok(false);
+        };
+        let token = String::from_utf8_lossy(token);
+        let Err(e) = self.config.type_map.resolve(&token) else {
+            return Ok(false);
+        };
+        if self.config.strict_kinds {
+            Err(e.context(format!("invalid tx type at line {}", position.line())))
+        } else {
+            warn!(line = position.line(); "{e} at line {}", position.line());
+            Ok(true)
+        }
+    }
+
+    pub fn clients(&self) -> &HashMap<u16, Client> {
+        &self.clients
+    }
+
+    /// Current balances of client `id`, if any record of it was applied yet.
+    pub fn client_snapshot(&self, id: u16) -> Option<ClientSnapshot> {
+        self.clients.get(&id).map(Client::snapshot)
+    }
+
+    /// Final balances, keyed by client id.
+    pub fn into_clients(self) -> HashMap<u16, Client> {
+        self.clients
+    }
+
+    /// Calls `f` for every client in ascending id order, e.g. to export final balances.
+    pub fn for_each_client(&self, mut f: impl FnMut(&Client)) {
+        let mut ids = self.clients.keys().copied().collect::<Vec<_>>();
+        ids.sort_unstable();
+        for id in ids {
+            f(&self.clients[&id]);
+        }
+    }
+
+    pub fn stats(&self) -> Stats {
+        self.stats
+    }
+
+    /// Sum of all fees charged so far.
+    pub fn fees_collected(&self) -> Money {
+        self.fees_collected
+    }
+
+    pub fn headers(&self) -> &ByteRecord {
+        &self.headers
+    }
+
+    /// Records of the `explain_tx` transaction in processing order.
+    pub fn timeline(&self) -> &[Step] {
+        &self.timeline
+    }
+
+    /// Counters of every batch seen, ordered by name.
+    pub fn batches(&self) -> impl Iterator<Item = &BatchStats> {
+        self.batches.values()
+    }
+
+    /// Records around the one which failed processing, empty unless `first_error_context`
+    /// is set.
+    pub fn error_context(&self) -> &[ContextRecord] {
+        &self.error_context
+    }
+
+    /// Notable outcomes in processing order, empty unless `record_events` is set.
+    pub fn events(&self) -> &[Event] {
+        &self.events
+    }
+
+    /// Line and error of every record which would have failed the run, in input order, empty
+    /// unless `keep_going` is set. The records are in [`Self::rejected`] too.
+    pub fn failures(&self) -> &[(u64, Error)] {
+        &self.failures
+    }
+
+    /// Raw records which were not applied, in input order.
+    pub fn rejected(&self) -> &[Rejection] {
+        &self.rejected
+    }
+
+    /// Open disputes per client, clients without any are left out.
+    pub fn open_disputes(&self) -> Result<HashMap<u16, OpenDisputes>, Error> {
+        let mut open = HashMap::<u16, OpenDisputes>::new();
+        for tx in self.disputed_transactions.values() {
+            let disputes = open.entry(tx.client).or_default();
+            disputes.count += 1;
+            disputes.amount = disputes.amount.checked_add(tx.amount)?;
+        }
+        Ok(open)
+    }
+
+    /// Clients whose disputes per deposit exceed `threshold`, ordered by id.
+    ///
+    /// Only deposits applied from the input and disputes of them count.
+    pub fn anomalies(&self, threshold: Decimal) -> Vec<Anomaly> {
+        let mut anomalies = self
+            .dispute_counts
+            .iter()
+            .filter(|(_, counts)| counts.deposits > 0)
+            .map(|(&client, counts)| Anomaly {
+                client,
+                deposits: counts.deposits,
+                disputes: counts.disputes,
+                ratio: (Decimal::from(counts.disputes) / Decimal::from(counts.deposits))
+                    .round_dp(4),
+            })
+            .filter(|anomaly| anomaly.ratio > threshold)
+            .collect::<Vec<_>>();
+        anomalies.sort_by_key(|anomaly| anomaly.client);
+        anomalies
+    }
+
+    /// Open disputes at the current point of processing, ordered by client and tx id, with
+    /// amounts truncated to `precision` decimal places.
+    pub fn held_detail(&self, precision: u32) -> Vec<HeldDetail> {
+        let mut detail = self
+            .disputed_transactions
+            .iter()
+            .map(|(&(_, _, id), tx)| HeldDetail {
+                client: Some(tx.client),
+                tx: id,
+                amount: tx.amount.truncate(precision),
+            })
+            .collect::<Vec<_>>();
+        detail.sort_by_key(|d| (d.client, d.tx));
+        detail
+    }
 
-        Ok(self.clients)
+    /// Exact amounts the open disputes of `client` hold, by tx id in ascending order.
+    ///
+    /// They make up the client's held funds, unless a resolve or chargeback fell short of
+    /// them or was forced through.
+    pub fn held_by_tx(&self, client: u16) -> Vec<(u32, Money)> {
+        let mut held = self
+            .disputed_transactions
+            .iter()
+            .filter(|(_, tx)| tx.client == client)
+            .map(|(&(_, _, id), tx)| (id, tx.amount))
+            .collect::<Vec<_>>();
+        held.sort_unstable();
+        held
+    }
+
+    #[cfg(not(feature = "tracing"))]
+    fn process_transaction(
+        &mut self,
+        transaction: &Transaction,
+        position: Position,
+    ) -> Result<(), EngineError> {
+        self.dispatch_transaction(transaction, position)
     }
 
+    /// Processes the transaction within a span, which records its outcome.
+    #[cfg(feature = "tracing")]
     fn process_transaction(
         &mut self,
         transaction: &Transaction,
         position: Position,
-    ) -> Result<(), Option<Error>> {
+    ) -> Result<(), EngineError> {
+        let span = tracing::info_span!(
+            "transaction",
+            kind = ?transaction.kind,
+            client = transaction.client,
+            tx = transaction.id,
+            outcome = tracing::field::Empty,
+        );
+        let _entered = span.enter();
+        let result = self.dispatch_transaction(transaction, position);
+        let outcome = match &result {
+            Ok(()) => "applied",
+            Err(e) if e.is_recoverable() => "skipped",
+            Err(_) => "failed",
+        };
+        span.record("outcome", outcome);
+        result
+    }
+
+    fn dispatch_transaction(
+        &mut self,
+        transaction: &Transaction,
+        position: Position,
+    ) -> Result<(), EngineError> {
+        self.check_reserved(transaction, transaction.client, &position)?;
+        self.validate(transaction, &position)?;
+        if let Some(max) = self.config.max_clients {
+            if self.clients.len() >= max && !self.clients.contains_key(&transaction.client) {
+                warn!(
+                    tx = transaction.id, client = transaction.client, line = position.line();
+                    "tx #{}: client #{} exceeds the limit of {max} clients, at {}",
+                    transaction.id,
+                    transaction.client,
+                    position.line()
+                );
+                return Err(EngineError::ClientLimit { tx: transaction.id });
+            }
+        }
         let f = match transaction.kind {
             Kind::Deposit => Self::deposit,
             Kind::Withdrawal => Self::withdrawal,
             Kind::Dispute => Self::dispute,
             Kind::Resolve => Self::resolve,
             Kind::Chargeback => Self::chargeback,
+            Kind::Unfreeze => Self::unfreeze,
+            Kind::Transfer => Self::transfer,
         };
         f(self, transaction, position)
     }
@@ -84,10 +2291,17 @@ impl Engine {
         &mut self,
         transaction: &Transaction,
         position: Position,
-    ) -> Result<(), Option<Error>> {
-        let amount = transaction.get_amount()?;
-        self.client(transaction.client).deposit(amount)?;
-        self.add_transaction(transaction.id, position)?;
+    ) -> Result<(), EngineError> {
+        let amount = transaction.validate_amount()?;
+        self.check_max_amount(transaction, amount, &position)?;
+        self.skip_duplicate(transaction)?;
+        let policy = self.config.funds_policy.clone();
+        self.client(transaction.client).deposit(amount, &*policy)?;
+        self.add_transaction(transaction, amount)?;
+        self.dispute_counts
+            .entry(transaction.client)
+            .or_default()
+            .deposits += 1;
         Ok(())
     }
 
@@ -95,10 +2309,14 @@ impl Engine {
         &mut self,
         transaction: &Transaction,
         position: Position,
-    ) -> Result<(), Option<Error>> {
-        let amount = transaction.get_amount()?;
-        self.client(transaction.client).withdrawal(amount)?;
-        self.add_transaction(transaction.id, position)?;
+    ) -> Result<(), EngineError> {
+        let amount = transaction.validate_amount()?;
+        self.check_max_amount(transaction, amount, &position)?;
+        self.skip_duplicate(transaction)?;
+        let policy = self.config.funds_policy.clone();
+        self.client(transaction.client)
+            .withdrawal(amount, &*policy)?;
+        self.add_transaction(transaction, amount)?;
         Ok(())
     }
 
@@ -106,47 +2324,174 @@ impl Engine {
         &mut self,
         transaction: &Transaction,
         position: Position,
-    ) -> Result<(), Option<Error>> {
-        let tx = self.load_transaction(transaction.id).map_err(|_| None)?;
+    ) -> Result<(), EngineError> {
+        self.check_unlocked(transaction, &position)?;
+        let key = self.key(transaction);
+        let Some(tx) = self.processed_transactions.get(&key)? else {
+            // Charged back txs are left out of saved states.
+            if self.charged_back_transactions.contains(&key) {
+                return Err(self.charged_back_dispute(transaction, &position));
+            }
+            return Err(EngineError::UnknownTx { tx: transaction.id });
+        };
         if tx.client != transaction.client {
-            warn!("tx clients mismatch: at {}", position.line());
+            self.client_mismatch(transaction, tx, &position);
             return Ok(());
         }
-        if self.disputed_transactions.contains_key(&tx.id) {
-            warn!("tx #{}: already disputed", tx.id);
-            return Ok(());
+        if matches!(tx.kind, Kind::Transfer) {
+            let reason = format!(
+                "tx #{}: dispute at {} targets a transfer",
+                transaction.id,
+                position.line()
+            );
+            return Err(self.protocol_violation(reason));
+        }
+        let open = self.disputed_transactions.get(&key).copied();
+        if open.is_some() && self.config.duplicate_dispute == DuplicateDispute::Reject {
+            let reason = format!(
+                "tx #{}: dispute at {} targets an open dispute",
+                transaction.id,
+                position.line()
+            );
+            return Err(self.protocol_violation(reason));
+        }
+        if self.charged_back_transactions.contains(&key) {
+            return Err(self.charged_back_dispute(transaction, &position));
         }
-        if !matches!(tx.kind, Kind::Deposit) {
-            warn!("tx #{}: only deposit tx can be disputed", tx.id);
+        if let (Some(max), None) = (self.config.max_open_disputes, open) {
+            if self
+                .open_dispute_counts
+                .get(&tx.client)
+                .copied()
+                .unwrap_or_default()
+                >= max
+            {
+                warn!(
+                    tx = transaction.id, client = transaction.client, line = position.line();
+                    "tx #{}: dispute at {} exceeds the {max} open disputes client #{} may have",
+                    transaction.id,
+                    position.line(),
+                    tx.client
+                );
+                return Err(EngineError::DisputeLimit {
+                    client: tx.client,
+                    max,
+                });
+            }
+        }
+        let amount = self.dispute_amount(transaction, tx, open)?;
+        let policy = self.config.funds_policy.clone();
+        let model = self.config.accounting_model;
+        let client = self.client(transaction.client);
+        match tx.kind {
+            Kind::Deposit => client.dispute_deposit(amount, &*policy, model)?,
+            Kind::Withdrawal => client.dispute_withdrawal(amount, model)?,
+            _ => return Err(self.not_disputable(transaction, tx, &position)),
+        }
+        if let Some(open) = open {
+            debug!("added {amount} to disputed tx: #{}", transaction.id);
+            let amount = open.amount.checked_add(amount)?;
+            self.disputed_transactions
+                .insert(key, Applied { amount, ..open });
             return Ok(());
         }
-        let amount = tx.get_amount()?;
-        self.client(transaction.client).dispute_deposit(amount)?;
-        debug!("added disputed tx: #{}", tx.id);
-        self.disputed_transactions.insert(tx.id, tx);
+        debug!("added disputed tx: #{}", transaction.id);
+        if matches!(tx.kind, Kind::Deposit) {
+            self.dispute_counts
+                .entry(transaction.client)
+                .or_default()
+                .disputes += 1;
+        }
+        self.resolved_transactions.remove(&key);
+        self.disputed_transactions
+            .insert(key, Applied { amount, ..tx });
+        if self.config.max_open_disputes.is_some() {
+            *self.open_dispute_counts.entry(tx.client).or_default() += 1;
+        }
         Ok(())
     }
 
+    fn charged_back_dispute(&self, transaction: &Transaction, position: &Position) -> EngineError {
+        let reason = format!(
+            "tx #{}: dispute at {} targets a charged back tx",
+            transaction.id,
+            position.line()
+        );
+        self.protocol_violation(reason)
+    }
+
+    /// Amount a dispute holds: its own amount, or what `open` disputes leave of the tx if it
+    /// has none, all of it unless disputes accumulate.
+    fn dispute_amount(
+        &self,
+        transaction: &Transaction,
+        tx: Applied,
+        open: Option<Applied>,
+    ) -> Result<Money, EngineError> {
+        let undisputed = tx
+            .amount
+            .checked_sub(open.map_or(Money::ZERO, |open| open.amount))?;
+        let amount = transaction.amount.unwrap_or(undisputed);
+        if amount <= Money::ZERO && transaction.amount.is_some() {
+            return Err(EngineError::InvalidAmount {
+                tx: transaction.id,
+                amount,
+            });
+        }
+        let pct = self.config.max_dispute_pct.unwrap_or(100);
+        let max = tx.amount.checked_mul(Decimal::new(pct.into(), 2))?;
+        let held = open.map_or(Money::ZERO, |open| open.amount);
+        if held.checked_add(amount)? > max {
+            warn!(
+                tx = transaction.id, client = transaction.client;
+                "tx #{}: dispute of {amount} exceeds {max}, {pct}% of the tx, skipping it",
+                transaction.id
+            );
+            return Err(EngineError::DisputeOverPct {
+                tx: transaction.id,
+                amount,
+                max,
+                pct,
+            });
+        }
+        if amount > undisputed || amount <= Money::ZERO {
+            warn!(
+                tx = transaction.id, client = transaction.client;
+                "tx #{}: dispute of {amount} exceeds the undisputed {undisputed}, skipping it",
+                transaction.id
+            );
+            return Err(EngineError::DisputeExceedsTx {
+                tx: transaction.id,
+                amount,
+            });
+        }
+        Ok(amount)
+    }
+
     fn resolve(
         &mut self,
         transaction: &Transaction,
         position: Position,
-    ) -> Result<(), Option<Error>> {
-        let tx = self
-            .disputed_transactions
-            .get(&transaction.id)
-            .cloned()
-            .ok_or(None)?;
+    ) -> Result<(), EngineError> {
+        self.check_unlocked(transaction, &position)?;
+        let tx = self.disputed_transaction(transaction, &position)?;
         if tx.client != transaction.client {
-            warn!("tx clients mismatch: at {}", position.line());
+            self.client_mismatch(transaction, tx, &position);
             return Ok(());
         }
-        if !matches!(tx.kind, Kind::Deposit) {
-            unreachable!("only deposit tx can be disputed");
+        let amount = tx.amount;
+        let policy = self.config.funds_policy.clone();
+        let mode = self.config.resolve_held;
+        let model = self.config.accounting_model;
+        let client = self.client(transaction.client);
+        match tx.kind {
+            Kind::Deposit => client.resolve_deposit(amount, &*policy, mode, model)?,
+            Kind::Withdrawal => client.resolve_withdrawal(amount, &*policy, mode, model)?,
+            _ => return Err(self.not_disputable(transaction, tx, &position)),
         }
-        let amount = tx.get_amount()?;
-        self.client(transaction.client).resolve_deposit(amount)?;
-        self.disputed_transactions.remove(&tx.id);
+        let key = self.key(transaction);
+        self.close_dispute(key);
+        self.resolved_transactions.insert(key);
         Ok(())
     }
 
@@ -154,48 +2499,418 @@ impl Engine {
         &mut self,
         transaction: &Transaction,
         position: Position,
-    ) -> Result<(), Option<Error>> {
-        let tx = self
-            .disputed_transactions
-            .get(&transaction.id)
-            .cloned()
-            .ok_or(None)?;
+    ) -> Result<(), EngineError> {
+        self.check_unlocked(transaction, &position)?;
+        let tx = self.disputed_transaction(transaction, &position)?;
         if tx.client != transaction.client {
-            warn!("tx clients mismatch: at {}", position.line());
+            self.client_mismatch(transaction, tx, &position);
             return Ok(());
         }
 
-        let amount = tx.get_amount()?;
-        self.client(transaction.client).chargeback(amount)?;
-        self.disputed_transactions.remove(&tx.id);
+        let amount = tx.amount;
+        let policy = self.config.funds_policy.clone();
+        let model = self.config.accounting_model;
+        let client = self.client(transaction.client);
+        match tx.kind {
+            Kind::Deposit => client.chargeback(amount, &*policy, model)?,
+            Kind::Withdrawal => client.chargeback_withdrawal(amount, &*policy, model)?,
+            _ => return Err(self.not_disputable(transaction, tx, &position)),
+        }
+        let key = self.key(transaction);
+        self.close_dispute(key);
+        self.charged_back_transactions.insert(key);
+        if self.config.record_events {
+            self.events.push(Event {
+                kind: EventKind::Lock,
+                level: "info",
+                line: Some(position.line()),
+                client: Some(transaction.client),
+                tx: Some(transaction.id),
+                reason: format!("charged back {amount}"),
+                detail: None,
+            });
+        }
+        if let Some(fee) = self.config.chargeback_fee {
+            info!(
+                tx = transaction.id, client = transaction.client;
+                "Client #{}: charging chargeback fee {fee}",
+                transaction.client
+            );
+            self.client(transaction.client).charge_fee(fee)?;
+            self.fees_collected = self.fees_collected.checked_add(fee)?;
+        }
+
+        Ok(())
+    }
 
+    /// Withdraws the amount from the client and deposits it to `dest` as one unit, neither
+    /// changes unless both sides can take it.
+    ///
+    /// The tx id is taken like a deposit's, but a transfer can't be disputed.
+    fn transfer(
+        &mut self,
+        transaction: &Transaction,
+        position: Position,
+    ) -> Result<(), EngineError> {
+        let amount = transaction.validate_amount()?;
+        self.check_max_amount(transaction, amount, &position)?;
+        let dest = transaction
+            .dest
+            .ok_or(EngineError::MissingDest { tx: transaction.id })?;
+        if dest == transaction.client {
+            warn!(
+                tx = transaction.id, client = transaction.client, line = position.line();
+                "tx #{}: transfer at {} to its own client #{dest}, rejecting it",
+                transaction.id,
+                position.line()
+            );
+            return Err(EngineError::SelfTransfer { tx: transaction.id });
+        }
+        self.check_dest(transaction, dest, &position)?;
+        self.skip_duplicate(transaction)?;
+        let policy = self.config.funds_policy.clone();
+        let new_source = !self.clients.contains_key(&transaction.client);
+        let source = self.client(transaction.client);
+        let before = source.clone();
+        if let Err(e) = source.withdrawal(amount, &*policy) {
+            if new_source {
+                self.clients.remove(&transaction.client);
+            }
+            return Err(e);
+        }
+        let new_dest = !self.clients.contains_key(&dest);
+        if let Err(e) = self.client(dest).deposit(amount, &*policy) {
+            self.clients.insert(transaction.client, before);
+            if new_dest {
+                self.clients.remove(&dest);
+            }
+            return Err(e);
+        }
+        self.add_transaction(transaction, amount)?;
         Ok(())
     }
 
-    fn add_transaction(&mut self, id: u32, position: Position) -> Result<(), Error> {
-        if let Entry::Vacant(e) = self.processed_transactions.entry(id) {
-            e.insert(position);
-            Ok(())
+    /// Skips an amount over `max_amount`, most likely a mis-scaled field.
+    ///
+    /// Lifecycle records take the amount of their tx, which passed the check already.
+    fn check_max_amount(
+        &self,
+        transaction: &Transaction,
+        amount: Money,
+        position: &Position,
+    ) -> Result<(), EngineError> {
+        match self.config.max_amount {
+            Some(max) if amount > max => {
+                warn!(
+                    tx = transaction.id, client = transaction.client, line = position.line();
+                    "tx #{}: {:?} of {amount} at {} exceeds the maximum of {max}, skipping it",
+                    transaction.id,
+                    transaction.kind,
+                    position.line()
+                );
+                Err(EngineError::AmountOverMax {
+                    tx: transaction.id,
+                    amount,
+                    max,
+                })
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Skips a record of a reserved client before any [`Client`] is created for it.
+    fn check_reserved(
+        &self,
+        transaction: &Transaction,
+        client: u16,
+        position: &Position,
+    ) -> Result<(), EngineError> {
+        if (self.config.reject_client_zero && client == 0)
+            || self.config.reserved_clients.contains(&client)
+        {
+            warn!(
+                tx = transaction.id, client = transaction.client, line = position.line();
+                "tx #{}: {:?} at {} names reserved client #{client}, skipping it",
+                transaction.id,
+                transaction.kind,
+                position.line()
+            );
+            return Err(EngineError::ReservedClient {
+                tx: transaction.id,
+                client,
+            });
+        }
+        Ok(())
+    }
+
+    fn validate(&self, transaction: &Transaction, position: &Position) -> Result<(), EngineError> {
+        let creates_tx = matches!(
+            transaction.kind,
+            Kind::Deposit | Kind::Withdrawal | Kind::Transfer
+        );
+        let outcome = if self.config.reject_tx_zero && creates_tx && transaction.id == 0 {
+            Err(EngineError::Invalid {
+                tx: 0,
+                reason: "tx id 0 is invalid".to_owned(),
+            })
+        } else if let Some(validator) = &self.config.validator {
+            validator.check(transaction)
         } else {
-            Err(anyhow!("duplicate tx index: {}", id))
+            Ok(())
+        };
+        if let Err(e) = &outcome {
+            warn!(
+                tx = transaction.id, client = transaction.client, line = position.line();
+                "{e}, skipping the {:?} at {}",
+                transaction.kind,
+                position.line()
+            );
         }
+        outcome
     }
 
-    fn get_position(&self, id: u32) -> Option<&Position> {
-        self.processed_transactions.get(&id)
+    /// Applies the checks of [`Self::dispatch_transaction`] on the client to `dest` too.
+    fn check_dest(
+        &self,
+        transaction: &Transaction,
+        dest: u16,
+        position: &Position,
+    ) -> Result<(), EngineError> {
+        self.check_reserved(transaction, dest, position)?;
+        if let Some(max) = self.config.max_clients {
+            if self.clients.len() >= max && !self.clients.contains_key(&dest) {
+                warn!(
+                    tx = transaction.id, client = transaction.client, line = position.line();
+                    "tx #{}: transfer to client #{dest} exceeds the limit of {max} clients, at {}",
+                    transaction.id,
+                    position.line()
+                );
+                return Err(EngineError::ClientLimit { tx: transaction.id });
+            }
+        }
+        Ok(())
     }
 
-    fn load_transaction(&mut self, id: u32) -> Result<Transaction, Error> {
-        let position = self
-            .get_position(id)
-            .ok_or_else(|| anyhow!("id not found: {}", id))?
-            .clone();
-        self.reader.seek(position)?;
-        let mut raw_record = ByteRecord::new();
-        let headers = self.reader.byte_headers()?.clone();
-        self.reader.read_byte_record(&mut raw_record)?;
-        let transaction = raw_record.deserialize(Some(&headers))?;
-        Ok(transaction)
+    /// Clears the lock of the client, rejected unless `allow_unfreeze` is set.
+    fn unfreeze(
+        &mut self,
+        transaction: &Transaction,
+        position: Position,
+    ) -> Result<(), EngineError> {
+        if !self.config.allow_unfreeze {
+            warn!(
+                tx = transaction.id, client = transaction.client, line = position.line();
+                "tx #{}: unfreeze at {} is not allowed, rejecting it",
+                transaction.id,
+                position.line()
+            );
+            return Err(EngineError::UnfreezeNotAllowed { tx: transaction.id });
+        }
+        let unlocked = match self.clients.get_mut(&transaction.client) {
+            Some(client) => client.unlock(),
+            None => Err(EngineError::NotLocked {
+                client: transaction.client,
+            }),
+        };
+        if let Err(e) = unlocked {
+            warn!(
+                tx = transaction.id, client = transaction.client, line = position.line();
+                "tx #{}: unfreeze at {} targets client #{} which is not locked, rejecting it",
+                transaction.id,
+                position.line(),
+                transaction.client
+            );
+            return Err(e);
+        }
+        info!(tx = transaction.id, client = transaction.client, line = position.line(); "Client #{}: unfrozen", transaction.client);
+        if self.config.record_events {
+            self.events.push(Event {
+                kind: EventKind::Unlock,
+                level: "info",
+                line: Some(position.line()),
+                client: Some(transaction.client),
+                tx: Some(transaction.id),
+                reason: "unfrozen".to_owned(),
+                detail: None,
+            });
+        }
+        Ok(())
+    }
+
+    /// Rejects dispute lifecycle records of a locked client, its balances stay as the
+    /// chargeback left them.
+    fn check_unlocked(
+        &self,
+        transaction: &Transaction,
+        position: &Position,
+    ) -> Result<(), EngineError> {
+        if self
+            .clients
+            .get(&transaction.client)
+            .is_some_and(Client::is_locked)
+        {
+            warn!(
+                tx = transaction.id, client = transaction.client, line = position.line();
+                "tx #{}: {:?} at {} targets locked client #{}, rejecting it",
+                transaction.id,
+                transaction.kind,
+                position.line(),
+                transaction.client
+            );
+            return Err(EngineError::AccountLocked {
+                client: transaction.client,
+            });
+        }
+        Ok(())
+    }
+
+    /// Looks up the open dispute targeted by a resolve or chargeback.
+    ///
+    /// Early settlements of inputs are deferred before, see [`Self::defer_settlement`].
+    fn disputed_transaction(
+        &mut self,
+        transaction: &Transaction,
+        position: &Position,
+    ) -> Result<Applied, EngineError> {
+        let key = self.key(transaction);
+        if let Some(&tx) = self.disputed_transactions.get(&key) {
+            return Ok(tx);
+        }
+        if self.resolved_transactions.contains(&key) {
+            let reason = format!(
+                "tx #{}: {:?} at {} targets an already resolved dispute",
+                transaction.id,
+                transaction.kind,
+                position.line()
+            );
+            return Err(self.protocol_violation(reason));
+        }
+        if self.charged_back_transactions.contains(&key) {
+            let reason = format!(
+                "tx #{}: {:?} at {} targets a charged back tx",
+                transaction.id,
+                transaction.kind,
+                position.line()
+            );
+            return Err(self.protocol_violation(reason));
+        }
+        if self.processed_transactions.get(&key)?.is_none() {
+            return Err(EngineError::UnknownTx { tx: transaction.id });
+        }
+        let reason = format!(
+            "tx #{}: {:?} at {} targets a tx without an open dispute",
+            transaction.id,
+            transaction.kind,
+            position.line()
+        );
+        Err(self.protocol_violation(reason))
+    }
+
+    fn close_dispute(&mut self, key: TxKey) {
+        let Some(tx) = self.disputed_transactions.remove(&key) else {
+            return;
+        };
+        if let Some(count) = self.open_dispute_counts.get_mut(&tx.client) {
+            *count -= 1;
+        }
+    }
+
+    /// Skips a dispute record whose tx is neither a deposit nor a withdrawal.
+    ///
+    /// Disputes of other txs are refused when they arrive, but a state saved by another
+    /// version may still hold one.
+    fn not_disputable(
+        &self,
+        transaction: &Transaction,
+        tx: Applied,
+        position: &Position,
+    ) -> EngineError {
+        warn!(
+            tx = transaction.id, client = transaction.client, line = position.line();
+            "tx #{}: {:?} at {} targets a {}, which can't be disputed, skipping it",
+            transaction.id,
+            transaction.kind,
+            position.line(),
+            tx.kind.name()
+        );
+        EngineError::NotDisputable {
+            tx: transaction.id,
+            kind: tx.kind,
+        }
+    }
+
+    /// Fatal with `strict_protocol`, otherwise the record is skipped with a warning.
+    fn protocol_violation(&self, reason: String) -> EngineError {
+        let strict = self.config.strict_protocol;
+        if !strict {
+            warn!("{reason}");
+        }
+        EngineError::ProtocolViolation { reason, strict }
+    }
+
+    fn add_transaction(&mut self, transaction: &Transaction, amount: Money) -> Result<(), Error> {
+        self.check_unique(transaction)?;
+        let applied = Applied {
+            client: transaction.client,
+            kind: transaction.kind,
+            amount,
+        };
+        self.processed_transactions
+            .insert(self.key(transaction), applied)
+    }
+
+    /// Skips a deposit or withdrawal reusing a tx id with a warning, the first one stays applied.
+    fn skip_duplicate(&mut self, transaction: &Transaction) -> Result<(), EngineError> {
+        if let Err(e) = self.check_unique(transaction) {
+            warn!(tx = transaction.id, client = transaction.client; "{e}, skipping it");
+            self.stats.duplicates += 1;
+            return Err(EngineError::DuplicateTx { tx: transaction.id });
+        }
+        Ok(())
+    }
+
+    /// Fails on a tx id already taken by an applied deposit or withdrawal.
+    ///
+    /// Ids are unique across kinds, so a dispute never has to guess which tx it references.
+    fn check_unique(&self, transaction: &Transaction) -> Result<(), Error> {
+        let key = self.key(transaction);
+        if self.charged_back_transactions.contains(&key) {
+            // Left out of saved states, see `save_state`.
+            return Err(anyhow!(
+                "duplicate tx index: {}, {:?} of client #{} reuses the id of a charged back tx",
+                transaction.id,
+                transaction.kind,
+                transaction.client
+            ));
+        }
+        match self.processed_transactions.get(&key)? {
+            Some(existing) => Err(anyhow!(
+                "duplicate tx index: {}, {:?} of client #{} reuses the id of a {:?} of client #{}",
+                transaction.id,
+                transaction.kind,
+                transaction.client,
+                existing.kind,
+                existing.client
+            )),
+            None => Ok(()),
+        }
+    }
+
+    /// Index of an epoch value, the same for every record of the epoch.
+    fn intern_epoch(&mut self, value: &[u8]) -> u32 {
+        if let Some(&index) = self.epochs.get(value) {
+            return index;
+        }
+        let index = self.epochs.len() as u32;
+        self.epochs.insert(value.to_vec(), index);
+        index
+    }
+
+    fn key(&self, transaction: &Transaction) -> TxKey {
+        match self.config.tx_id_scope {
+            TxIdScope::Global => (None, transaction.epoch, transaction.id),
+            TxIdScope::PerClient => (Some(transaction.client), transaction.epoch, transaction.id),
+        }
     }
 
     fn client(&mut self, client_id: u16) -> &mut Client {
@@ -0,0 +1,86 @@
+use thiserror::Error;
+
+use crate::{
+    money::{Money, Overflow},
+    transaction::Kind,
+};
+
+/// Why a transaction was not applied.
+///
+/// Most outcomes skip the record and processing goes on, see [`EngineError::is_recoverable`].
+#[derive(Debug, Error)]
+pub enum EngineError {
+    #[error("Client #{client}: is locked")]
+    AccountLocked { client: u16 },
+    /// An unfreeze of a client without a lock to clear.
+    #[error("Client #{client}: is not locked")]
+    NotLocked { client: u16 },
+    /// The funds policy refused a withdrawal.
+    #[error(transparent)]
+    InsufficientFunds(anyhow::Error),
+    #[error("Client #{client}: dispute of {amount} exceeds available funds")]
+    DisputeExceedsAvailable { client: u16, amount: Money },
+    /// The operation would leave available funds outside of the configured bounds.
+    #[error(transparent)]
+    OutOfBounds(anyhow::Error),
+    #[error("tx #{tx}: dispute of {amount} exceeds the undisputed amount")]
+    DisputeExceedsTx { tx: u32, amount: Money },
+    /// The dispute would hold more than `max_dispute_pct` of its tx.
+    #[error("tx #{tx}: dispute of {amount} exceeds {max}, {pct}% of the tx")]
+    DisputeOverPct {
+        tx: u32,
+        amount: Money,
+        max: Money,
+        pct: u32,
+    },
+    #[error("tx #{tx}: duplicate tx index")]
+    DuplicateTx { tx: u32 },
+    #[error("tx #{tx}: references an unknown tx")]
+    UnknownTx { tx: u32 },
+    #[error("tx #{tx}: missing amount field")]
+    MissingAmount { tx: u32 },
+    #[error("tx #{tx}: missing dest field")]
+    MissingDest { tx: u32 },
+    #[error("tx #{tx}: transfer to its own client")]
+    SelfTransfer { tx: u32 },
+    #[error("tx #{tx}: amount {amount} is not positive")]
+    InvalidAmount { tx: u32, amount: Money },
+    #[error("tx #{tx}: amount {amount} exceeds the maximum of {max}")]
+    AmountOverMax { tx: u32, amount: Money, max: Money },
+    #[error("tx #{tx}: unfreeze is not allowed")]
+    UnfreezeNotAllowed { tx: u32 },
+    /// Refused by `reject_tx_zero` or the configured validator.
+    #[error("tx #{tx}: {reason}")]
+    Invalid { tx: u32, reason: String },
+    #[error("tx #{tx}: client #{client} is reserved")]
+    ReservedClient { tx: u32, client: u16 },
+    #[error("tx #{tx}: a {} can't be disputed", kind.name())]
+    NotDisputable { tx: u32, kind: Kind },
+    #[error("tx #{tx}: client limit reached")]
+    ClientLimit { tx: u32 },
+    #[error("Client #{client}: limit of {max} open disputes reached")]
+    DisputeLimit { client: u16, max: usize },
+    /// A balance would leave the range of [`Money`], the client is left as it was.
+    #[error(transparent)]
+    Overflow(#[from] Overflow),
+    /// A dispute lifecycle record out of order, fatal with `strict_protocol` only.
+    #[error("{reason}")]
+    ProtocolViolation { reason: String, strict: bool },
+    /// Anything else, e.g. an unreadable input.
+    #[error(transparent)]
+    Fatal(#[from] anyhow::Error),
+}
+
+impl EngineError {
+    /// Whether the record is skipped and processing goes on, otherwise the run fails.
+    ///
+    /// Every outcome of a bad record is recoverable, only engine failures and strict protocol
+    /// violations aren't.
+    pub fn is_recoverable(&self) -> bool {
+        match self {
+            Self::Fatal(_) => false,
+            Self::ProtocolViolation { strict, .. } => !strict,
+            _ => true,
+        }
+    }
+}
@@ -0,0 +1,66 @@
+use std::{
+    fmt,
+    io::{BufRead, BufReader, Read},
+    path::Path,
+};
+
+use anyhow::{Context, Result};
+use csv::ByteRecord;
+use serde::de::{self, Deserializer, SeqAccess, Visitor};
+use serde_json::Value;
+
+/// Columns of the CSV schema that JSON transactions are mapped onto.
+pub const HEADERS: [&str; 6] = ["type", "client", "tx", "amount", "batch", "dest"];
+
+/// UTF-8 byte order mark, which some exporters lead files with.
+const BOM: &[u8] = b"\xef\xbb\xbf";
+
+/// Calls `f` for every element of the top-level JSON array read from `path`, one at a time.
+///
+/// A leading byte order mark is skipped, like the CSV reader does.
+pub fn for_each_element(
+    input: impl Read,
+    path: &Path,
+    f: impl FnMut(Value) -> Result<()>,
+) -> Result<()> {
+    let mut input = BufReader::new(input);
+    if input.fill_buf()?.starts_with(BOM) {
+        input.consume(BOM.len());
+    }
+    let mut deserializer = serde_json::Deserializer::from_reader(input);
+    deserializer
+        .deserialize_seq(ElementVisitor(f))
+        .with_context(|| format!("invalid JSON array in {}", path.display()))?;
+    deserializer.end()?;
+    Ok(())
+}
+
+struct ElementVisitor<F>(F);
+
+impl<'de, F: FnMut(Value) -> Result<()>> Visitor<'de> for ElementVisitor<F> {
+    type Value = ();
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("an array of transactions")
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(mut self, mut seq: A) -> Result<(), A::Error> {
+        while let Some(element) = seq.next_element()? {
+            (self.0)(element).map_err(|e| de::Error::custom(format!("{e:#}")))?;
+        }
+        Ok(())
+    }
+}
+
+/// Lays a transaction object out as a record under [`HEADERS`], so it goes through the same
+/// parsing as a CSV row. Missing and `null` fields become empty.
+pub fn to_record(element: &Value) -> ByteRecord {
+    HEADERS
+        .iter()
+        .map(|field| match element.get(field) {
+            None | Some(Value::Null) => String::new(),
+            Some(Value::String(s)) => s.clone(),
+            Some(value) => value.to_string(),
+        })
+        .collect()
+}
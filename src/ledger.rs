@@ -0,0 +1,388 @@
+use std::{
+    cell::RefCell,
+    collections::VecDeque,
+    env,
+    fs::{self, File, OpenOptions},
+    io::{BufWriter, Read, Seek, SeekFrom, Write},
+    path::PathBuf,
+    process,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use anyhow::{anyhow, Context, Result};
+use rust_decimal::Decimal;
+
+use crate::{
+    engine::{Applied, TxKey, TxMap},
+    money::Money,
+    transaction::Kind,
+};
+
+/// Bytes of an entry on disk: the key and the applied tx.
+const ENTRY_SIZE: usize = 3 + 4 + 4 + 2 + 1 + 16;
+/// Entries of a spilled run per key of its in-memory index.
+const BLOCK_SIZE: usize = 256;
+
+static SPILL_FILES: AtomicUsize = AtomicUsize::new(0);
+
+/// Applied deposits, withdrawals and transfers by key.
+///
+/// Without a capacity every entry stays in memory. With one, the oldest entries beyond it are
+/// moved to sorted runs in a temporary file, of which only every [`BLOCK_SIZE`]th key is kept
+/// in memory, and looked up there on a miss.
+#[derive(Debug, Default)]
+pub(crate) struct Ledger {
+    recent: TxMap<Compact>,
+    /// Entries of `recent` whose amount doesn't fit a [`Compact`].
+    wide: TxMap<Applied>,
+    /// Keys of `recent` in insertion order, only kept with a capacity.
+    order: VecDeque<TxKey>,
+    capacity: Option<usize>,
+    spill: Option<Spill>,
+}
+
+/// An [`Applied`] as kept in memory, 12 bytes rather than 20.
+///
+/// The amount is kept as its mantissa and scale, unless the mantissa is too wide for 64 bits.
+#[derive(Debug, Clone, Copy)]
+struct Compact {
+    mantissa: [u32; 2],
+    client: u16,
+    kind: Kind,
+    /// Scale of the amount, [`WIDE`] if the amount is in [`Ledger::wide`].
+    scale: u8,
+}
+
+const WIDE: u8 = u8::MAX;
+
+impl Compact {
+    fn new(tx: &Applied) -> Self {
+        let amount = tx.amount.decimal();
+        let (mantissa, scale) = match i64::try_from(amount.mantissa()) {
+            Ok(mantissa) => (mantissa as u64, amount.scale() as u8),
+            Err(_) => (0, WIDE),
+        };
+        Self {
+            mantissa: [mantissa as u32, (mantissa >> 32) as u32],
+            client: tx.client,
+            kind: tx.kind,
+            scale,
+        }
+    }
+
+    /// The entry as applied, `None` if its amount is a wide one.
+    fn applied(self) -> Option<Applied> {
+        if self.scale == WIDE {
+            return None;
+        }
+        let mantissa = (u64::from(self.mantissa[1]) << 32 | u64::from(self.mantissa[0])) as i64;
+        Some(Applied {
+            client: self.client,
+            kind: self.kind,
+            amount: Money::new(Decimal::new(mantissa, self.scale.into())),
+        })
+    }
+}
+
+#[derive(Debug)]
+struct Spill {
+    path: PathBuf,
+    file: RefCell<File>,
+    /// Entries written so far.
+    len: u64,
+    runs: Vec<Run>,
+}
+
+/// Entries spilled at once, sorted by key.
+#[derive(Debug)]
+struct Run {
+    /// Index of the first entry in the file.
+    start: u64,
+    len: usize,
+    /// First key of every block.
+    index: Vec<TxKey>,
+    last: TxKey,
+}
+
+impl Ledger {
+    pub fn new(capacity: Option<usize>) -> Self {
+        Self {
+            capacity,
+            ..Self::default()
+        }
+    }
+
+    pub fn get(&self, key: &TxKey) -> Result<Option<Applied>> {
+        if let Some(&entry) = self.recent.get(key) {
+            return Ok(Some(self.unpack(key, entry)));
+        }
+        match &self.spill {
+            Some(spill) => spill.get(key),
+            None => Ok(None),
+        }
+    }
+
+    pub fn insert(&mut self, key: TxKey, tx: Applied) -> Result<()> {
+        let entry = Compact::new(&tx);
+        if entry.scale == WIDE {
+            self.wide.insert(key, tx);
+        } else if !self.wide.is_empty() {
+            self.wide.remove(&key);
+        }
+        let Some(capacity) = self.capacity else {
+            self.recent.insert(key, entry);
+            return Ok(());
+        };
+        if self.recent.insert(key, entry).is_none() {
+            self.order.push_back(key);
+        }
+        if self.recent.len() > capacity {
+            self.spill_oldest(capacity / 2)?;
+        }
+        Ok(())
+    }
+
+    /// Moves the oldest entries to disk until `keep` are left in memory.
+    fn spill_oldest(&mut self, keep: usize) -> Result<()> {
+        let mut entries = Vec::with_capacity(self.recent.len() - keep);
+        while self.recent.len() > keep {
+            let key = self.order.pop_front().expect("every entry is ordered");
+            let entry = self
+                .recent
+                .remove(&key)
+                .expect("ordered keys are in memory");
+            let tx = entry
+                .applied()
+                .unwrap_or_else(|| self.wide.remove(&key).expect("wide amounts are kept"));
+            entries.push((key, tx));
+        }
+        entries.sort_unstable_by_key(|(key, _)| *key);
+        let spill = match &mut self.spill {
+            Some(spill) => spill,
+            None => self.spill.insert(Spill::create()?),
+        };
+        spill.write_run(&entries)?;
+        debug!(
+            "spilled {} txs, {} runs on disk",
+            entries.len(),
+            spill.runs.len()
+        );
+        Ok(())
+    }
+
+    /// Every entry, in memory or spilled, in no particular order.
+    pub fn entries(&self) -> Result<Vec<(TxKey, Applied)>> {
+        let mut entries = self
+            .recent
+            .iter()
+            .map(|(key, entry)| (*key, self.unpack(key, *entry)))
+            .collect::<Vec<_>>();
+        if let Some(spill) = &self.spill {
+            entries.extend(spill.entries()?);
+        }
+        Ok(entries)
+    }
+
+    /// Takes the entries out of memory, only complete if nothing was spilled.
+    pub fn drain(&mut self) -> impl Iterator<Item = (TxKey, Applied)> + '_ {
+        self.order.clear();
+        let mut wide = std::mem::take(&mut self.wide);
+        self.recent.drain().map(move |(key, entry)| {
+            let tx = entry
+                .applied()
+                .unwrap_or_else(|| wide.remove(&key).expect("wide amounts are kept"));
+            (key, tx)
+        })
+    }
+
+    /// In-memory entries of another ledger, which must not have spilled either.
+    pub fn extend(&mut self, other: Ledger) {
+        self.recent.extend(other.recent);
+        self.wide.extend(other.wide);
+    }
+
+    fn unpack(&self, key: &TxKey, entry: Compact) -> Applied {
+        entry.applied().unwrap_or_else(|| self.wide[key])
+    }
+}
+
+impl Spill {
+    fn create() -> Result<Self> {
+        let path = env::temp_dir().join(format!(
+            "paygine-{}-{}.ledger",
+            process::id(),
+            SPILL_FILES.fetch_add(1, Ordering::Relaxed)
+        ));
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(&path)
+            .with_context(|| format!("can't create {}", path.display()))?;
+        info!("Spilling txs to {}", path.display());
+        Ok(Self {
+            path,
+            file: RefCell::new(file),
+            len: 0,
+            runs: Vec::new(),
+        })
+    }
+
+    fn write_run(&mut self, entries: &[(TxKey, Applied)]) -> Result<()> {
+        let Some(last) = entries.last() else {
+            return Ok(());
+        };
+        let mut file = self.file.borrow_mut();
+        file.seek(SeekFrom::End(0))?;
+        let mut writer = BufWriter::new(&mut *file);
+        for (key, tx) in entries {
+            writer.write_all(&encode(key, tx))?;
+        }
+        writer.flush()?;
+        let index = entries
+            .iter()
+            .step_by(BLOCK_SIZE)
+            .map(|(key, _)| *key)
+            .collect();
+        self.runs.push(Run {
+            start: self.len,
+            len: entries.len(),
+            index,
+            last: last.0,
+        });
+        self.len += entries.len() as u64;
+        Ok(())
+    }
+
+    fn entries(&self) -> Result<Vec<(TxKey, Applied)>> {
+        let mut buffer = vec![0; self.len as usize * ENTRY_SIZE];
+        let mut file = self.file.borrow_mut();
+        file.seek(SeekFrom::Start(0))?;
+        file.read_exact(&mut buffer)?;
+        buffer.chunks_exact(ENTRY_SIZE).map(decode).collect()
+    }
+
+    /// Looks `key` up in the runs, newest first.
+    fn get(&self, key: &TxKey) -> Result<Option<Applied>> {
+        for run in self.runs.iter().rev() {
+            if *key < run.index[0] || *key > run.last {
+                continue;
+            }
+            let block = run.index.partition_point(|first| first <= key) - 1;
+            let offset = block * BLOCK_SIZE;
+            let len = BLOCK_SIZE.min(run.len - offset);
+            let mut buffer = vec![0; len * ENTRY_SIZE];
+            let mut file = self.file.borrow_mut();
+            file.seek(SeekFrom::Start(
+                (run.start + offset as u64) * ENTRY_SIZE as u64,
+            ))?;
+            file.read_exact(&mut buffer)?;
+            let entries = buffer
+                .chunks_exact(ENTRY_SIZE)
+                .map(decode)
+                .collect::<Result<Vec<_>>>()?;
+            if let Ok(i) = entries.binary_search_by_key(key, |(key, _)| *key) {
+                return Ok(Some(entries[i].1));
+            }
+        }
+        Ok(None)
+    }
+}
+
+impl Drop for Spill {
+    fn drop(&mut self) {
+        if let Err(e) = fs::remove_file(&self.path) {
+            warn!("can't remove {}: {e}", self.path.display());
+        }
+    }
+}
+
+fn encode((client, epoch, id): &TxKey, tx: &Applied) -> [u8; ENTRY_SIZE] {
+    let mut entry = [0; ENTRY_SIZE];
+    entry[0] = u8::from(client.is_some());
+    entry[1..3].copy_from_slice(&client.unwrap_or_default().to_le_bytes());
+    entry[3..7].copy_from_slice(&epoch.to_le_bytes());
+    entry[7..11].copy_from_slice(&id.to_le_bytes());
+    entry[11..13].copy_from_slice(&tx.client.to_le_bytes());
+    entry[13] = match tx.kind {
+        Kind::Deposit => 0,
+        Kind::Withdrawal => 1,
+        Kind::Transfer => 2,
+        kind => unreachable!("only deposits, withdrawals and transfers are recorded, not {kind:?}"),
+    };
+    entry[14..].copy_from_slice(&tx.amount.decimal().serialize());
+    entry
+}
+
+fn decode(entry: &[u8]) -> Result<(TxKey, Applied)> {
+    let u16_at = |i: usize| u16::from_le_bytes([entry[i], entry[i + 1]]);
+    let u32_at = |i: usize| u32::from_le_bytes(entry[i..i + 4].try_into().expect("4 bytes"));
+    let client = (entry[0] == 1).then(|| u16_at(1));
+    let kind = match entry[13] {
+        0 => Kind::Deposit,
+        1 => Kind::Withdrawal,
+        2 => Kind::Transfer,
+        kind => return Err(anyhow!("corrupt spilled tx kind: {kind}")),
+    };
+    let amount = Decimal::deserialize(entry[14..].try_into().expect("16 bytes"));
+    let tx = Applied {
+        client: u16_at(11),
+        kind,
+        amount: Money::new(amount),
+    };
+    Ok(((client, u32_at(3), u32_at(7)), tx))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn applied(amount: &str) -> Applied {
+        Applied {
+            client: 7,
+            kind: Kind::Withdrawal,
+            amount: amount.parse().unwrap(),
+        }
+    }
+
+    #[test]
+    fn entries_are_compact() {
+        assert_eq!(std::mem::size_of::<Compact>(), 12);
+    }
+
+    #[test]
+    fn amounts_round_trip() {
+        let mut ledger = Ledger::new(None);
+        let amounts = ["100.10", "0.0001", "1e-28", "79228162514264337593543950335"];
+        for (id, amount) in (1..).zip(amounts) {
+            ledger.insert((None, 0, id), applied(amount)).unwrap();
+        }
+        for (id, amount) in (1..).zip(amounts) {
+            let tx = ledger.get(&(None, 0, id)).unwrap().unwrap();
+            assert_eq!((tx.client, tx.amount), (7, amount.parse().unwrap()));
+            assert!(matches!(tx.kind, Kind::Withdrawal));
+        }
+        // A narrow amount replaces a wide one.
+        ledger.insert((None, 0, 4), applied("2.5")).unwrap();
+        assert_eq!(
+            ledger.get(&(None, 0, 4)).unwrap().unwrap().amount,
+            "2.5".parse().unwrap()
+        );
+        assert!(ledger.wide.is_empty());
+    }
+
+    #[test]
+    fn wide_amounts_spill() {
+        let mut ledger = Ledger::new(Some(2));
+        let wide = "1.2345678901234567890123456789";
+        for id in 1..=8 {
+            ledger.insert((None, 0, id), applied(wide)).unwrap();
+        }
+        for id in 1..=8 {
+            let tx = ledger.get(&(None, 0, id)).unwrap().unwrap();
+            assert_eq!(tx.amount, wide.parse().unwrap());
+        }
+        assert_eq!(ledger.entries().unwrap().len(), 8);
+        assert!(ledger.wide.len() <= 2);
+    }
+}
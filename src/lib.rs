@@ -0,0 +1,41 @@
+//! Toy payment engine: applies deposits, withdrawals and their disputes to client balances.
+//!
+//! ```no_run
+//! use paygine::Engine;
+//!
+//! let engine = Engine::from_reader(std::io::stdin())?;
+//! for client in engine.clients().values() {
+//!     println!("{}: {}", client.id(), client.available());
+//! }
+//! # Ok::<(), anyhow::Error>(())
+//! ```
+
+#[macro_use]
+extern crate log;
+
+pub mod client;
+#[cfg(feature = "parquet")]
+mod columnar;
+pub mod config;
+#[cfg(feature = "postgres")]
+mod database;
+pub mod engine;
+pub mod error;
+mod json;
+mod ledger;
+pub mod money;
+pub mod output;
+pub mod policy;
+mod progress;
+pub mod sort;
+mod state;
+#[cfg(feature = "tokio")]
+pub mod stream;
+pub mod transaction;
+
+pub use crate::{
+    client::Client,
+    engine::{Engine, EngineBuilder},
+    error::EngineError,
+    transaction::{Kind, Transaction},
+};
@@ -0,0 +1,74 @@
+use std::{io::Write, str::FromStr};
+
+use anyhow::{anyhow, Result};
+use env_logger::fmt::Formatter;
+use log::{
+    kv::{Error, Key, Value, VisitSource},
+    Record,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Number};
+
+/// How log records are written to stderr.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// `env_logger`'s own format.
+    #[default]
+    Human,
+    /// A JSON object per line, with the structured fields of the record as keys.
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "human" => Ok(Self::Human),
+            "json" => Ok(Self::Json),
+            _ => Err(anyhow!("unknown log format: {s}")),
+        }
+    }
+}
+
+/// Installs the logger, filtered by `RUST_LOG` as before.
+pub fn init(format: LogFormat) {
+    let mut builder = env_logger::Builder::from_default_env();
+    match format {
+        // The messages already mention their fields.
+        LogFormat::Human => builder.format_key_values(|_, _| Ok(())),
+        LogFormat::Json => builder.format(write_json),
+    };
+    builder.init();
+}
+
+/// `{"timestamp":…,"level":"WARN","target":…,"message":…,"tx":9,"client":1,"line":4}`
+fn write_json(buf: &mut Formatter, record: &Record) -> std::io::Result<()> {
+    let mut object = Map::new();
+    object.insert("timestamp".into(), buf.timestamp().to_string().into());
+    object.insert("level".into(), record.level().as_str().into());
+    object.insert("target".into(), record.target().into());
+    object.insert("message".into(), record.args().to_string().into());
+    let mut fields = Fields(&mut object);
+    if let Err(e) = record.key_values().visit(&mut fields) {
+        return Err(std::io::Error::other(e.to_string()));
+    }
+    serde_json::to_writer(&mut *buf, &object)?;
+    writeln!(buf)
+}
+
+struct Fields<'a>(&'a mut Map<String, serde_json::Value>);
+
+impl<'kvs> VisitSource<'kvs> for Fields<'_> {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), Error> {
+        let value = match (value.to_u64(), value.to_i64(), value.to_bool()) {
+            (Some(n), _, _) => Number::from(n).into(),
+            (_, Some(n), _) => Number::from(n).into(),
+            (_, _, Some(b)) => b.into(),
+            _ => value.to_string().into(),
+        };
+        self.0.insert(key.as_str().to_owned(), value);
+        Ok(())
+    }
+}
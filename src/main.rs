@@ -1,46 +1,521 @@
-use std::{env::args, io::stdout, path::PathBuf};
+use std::{
+    env::args,
+    fs::{create_dir_all, File},
+    io::{stdout, BufWriter, Write},
+    path::{Path, PathBuf},
+    process::ExitCode,
+    time::{Duration, Instant},
+};
 
-use anyhow::{anyhow, Context, Error, Result};
-use csv::Writer;
+use anyhow::{anyhow, Context, Result};
+use csv::{ByteRecord, Writer};
+use serde::Serialize;
 
-use crate::engine::Engine;
+use logging::LogFormat;
+use paygine::{
+    config::Config,
+    engine::{Engine, EngineBuilder, EventKind, Rejection, REDACTED},
+    money::Money,
+    output::{self, Format, OutputFormat},
+    sort,
+};
 
 #[macro_use]
 extern crate log;
 
-mod client;
-mod engine;
-mod transaction;
+mod cli;
+mod logging;
 
-pub type MaybeError = Option<Error>;
+/// Exit code of a run which wrote its output but didn't apply every record, see the README.
+const EXIT_REJECTED: u8 = 2;
 
-fn main() -> Result<()> {
-    env_logger::init();
-    info!("Toy Payment Engine");
+fn main() -> ExitCode {
+    match run() {
+        Ok(code) => code,
+        Err(e) => {
+            eprintln!("Error: {e:?}");
+            ExitCode::FAILURE
+        }
+    }
+}
 
-    let input = input_file_from_args()?;
-    info!("Input: {}", input.display());
+fn run() -> Result<ExitCode> {
+    let started = Instant::now();
+    match args().nth(1).as_deref() {
+        Some("sort") => {
+            logging::init(LogFormat::Human);
+            return sort_command().map(|()| ExitCode::SUCCESS);
+        }
+        Some("explain-tx") => {
+            logging::init(LogFormat::Human);
+            return explain_command().map(|()| ExitCode::SUCCESS);
+        }
+        Some("diff") => {
+            logging::init(LogFormat::Human);
+            return diff_command();
+        }
+        _ => {}
+    }
 
-    let engine = Engine::new(input).with_context(|| "invalid input")?;
-    let clients = engine
-        .process()
-        .with_context(|| "processing input failed")?;
+    let args = cli::parse_args()?;
+    logging::init(args.log_format);
+    info!("Toy Payment Engine");
+    let format = output_format(&args);
+    if let Some(path) = &args.schema {
+        let schema = format.schema(
+            args.stats || args.report_open_disputes,
+            args.report_open_disputes,
+        );
+        if path == Path::new("-") {
+            serde_json::to_writer_pretty(stdout(), &schema)?;
+            println!();
+            return Ok(ExitCode::SUCCESS);
+        }
+        let file =
+            File::create(path).with_context(|| format!("can't create {}", path.display()))?;
+        serde_json::to_writer_pretty(file, &schema)?;
+    }
+    for input in &args.engine.input {
+        info!("Input: {}", input.display());
+    }
+
+    let mut engine = EngineBuilder::from(args.engine.clone())
+        .build()
+        .with_context(|| "invalid input")?;
+    let processed = match (args.byte_range, args.jobs) {
+        (Some(range), _) => engine.process_range(range.start, range.end),
+        (None, Some(jobs)) => engine.process_parallel(jobs),
+        (None, None) => engine.process(),
+    };
+    if processed.is_err() && !engine.error_context().is_empty() {
+        eprintln!("records around the failing one:");
+        for record in engine.error_context() {
+            eprintln!("{record}");
+        }
+    }
+    processed.with_context(|| "processing input failed")?;
     info!("Process finished");
+    let stats = engine.stats();
+    info!(
+        "Deposits: {}, withdrawals: {}, disputes: {}, resolves: {}, chargebacks: {}, \
+         unfreezes: {}, transfers: {}, rejected: {}, locked clients: {}",
+        stats.deposits,
+        stats.withdrawals,
+        stats.disputes,
+        stats.resolves,
+        stats.chargebacks,
+        stats.unfreezes,
+        stats.transfers,
+        engine.rejected().len(),
+        engine.clients().values().filter(|c| c.is_locked()).count()
+    );
+    if args.check {
+        return check(&engine).map(|()| ExitCode::SUCCESS);
+    }
+
+    if stats.records > 0 && stats.applied == 0 && !args.allow_empty_result {
+        return Err(anyhow!(
+            "none of {} records were applied, the input is probably in a wrong format \
+             (use --allow-empty-result to accept it)",
+            stats.records
+        ));
+    }
+
+    let mut rows = format.rows(
+        engine
+            .clients()
+            .values()
+            .filter(|c| !args.redact_clients.contains(&c.id())),
+    )?;
+    // Client ids are unique, rows come out the same on every run.
+    rows.sort_unstable_by_key(|row| row.client);
+    if args.stats || args.report_open_disputes {
+        let open_disputes = engine.open_disputes()?;
+        for row in &mut rows {
+            let open = open_disputes.get(&row.client).copied().unwrap_or_default();
+            row.open_disputes = Some(open.count);
+            if !args.report_open_disputes {
+                continue;
+            }
+            let held = engine.clients()[&row.client].held();
+            if open.amount != held {
+                warn!(
+                    client = row.client;
+                    "Client #{}: open disputes hold {}, held is {held}",
+                    row.client, open.amount
+                );
+            }
+            row.disputed_held = Some(format.value(open.amount)?);
+        }
+    }
+    if args.output.is_empty() {
+        output::sink(None, args.format, None, None, &format, false)?.write(&rows)?;
+    }
+    for path in &args.output {
+        let output_format = OutputFormat::from_path(path).unwrap_or(args.format);
+        output::sink(
+            Some(path),
+            output_format,
+            args.output_compress,
+            args.max_output_bytes,
+            &format,
+            args.fsync,
+        )?
+        .write(&rows)?;
+    }
+
+    info!("Result printed");
+    if let Some(path) = &args.save_state {
+        engine.save_state(path)?;
+    }
+    if args.engine.chargeback_fee.is_some() {
+        info!("Fees collected: {}", engine.fees_collected());
+    }
+
+    if let Some(path) = &args.held_detail {
+        let mut detail = engine.held_detail(format.precision);
+        for held in &mut detail {
+            if held
+                .client()
+                .is_some_and(|id| args.redact_clients.contains(&id))
+            {
+                held.redact();
+            }
+        }
+        write_sidecar(path, detail)?;
+    }
+    if let Some(dir) = &args.group_by_locked {
+        create_dir_all(dir).with_context(|| format!("can't create {}", dir.display()))?;
+        let (locked, active): (Vec<_>, Vec<_>) = rows.iter().partition(|row| row.locked);
+        write_sidecar(&dir.join("active.csv"), active)?;
+        write_sidecar(&dir.join("locked.csv"), locked)?;
+    }
+    if let Some(path) = &args.batch_report {
+        write_sidecar(path, engine.batches())?;
+    }
+    if let Some(path) = &args.rejects_replay {
+        write_replay(path, &engine, &args.redact_clients)?;
+    }
+    if let Some(path) = &args.rejects {
+        write_rejects(path, &engine, &args.redact_clients)?;
+    }
+    if let (Some(path), Some(threshold)) = (&args.anomalies, args.dispute_ratio_threshold) {
+        let anomalies = engine
+            .anomalies(threshold)
+            .into_iter()
+            .filter(|anomaly| !args.redact_clients.contains(&anomaly.client));
+        write_sidecar(path, anomalies)?;
+    }
+    if let Some(path) = &args.events {
+        write_events(path, &engine, &args.redact_clients)?;
+    }
+    if let Some(path) = &args.manifest {
+        write_manifest(path, &args)?;
+    }
+    if let Some(path) = &args.summary {
+        write_summary(path, &engine)?;
+    }
+
+    if args.rounding_report {
+        output::print_rounding_report(&format, engine.clients().values())?;
+    }
+    if !args.quiet {
+        print_summary(&engine, started.elapsed());
+    }
+    for (line, e) in engine.failures() {
+        eprintln!("line {line}: {e:#}");
+    }
+
+    if let Some(path) = &args.verify_against {
+        let differences = output::verify(&rows, path)?;
+        for difference in &differences {
+            eprintln!("{difference}");
+        }
+        if !differences.is_empty() {
+            return Err(anyhow!("output differs from {}", path.display()));
+        }
+    }
+    if args.fail_on_locked {
+        let mut locked = engine
+            .clients()
+            .values()
+            .filter(|c| c.is_locked())
+            .map(|c| c.id())
+            .collect::<Vec<_>>();
+        if !locked.is_empty() {
+            locked.sort_unstable();
+            let ids = locked.iter().map(u16::to_string).collect::<Vec<_>>();
+            return Err(anyhow!("locked clients: {}", ids.join(", ")));
+        }
+    }
+
+    if !engine.failures().is_empty() {
+        eprintln!("{} records failed", engine.failures().len());
+        return Ok(ExitCode::from(EXIT_REJECTED));
+    }
+    if !engine.rejected().is_empty() {
+        return Ok(ExitCode::from(EXIT_REJECTED));
+    }
+    Ok(ExitCode::SUCCESS)
+}
+
+fn output_format(args: &cli::Args) -> Format {
+    let mut format = Format {
+        balance_extremes: args.balance_extremes,
+        fix_invariant: args.fix_output_invariant,
+        clamp_negative: args.clamp_negative_output,
+        json_strings: args.json_numbers_as_strings,
+        held_negative: args.held_negative,
+        rounding: args.rounding,
+        ..Format::default()
+    };
+    if let Some(rate) = args.convert {
+        format.convert = rate;
+    }
+    if let Some(precision) = args.precision {
+        format.precision = precision;
+    }
+    format
+}
+
+/// Lists every reject and warning of a `--check` run, failing if there is any.
+fn check(engine: &Engine) -> Result<()> {
+    let mut problems = engine
+        .events()
+        .iter()
+        .filter(|event| matches!(event.kind, EventKind::Reject | EventKind::Warning))
+        .collect::<Vec<_>>();
+    problems.sort_by_key(|event| event.line);
+    for event in &problems {
+        let line = event
+            .line
+            .map_or_else(String::new, |line| format!("line {line}: "));
+        match &event.detail {
+            Some(record) => println!("{line}{} ({record})", event.reason),
+            None => println!("{line}{}", event.reason),
+        }
+    }
+    if !problems.is_empty() {
+        return Err(anyhow!("{} problems found", problems.len()));
+    }
+    Ok(())
+}
+
+fn print_summary(engine: &Engine, elapsed: Duration) {
+    let stats = engine.stats();
+    let clients = engine.clients();
+    let locked = clients.values().filter(|c| c.is_locked()).count();
+    let sampled_out = if stats.sampled_out > 0 {
+        format!(", sampled out: {}", stats.sampled_out)
+    } else {
+        String::new()
+    };
+    let duplicates = if stats.duplicates > 0 {
+        format!(", duplicates: {}", stats.duplicates)
+    } else {
+        String::new()
+    };
+    eprintln!(
+        "records: {}, applied: {}, skipped: {}, rejected: {}{sampled_out}{duplicates}, clients: {}, \
+         locked: {locked}, elapsed: {:.3}s",
+        stats.records,
+        stats.applied,
+        stats.skipped,
+        engine.rejected().len(),
+        clients.len(),
+        elapsed.as_secs_f64()
+    );
+}
+
+/// `sort <input> <output> [--chunk-size N]`
+fn sort_command() -> Result<()> {
+    let mut paths = Vec::new();
+    let mut chunk_size = sort::DEFAULT_CHUNK_SIZE;
+    let mut args = args().skip(2);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--chunk-size" => chunk_size = cli::flag_value(&arg, args.next())?,
+            _ => paths.push(PathBuf::from(arg)),
+        }
+    }
+    let [input, output] = paths.as_slice() else {
+        return Err(anyhow!("usage: sort <input> <output> [--chunk-size N]"));
+    };
+    if chunk_size == 0 {
+        return Err(anyhow!("--chunk-size must be positive"));
+    }
+    sort::sort_file(input, output, chunk_size)
+}
 
+/// `explain-tx <input> <id>`
+fn explain_command() -> Result<()> {
+    let (Some(input), Some(id), None) = (args().nth(2), args().nth(3), args().nth(4)) else {
+        return Err(anyhow!("usage: explain-tx <input> <id>"));
+    };
+    let config = Config {
+        input: vec![PathBuf::from(input)],
+        explain_tx: Some(cli::flag_value("explain-tx", Some(id))?),
+        ..Config::default()
+    };
+    let mut engine = EngineBuilder::from(config).build()?;
+    engine.process()?;
     let mut writer = Writer::from_writer(stdout());
-    for client in clients.values() {
-        writer.serialize(client)?;
+    for step in engine.timeline() {
+        writer.serialize(step)?;
     }
     writer.flush()?;
+    Ok(())
+}
 
-    info!("Result printed");
+/// `diff <old> <new>`, exiting with 1 if the outputs differ.
+fn diff_command() -> Result<ExitCode> {
+    let (Some(old), Some(new), None) = (args().nth(2), args().nth(3), args().nth(4)) else {
+        return Err(anyhow!("usage: diff <old> <new>"));
+    };
+    let differences = output::diff(Path::new(&old), Path::new(&new))?;
+    for difference in &differences {
+        println!("{difference}");
+    }
+    if differences.is_empty() {
+        return Ok(ExitCode::SUCCESS);
+    }
+    eprintln!("{} clients differ", differences.len());
+    Ok(ExitCode::FAILURE)
+}
+
+fn write_sidecar<T: Serialize>(path: &Path, rows: impl IntoIterator<Item = T>) -> Result<()> {
+    let mut writer =
+        Writer::from_path(path).with_context(|| format!("can't create {}", path.display()))?;
+    for row in rows {
+        writer.serialize(row)?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// Totals of a run as one JSON object, for monitoring.
+#[derive(Serialize)]
+struct Summary {
+    records: u64,
+    applied: u64,
+    rejected: usize,
+    deposits: u64,
+    withdrawals: u64,
+    disputes: u64,
+    resolves: u64,
+    chargebacks: u64,
+    unfreezes: u64,
+    transfers: u64,
+    clients: usize,
+    locked: usize,
+    /// Sums over all clients, exact rather than rounded like the output.
+    total: Money,
+    held: Money,
+}
+
+fn write_summary(path: &Path, engine: &Engine) -> Result<()> {
+    let stats = engine.stats();
+    let clients = engine.clients().values();
+    let mut summary = Summary {
+        records: stats.records,
+        applied: stats.applied,
+        rejected: engine.rejected().len(),
+        deposits: stats.deposits,
+        withdrawals: stats.withdrawals,
+        disputes: stats.disputes,
+        resolves: stats.resolves,
+        chargebacks: stats.chargebacks,
+        unfreezes: stats.unfreezes,
+        transfers: stats.transfers,
+        clients: clients.len(),
+        locked: 0,
+        total: Money::ZERO,
+        held: Money::ZERO,
+    };
+    for client in clients {
+        summary.locked += usize::from(client.is_locked());
+        summary.total = summary.total.checked_add(client.total())?;
+        summary.held = summary.held.checked_add(client.held())?;
+    }
+    let file = File::create(path).with_context(|| format!("can't create {}", path.display()))?;
+    serde_json::to_writer_pretty(file, &summary)?;
+    Ok(())
+}
+
+/// What a run needs to be reproduced: the seed and a hash of every other option.
+#[derive(Serialize)]
+struct Manifest {
+    seed: u64,
+    config_hash: String,
+}
+
+fn write_manifest(path: &Path, args: &cli::Args) -> Result<()> {
+    let manifest = Manifest {
+        seed: args.engine.seed,
+        config_hash: format!("{:016x}", args.config_hash()?),
+    };
+    let file = File::create(path).with_context(|| format!("can't create {}", path.display()))?;
+    serde_json::to_writer_pretty(file, &manifest)?;
+    Ok(())
+}
 
+/// Writes rejected records in the input schema, so they can be fixed and fed back.
+fn write_replay(path: &Path, engine: &Engine, redact_clients: &[u16]) -> Result<()> {
+    let mut writer =
+        Writer::from_path(path).with_context(|| format!("can't create {}", path.display()))?;
+    let headers = engine.headers();
+    writer.write_byte_record(headers)?;
+    for rejection in engine.rejected() {
+        writer.write_byte_record(&redact(&rejection.record, headers, redact_clients))?;
+    }
+    writer.flush()?;
     Ok(())
 }
 
-fn input_file_from_args() -> Result<PathBuf> {
-    let mut args = args();
-    args.nth(1)
-        .map(PathBuf::from)
-        .ok_or_else(|| anyhow!("Valid path to CSV file must be provided as a first argument"))
+/// Writes rejected records with their line and the reason they were not applied.
+fn write_rejects(path: &Path, engine: &Engine, redact_clients: &[u16]) -> Result<()> {
+    let rejects = engine.rejected().iter().map(|rejection| Rejection {
+        record: redact(&rejection.record, engine.headers(), redact_clients),
+        ..rejection.clone()
+    });
+    write_sidecar(path, rejects)
+}
+
+/// Writes the rejects, warnings and locks as JSON lines, in processing order.
+fn write_events(path: &Path, engine: &Engine, redact_clients: &[u16]) -> Result<()> {
+    let file = File::create(path).with_context(|| format!("can't create {}", path.display()))?;
+    let mut writer = BufWriter::new(file);
+    for event in engine.events() {
+        let mut event = event.clone();
+        if event.client.is_some_and(|id| redact_clients.contains(&id)) {
+            event.redact();
+        }
+        serde_json::to_writer(&mut writer, &event)?;
+        writeln!(writer)?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// Replaces the client id of `record` if the client is redacted.
+fn redact(record: &ByteRecord, headers: &ByteRecord, redact_clients: &[u16]) -> ByteRecord {
+    let client_column = headers.iter().position(|h| h == b"client");
+    let redacted = client_column
+        .and_then(|i| record.get(i))
+        .and_then(|id| std::str::from_utf8(id).ok()?.parse::<u16>().ok())
+        .is_some_and(|id| redact_clients.contains(&id));
+    if !redacted {
+        return record.clone();
+    }
+    record
+        .iter()
+        .enumerate()
+        .map(|(i, field)| {
+            if Some(i) == client_column {
+                REDACTED.as_bytes()
+            } else {
+                field
+            }
+        })
+        .collect()
 }
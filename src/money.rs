@@ -0,0 +1,306 @@
+use std::{
+    fmt::{self, Display},
+    str::FromStr,
+};
+
+use anyhow::{anyhow, Error, Result};
+use rust_decimal::{Decimal, RoundingStrategy};
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use thiserror::Error as ThisError;
+
+/// Decimal places kept in the output by default, extra digits are truncated.
+pub const DEFAULT_PRECISION: u32 = 4;
+
+/// Most decimal places a [`Money`] can hold.
+pub const MAX_PRECISION: u32 = Decimal::MAX_SCALE;
+
+/// An arithmetic result outside of the range of [`Money`].
+#[derive(Debug, Clone, PartialEq, Eq, ThisError)]
+#[error("amount overflow: {0}")]
+pub struct Overflow(String);
+
+/// An exact decimal amount of money.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Money(Decimal);
+
+impl Money {
+    pub const ZERO: Self = Self(Decimal::ZERO);
+
+    pub fn new(value: Decimal) -> Self {
+        Self(value)
+    }
+
+    pub fn decimal(self) -> Decimal {
+        self.0
+    }
+
+    pub fn abs(self) -> Self {
+        Self(self.0.abs())
+    }
+
+    pub fn checked_add(self, rhs: Self) -> Result<Self, Overflow> {
+        self.0
+            .checked_add(rhs.0)
+            .map(Self)
+            .ok_or_else(|| Overflow(format!("{self} + {rhs}")))
+    }
+
+    pub fn checked_sub(self, rhs: Self) -> Result<Self, Overflow> {
+        self.0
+            .checked_sub(rhs.0)
+            .map(Self)
+            .ok_or_else(|| Overflow(format!("{self} - {rhs}")))
+    }
+
+    pub fn checked_mul(self, factor: Decimal) -> Result<Self, Overflow> {
+        self.0
+            .checked_mul(factor)
+            .map(Self)
+            .ok_or_else(|| Overflow(format!("{self} * {factor}")))
+    }
+
+    /// The amount truncated to `dp` decimal places, as an integer count of `10^-dp` units.
+    #[cfg(feature = "parquet")]
+    pub fn scaled(self, dp: u32) -> i128 {
+        let mut value = self.0.trunc_with_scale(dp);
+        value.rescale(dp);
+        value.mantissa()
+    }
+
+    /// Drops digits past `dp` decimal places, rounding toward zero.
+    pub fn truncate(self, dp: u32) -> Self {
+        Self(self.0.trunc_with_scale(dp))
+    }
+
+    /// Rounds to `dp` decimal places with `strategy`.
+    pub fn round(self, dp: u32, strategy: RoundingStrategy) -> Self {
+        Self(self.0.round_dp_with_strategy(dp, strategy))
+    }
+}
+
+/// Accepts plain (`12.5`, `-3`) and scientific (`1.25e1`) notation with a `.` separator.
+///
+/// Digit grouping (`1,000`, `1_000`) is rejected rather than guessed at.
+impl FromStr for Money {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        if s.is_empty() {
+            return Err(anyhow!("empty amount"));
+        }
+        if s.contains([',', '_']) {
+            return Err(anyhow!(
+                "invalid amount {s}: digit grouping is not supported"
+            ));
+        }
+        let value = if s.contains(['e', 'E']) {
+            Decimal::from_scientific(s)
+        } else {
+            Decimal::from_str(s)
+        };
+        value
+            .map(Self)
+            .map_err(|e| anyhow!("invalid amount {s}: {e}"))
+    }
+}
+
+/// Prints the shortest exact form with at least one decimal place (`3.0`, `0.125`).
+///
+/// An explicit precision (`{:.2}`) truncates to that many places instead.
+impl Display for Money {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match f.precision() {
+            Some(dp) => {
+                let value = self.0.trunc_with_scale(dp as u32);
+                write!(f, "{value:.dp$}")
+            }
+            None => {
+                let mut value = self.0.normalize();
+                if value.scale() == 0 {
+                    value.rescale(1);
+                }
+                write!(f, "{value}")
+            }
+        }
+    }
+}
+
+impl Serialize for Money {
+    fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+        s.collect_str(self)
+    }
+}
+
+/// Reads amounts from their text, so CSV fields are never rounded through `f64`.
+impl<'de> Deserialize<'de> for Money {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_str(MoneyVisitor)
+    }
+}
+
+/// Deserializes an amount field, empty or blank text being no amount.
+///
+/// Text which isn't an amount fails with the text, instead of reading as a missing amount.
+pub fn deserialize_field<'de, D>(deserializer: D) -> Result<Option<Money>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    deserializer.deserialize_option(FieldVisitor)
+}
+
+/// Parses the field text where it is, a record's amount isn't copied into a `String` first.
+struct FieldVisitor;
+
+impl<'de> de::Visitor<'de> for FieldVisitor {
+    type Value = Option<Money>;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("an optional decimal amount")
+    }
+
+    fn visit_none<E: de::Error>(self) -> Result<Self::Value, E> {
+        Ok(None)
+    }
+
+    fn visit_unit<E: de::Error>(self) -> Result<Self::Value, E> {
+        Ok(None)
+    }
+
+    fn visit_some<D: Deserializer<'de>>(self, deserializer: D) -> Result<Self::Value, D::Error> {
+        deserializer.deserialize_str(self)
+    }
+
+    fn visit_str<E: de::Error>(self, v: &str) -> Result<Self::Value, E> {
+        if v.trim().is_empty() {
+            return Ok(None);
+        }
+        v.parse().map(Some).map_err(|e| E::custom(format!("{e:#}")))
+    }
+}
+
+/// Deserializes an optional amount given either as a number or as text, e.g. in the TOML config.
+pub fn deserialize_number<'de, D>(deserializer: D) -> Result<Option<Money>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    deserializer.deserialize_any(MoneyVisitor).map(Some)
+}
+
+struct MoneyVisitor;
+
+impl de::Visitor<'_> for MoneyVisitor {
+    type Value = Money;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a decimal amount")
+    }
+
+    fn visit_str<E: de::Error>(self, v: &str) -> Result<Money, E> {
+        v.parse().map_err(E::custom)
+    }
+
+    fn visit_f64<E: de::Error>(self, v: f64) -> Result<Money, E> {
+        self.visit_str(&v.to_string())
+    }
+
+    fn visit_i64<E: de::Error>(self, v: i64) -> Result<Money, E> {
+        Ok(Money(v.into()))
+    }
+
+    fn visit_u64<E: de::Error>(self, v: u64) -> Result<Money, E> {
+        Ok(Money(v.into()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn money(s: &str) -> Money {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn parses_plain_and_scientific() {
+        assert_eq!(money("12.5"), Money(Decimal::new(125, 1)));
+        assert_eq!(money("-3"), Money(Decimal::new(-3, 0)));
+        assert_eq!(money("1.25e1"), money("12.5"));
+        assert_eq!(money("5E-3"), money("0.005"));
+    }
+
+    #[test]
+    fn rejects_invalid_text() {
+        for text in ["", "abc", "1.2.3", "1,000", "1_000", "12.5 EUR"] {
+            assert!(text.parse::<Money>().is_err(), "{text:?} parsed");
+        }
+    }
+
+    #[test]
+    fn displays_shortest_exact_form() {
+        assert_eq!(money("3").to_string(), "3.0");
+        assert_eq!(money("0.1250").to_string(), "0.125");
+        assert_eq!(money("-2.50").to_string(), "-2.5");
+        assert_eq!(format!("{:.2}", money("1.999")), "1.99");
+        assert_eq!(format!("{:.4}", money("2")), "2.0000");
+    }
+
+    #[test]
+    fn truncates_toward_zero() {
+        assert_eq!(money("1.23456").truncate(4), money("1.2345"));
+        assert_eq!(money("-1.23456").truncate(4), money("-1.2345"));
+        assert_eq!(money("1.2").truncate(4), money("1.2"));
+    }
+
+    #[test]
+    fn rounds_with_strategy() {
+        let value = money("2.345");
+        assert_eq!(
+            value.round(2, RoundingStrategy::MidpointNearestEven),
+            money("2.34")
+        );
+        assert_eq!(
+            value.round(2, RoundingStrategy::MidpointAwayFromZero),
+            money("2.35")
+        );
+        assert_eq!(value.round(2, RoundingStrategy::ToZero), money("2.34"));
+    }
+
+    #[test]
+    fn adds_exactly() {
+        let sum = money("0.1").checked_add(money("0.2")).unwrap();
+        assert_eq!(sum, money("0.3"));
+        let mut total = Money::ZERO;
+        for _ in 0..10 {
+            total = total.checked_add(money("0.1")).unwrap();
+        }
+        assert_eq!(total, money("1"));
+        assert_eq!(total.checked_sub(money("0.9999")).unwrap(), money("0.0001"));
+        assert_eq!(
+            money("1.5").checked_mul(Decimal::new(3, 0)).unwrap(),
+            money("4.5")
+        );
+    }
+
+    #[test]
+    fn reports_overflow() {
+        let max = Money(Decimal::MAX);
+        assert!(max.checked_add(money("1")).is_err());
+        assert!(Money(Decimal::MIN).checked_sub(money("1")).is_err());
+        assert!(max.checked_mul(Decimal::new(2, 0)).is_err());
+    }
+
+    #[test]
+    fn deserializes_empty_field_as_none() {
+        #[derive(Deserialize)]
+        struct Row {
+            #[serde(deserialize_with = "deserialize_field")]
+            amount: Option<Money>,
+        }
+        let mut reader = csv::Reader::from_reader("amount\n\"\"\n1.5\nabc\n".as_bytes());
+        let rows: Vec<_> = reader.deserialize::<Row>().collect();
+        assert_eq!(rows.len(), 3);
+        assert_eq!(rows[0].as_ref().unwrap().amount, None);
+        assert_eq!(rows[1].as_ref().unwrap().amount, Some(money("1.5")));
+        assert!(rows[2].is_err());
+    }
+}
@@ -0,0 +1,757 @@
+use std::{
+    cell::Cell,
+    collections::BTreeMap,
+    fs::File,
+    io::{self, stdout, Stdout, Write},
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use anyhow::{anyhow, Context, Result};
+use csv::{Reader, Writer};
+use flate2::{write::GzEncoder, Compression};
+use rust_decimal::{Decimal, RoundingStrategy};
+use serde::{ser::Error as _, Deserialize, Serialize, Serializer};
+use serde_json::value::RawValue;
+
+use crate::{
+    client::Client,
+    money::{Money, DEFAULT_PRECISION},
+};
+
+/// Compression applied to the output file.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputCompression {
+    #[serde(alias = "gz")]
+    Gzip,
+}
+
+impl FromStr for OutputCompression {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "gz" | "gzip" => Ok(Self::Gzip),
+            _ => Err(anyhow!("unsupported compression: {s}")),
+        }
+    }
+}
+
+/// File format of the client rows.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputFormat {
+    #[default]
+    Csv,
+    /// An array of row objects.
+    Json,
+    /// Typed columns, needs the `parquet` feature.
+    Parquet,
+}
+
+impl OutputFormat {
+    /// The format implied by the file extension, if it names one.
+    pub fn from_path(path: &Path) -> Option<Self> {
+        match path.extension()?.to_str()? {
+            "csv" => Some(Self::Csv),
+            "json" => Some(Self::Json),
+            "parquet" => Some(Self::Parquet),
+            _ => None,
+        }
+    }
+}
+
+impl FromStr for OutputFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "csv" => Ok(Self::Csv),
+            "json" => Ok(Self::Json),
+            "parquet" => Ok(Self::Parquet),
+            _ => Err(anyhow!("unsupported output format: {s}")),
+        }
+    }
+}
+
+/// A destination the finalized client rows are written to.
+pub trait OutputSink {
+    fn write(self: Box<Self>, rows: &[ClientRow]) -> Result<()>;
+}
+
+/// Rows as CSV, to stdout or a possibly compressed file.
+pub struct CsvSink {
+    output: Output,
+    fsync: bool,
+}
+
+impl OutputSink for CsvSink {
+    fn write(self: Box<Self>, rows: &[ClientRow]) -> Result<()> {
+        let mut writer = Writer::from_writer(self.output);
+        for row in rows {
+            writer.serialize(row)?;
+        }
+        writer
+            .into_inner()
+            .map_err(|e| e.into_error())?
+            .finish(self.fsync)
+            .with_context(|| "can't finish output")
+    }
+}
+
+/// Rows as CSV parts of bounded size, see [`write_rotated`].
+pub struct RotatedSink {
+    path: PathBuf,
+    max_bytes: u64,
+    fsync: bool,
+}
+
+impl OutputSink for RotatedSink {
+    fn write(self: Box<Self>, rows: &[ClientRow]) -> Result<()> {
+        let parts = write_rotated(&self.path, rows, self.max_bytes, self.fsync)?;
+        info!("Output split into {} parts", parts.len());
+        Ok(())
+    }
+}
+
+/// Rows as a JSON array of objects, with balances at the output precision.
+pub struct JsonSink {
+    output: Output,
+    balances: JsonBalances,
+    fsync: bool,
+}
+
+impl OutputSink for JsonSink {
+    fn write(mut self: Box<Self>, rows: &[ClientRow]) -> Result<()> {
+        JSON_BALANCES.with(|balances| balances.set(Some(self.balances)));
+        let written = serde_json::to_writer_pretty(&mut self.output, rows);
+        JSON_BALANCES.with(|balances| balances.set(None));
+        written?;
+        self.output.write_all(b"\n")?;
+        self.output
+            .finish(self.fsync)
+            .with_context(|| "can't finish output")
+    }
+}
+
+/// Rows as a Parquet file.
+#[cfg_attr(not(feature = "parquet"), allow(dead_code))]
+pub struct ParquetSink {
+    path: PathBuf,
+    precision: u32,
+    fsync: bool,
+}
+
+impl OutputSink for ParquetSink {
+    #[cfg(feature = "parquet")]
+    fn write(self: Box<Self>, rows: &[ClientRow]) -> Result<()> {
+        crate::columnar::write_parquet(&self.path, rows, self.precision, self.fsync)
+    }
+
+    #[cfg(not(feature = "parquet"))]
+    fn write(self: Box<Self>, _rows: &[ClientRow]) -> Result<()> {
+        Err(anyhow!(
+            "parquet output {} needs a build with the `parquet` feature",
+            self.path.display()
+        ))
+    }
+}
+
+/// Opens the sink for `path`, stdout if `None`.
+///
+/// Compression and rotation only apply to CSV sinks, the precision of `style` only to JSON
+/// balances and the typed Parquet columns. With `fsync` written files are synced to disk before
+/// the sink returns.
+pub fn sink(
+    path: Option<&Path>,
+    format: OutputFormat,
+    compression: Option<OutputCompression>,
+    max_bytes: Option<u64>,
+    style: &Format,
+    fsync: bool,
+) -> Result<Box<dyn OutputSink>> {
+    Ok(match (format, path, max_bytes) {
+        (OutputFormat::Parquet, Some(path), _) => Box::new(ParquetSink {
+            path: path.to_owned(),
+            precision: style.precision,
+            fsync,
+        }),
+        (OutputFormat::Parquet, None, _) => {
+            return Err(anyhow!("parquet output can't be written to stdout"))
+        }
+        (OutputFormat::Json, path, _) => Box::new(JsonSink {
+            output: Output::open(path, compression)?,
+            balances: JsonBalances {
+                precision: style.precision,
+                strings: style.json_strings,
+            },
+            fsync,
+        }),
+        (OutputFormat::Csv, Some(path), Some(max_bytes)) => Box::new(RotatedSink {
+            path: path.to_owned(),
+            max_bytes,
+            fsync,
+        }),
+        (OutputFormat::Csv, path, _) => Box::new(CsvSink {
+            output: Output::open(path, compression)?,
+            fsync,
+        }),
+    })
+}
+
+/// Destination of the client rows.
+pub enum Output {
+    Stdout(Stdout),
+    File(File),
+    Gzip(GzEncoder<File>),
+}
+
+impl Output {
+    pub fn open(path: Option<&Path>, compression: Option<OutputCompression>) -> Result<Self> {
+        let Some(path) = path else {
+            return Ok(Self::Stdout(stdout()));
+        };
+        let file =
+            File::create(path).with_context(|| format!("can't create {}", path.display()))?;
+        Ok(match compression {
+            None => Self::File(file),
+            Some(OutputCompression::Gzip) => {
+                Self::Gzip(GzEncoder::new(file, Compression::default()))
+            }
+        })
+    }
+
+    /// Flushes pending data, writing the compression trailer if any, and with `fsync` syncs
+    /// a file to disk.
+    pub fn finish(self, fsync: bool) -> io::Result<()> {
+        let mut file = match self {
+            Self::Stdout(mut out) => return out.flush(),
+            Self::File(file) => file,
+            Self::Gzip(encoder) => encoder.finish()?,
+        };
+        file.flush()?;
+        if fsync {
+            file.sync_all()?;
+        }
+        Ok(())
+    }
+}
+
+impl Write for Output {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Self::Stdout(out) => out.write(buf),
+            Self::File(file) => file.write(buf),
+            Self::Gzip(encoder) => encoder.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Self::Stdout(out) => out.flush(),
+            Self::File(file) => file.flush(),
+            Self::Gzip(encoder) => encoder.flush(),
+        }
+    }
+}
+
+/// How client balances are presented in the output.
+#[derive(Debug, Clone, Copy)]
+pub struct Format {
+    /// Multiplier applied to every balance, e.g. a currency conversion rate.
+    pub convert: Decimal,
+    /// Add the lowest and highest available balance seen during processing.
+    pub balance_extremes: bool,
+    /// Force `total` to `available + held` in the output.
+    pub fix_invariant: bool,
+    /// Show negative balances of locked clients as zero.
+    pub clamp_negative: bool,
+    pub held_negative: HeldNegative,
+    /// Decimal places balances are rounded to.
+    pub precision: u32,
+    pub rounding: Rounding,
+    /// Write JSON balances as strings rather than numbers, for consumers parsing numbers as
+    /// floats.
+    pub json_strings: bool,
+}
+
+/// How balances are rounded to the output precision.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Rounding {
+    /// Toward zero.
+    #[default]
+    Truncate,
+    /// To the nearest, halves away from zero.
+    HalfUp,
+    /// To the nearest, halves to the even neighbour (banker's rounding).
+    HalfEven,
+}
+
+impl FromStr for Rounding {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "truncate" => Ok(Self::Truncate),
+            "half-up" => Ok(Self::HalfUp),
+            "half-even" => Ok(Self::HalfEven),
+            _ => Err(anyhow!("unknown rounding mode: {s}")),
+        }
+    }
+}
+
+/// How a negative held balance, possible only with a forced resolve, is presented.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HeldNegative {
+    #[default]
+    Show,
+    /// Show it as zero.
+    Clamp,
+    /// Fail the output.
+    Error,
+}
+
+impl FromStr for HeldNegative {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "show" => Ok(Self::Show),
+            "clamp" => Ok(Self::Clamp),
+            "error" => Ok(Self::Error),
+            _ => Err(anyhow!("unknown negative held mode: {s}")),
+        }
+    }
+}
+
+impl Default for Format {
+    fn default() -> Self {
+        Self {
+            convert: Decimal::ONE,
+            balance_extremes: false,
+            fix_invariant: false,
+            clamp_negative: false,
+            held_negative: HeldNegative::Show,
+            precision: DEFAULT_PRECISION,
+            rounding: Rounding::Truncate,
+            json_strings: false,
+        }
+    }
+}
+
+impl Format {
+    /// Converts and rounds a balance for the output.
+    pub fn value(&self, x: Money) -> Result<Money> {
+        let x = x.checked_mul(self.convert)?;
+        Ok(match self.rounding {
+            Rounding::Truncate => x.truncate(self.precision),
+            Rounding::HalfUp => x.round(self.precision, RoundingStrategy::MidpointAwayFromZero),
+            Rounding::HalfEven => x.round(self.precision, RoundingStrategy::MidpointNearestEven),
+        })
+    }
+
+    pub fn row(&self, client: &Client) -> Result<ClientRow> {
+        let mut row = ClientRow {
+            client: client.id(),
+            available: self.value(client.available())?,
+            held: self.value(client.held())?,
+            total: self.value(client.total())?,
+            locked: client.is_locked(),
+            available_min: None,
+            available_max: None,
+            open_disputes: None,
+            disputed_held: None,
+        };
+        if self.fix_invariant {
+            let total = row.available.checked_add(row.held)?;
+            // Truncating each balance separately can drift the sum by one output step.
+            let step = Money::new(Decimal::new(1, self.precision));
+            if total.checked_sub(row.total)?.abs() > step {
+                warn!(
+                    "Client #{}: fixing output total {} to {total}",
+                    row.client, row.total
+                );
+            }
+            row.total = total;
+        }
+        if self.balance_extremes {
+            let (min, max) = client.available_extremes();
+            row.available_min = Some(self.value(min)?);
+            row.available_max = Some(self.value(max)?);
+        }
+        if row.held < Money::ZERO {
+            match self.held_negative {
+                HeldNegative::Show => {}
+                HeldNegative::Clamp => {
+                    warn!("Client #{}: showing held {} as zero", row.client, row.held);
+                    row.held = Money::ZERO;
+                }
+                HeldNegative::Error => {
+                    return Err(anyhow!(
+                        "Client #{}: negative held {}",
+                        row.client,
+                        row.held
+                    ))
+                }
+            }
+        }
+        if self.clamp_negative && row.locked {
+            for (name, balance) in [
+                ("available", &mut row.available),
+                ("held", &mut row.held),
+                ("total", &mut row.total),
+            ] {
+                if *balance < Money::ZERO {
+                    warn!("Client #{}: showing {name} {balance} as zero", row.client);
+                    *balance = Money::ZERO;
+                }
+            }
+        }
+        Ok(row)
+    }
+
+    /// Columns of the rows in output order, `open_disputes` if the rows carry the count and
+    /// `disputed_held` if they carry the amount as well.
+    pub fn schema(&self, open_disputes: bool, disputed_held: bool) -> Vec<Column> {
+        let balance = |name| Column {
+            name,
+            kind: "decimal",
+            precision: Some(self.precision),
+            nullable: false,
+        };
+        let mut columns = vec![
+            Column {
+                name: "client",
+                kind: "integer",
+                precision: None,
+                nullable: false,
+            },
+            balance("available"),
+            balance("held"),
+            balance("total"),
+            Column {
+                name: "locked",
+                kind: "boolean",
+                precision: None,
+                nullable: false,
+            },
+        ];
+        if self.balance_extremes {
+            columns.extend([balance("available_min"), balance("available_max")]);
+        }
+        if open_disputes {
+            columns.push(Column {
+                name: "open_disputes",
+                kind: "integer",
+                precision: None,
+                nullable: false,
+            });
+        }
+        if disputed_held {
+            columns.push(balance("disputed_held"));
+        }
+        columns
+    }
+
+    /// Formats the rows, which are then serialized at this precision on the current thread.
+    pub fn rows<'a>(
+        &self,
+        clients: impl IntoIterator<Item = &'a Client>,
+    ) -> Result<Vec<ClientRow>> {
+        WHOLE_BALANCES.with(|whole| whole.set(self.precision == 0));
+        clients.into_iter().map(|client| self.row(client)).collect()
+    }
+}
+
+thread_local! {
+    /// Whether balances are serialized without a fractional part, see [`Format::rows`].
+    static WHOLE_BALANCES: Cell<bool> = const { Cell::new(false) };
+    /// How balances are serialized while a [`JsonSink`] writes them.
+    static JSON_BALANCES: Cell<Option<JsonBalances>> = const { Cell::new(None) };
+}
+
+#[derive(Debug, Clone, Copy)]
+struct JsonBalances {
+    precision: u32,
+    /// Quoted rather than bare numbers.
+    strings: bool,
+}
+
+/// A client as written to the output, with balances already formatted.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ClientRow {
+    /// Named like the input column, `id` is still read from outputs of older versions.
+    #[serde(alias = "id")]
+    pub client: u16,
+    #[serde(serialize_with = "serialize_balance")]
+    pub available: Money,
+    #[serde(serialize_with = "serialize_balance")]
+    pub held: Money,
+    #[serde(serialize_with = "serialize_balance")]
+    pub total: Money,
+    pub locked: bool,
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none",
+        serialize_with = "serialize_optional_balance"
+    )]
+    pub available_min: Option<Money>,
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none",
+        serialize_with = "serialize_optional_balance"
+    )]
+    pub available_max: Option<Money>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub open_disputes: Option<u64>,
+    /// Funds held by the open disputes, matching `held` unless a resolve or chargeback fell
+    /// short of them.
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none",
+        serialize_with = "serialize_optional_balance"
+    )]
+    pub disputed_held: Option<Money>,
+}
+
+/// An output column as described by [`Format::schema`].
+#[derive(Debug, Clone, Serialize)]
+pub struct Column {
+    pub name: &'static str,
+    /// `integer`, `decimal` or `boolean`.
+    #[serde(rename = "type")]
+    pub kind: &'static str,
+    /// Most decimal places of a `decimal` column.
+    pub precision: Option<u32>,
+    pub nullable: bool,
+}
+
+/// Writes `3` rather than `3.0` at precision `0`, and JSON balances with every decimal place.
+fn serialize_balance<S: Serializer>(balance: &Money, s: S) -> Result<S::Ok, S::Error> {
+    if let Some(json) = JSON_BALANCES.with(Cell::get) {
+        let text = format!("{balance:.0$}", json.precision as usize);
+        if json.strings {
+            return s.serialize_str(&text);
+        }
+        // Written as it is, a number through `f64` would lose digits.
+        return RawValue::from_string(text)
+            .map_err(S::Error::custom)?
+            .serialize(s);
+    }
+    if WHOLE_BALANCES.with(Cell::get) {
+        s.collect_str(&format_args!("{balance:.0}"))
+    } else {
+        balance.serialize(s)
+    }
+}
+
+fn serialize_optional_balance<S: Serializer>(
+    balance: &Option<Money>,
+    s: S,
+) -> Result<S::Ok, S::Error> {
+    match balance {
+        Some(balance) => serialize_balance(balance, s),
+        None => s.serialize_none(),
+    }
+}
+
+fn serialized_rows(rows: &[ClientRow]) -> Result<BTreeMap<u16, String>> {
+    rows.iter()
+        .map(|row| {
+            let mut writer = Writer::from_writer(Vec::new());
+            writer.serialize(row)?;
+            let line = String::from_utf8(writer.into_inner()?)?;
+            // The first line is the header.
+            let line = line.lines().nth(1).unwrap_or_default().to_owned();
+            Ok((row.client, line))
+        })
+        .collect()
+}
+
+/// Compares rows against an expected output file, returning a line per difference.
+pub fn verify(rows: &[ClientRow], expected: &Path) -> Result<Vec<String>> {
+    let mut reader = Reader::from_path(expected)
+        .with_context(|| format!("can't open expected output {}", expected.display()))?;
+    let expected = reader
+        .deserialize::<ClientRow>()
+        .collect::<Result<Vec<_>, _>>()?;
+    let expected = serialized_rows(&expected)?;
+    let actual = serialized_rows(rows)?;
+
+    let mut differences = Vec::new();
+    for (id, row) in &actual {
+        match expected.get(id) {
+            Some(expected) if expected == row => {}
+            Some(expected) => {
+                differences.push(format!("client #{id}: expected {expected}, got {row}"))
+            }
+            None => differences.push(format!("client #{id}: unexpected {row}")),
+        }
+    }
+    for (id, row) in &expected {
+        if !actual.contains_key(id) {
+            differences.push(format!("client #{id}: missing {row}"));
+        }
+    }
+    Ok(differences)
+}
+
+/// Compares two output files client by client, returning a line per client whose fields differ
+/// or which only one of them has.
+///
+/// Balances are compared as amounts, so `1.5` and `1.50` are equal.
+pub fn diff(old: &Path, new: &Path) -> Result<Vec<String>> {
+    let read = |path: &Path| -> Result<BTreeMap<u16, ClientRow>> {
+        let mut reader =
+            Reader::from_path(path).with_context(|| format!("can't open {}", path.display()))?;
+        reader
+            .deserialize::<ClientRow>()
+            .map(|row| row.map(|row| (row.client, row)))
+            .collect::<Result<_, _>>()
+            .with_context(|| format!("invalid output row in {}", path.display()))
+    };
+    let (old, new) = (read(old)?, read(new)?);
+    let mut ids = old.keys().chain(new.keys()).copied().collect::<Vec<_>>();
+    ids.sort_unstable();
+    ids.dedup();
+    let mut differences = Vec::new();
+    for id in ids {
+        match (old.get(&id), new.get(&id)) {
+            (Some(old), Some(new)) => {
+                let changes = changed_fields(old, new);
+                if !changes.is_empty() {
+                    differences.push(format!("client #{id}: {}", changes.join(", ")));
+                }
+            }
+            (Some(row), None) => {
+                let row = &serialized_rows(std::slice::from_ref(row))?[&id];
+                differences.push(format!("client #{id}: removed {row}"));
+            }
+            (None, Some(row)) => {
+                let row = &serialized_rows(std::slice::from_ref(row))?[&id];
+                differences.push(format!("client #{id}: added {row}"));
+            }
+            (None, None) => unreachable!("ids come from either file"),
+        }
+    }
+    Ok(differences)
+}
+
+/// `field old -> new` for every field of a client which differs.
+fn changed_fields(old: &ClientRow, new: &ClientRow) -> Vec<String> {
+    let optional = |value: Option<String>| value.unwrap_or_else(|| "none".to_owned());
+    let text = |value: Option<Money>| optional(value.map(|value| value.to_string()));
+    let fields = [
+        ("available", Some(old.available), Some(new.available)),
+        ("held", Some(old.held), Some(new.held)),
+        ("total", Some(old.total), Some(new.total)),
+        ("available_min", old.available_min, new.available_min),
+        ("available_max", old.available_max, new.available_max),
+        ("disputed_held", old.disputed_held, new.disputed_held),
+    ];
+    let mut changes = fields
+        .into_iter()
+        .filter(|(_, old, new)| old != new)
+        .map(|(field, old, new)| format!("{field} {} -> {}", text(old), text(new)))
+        .collect::<Vec<_>>();
+    if old.locked != new.locked {
+        changes.push(format!("locked {} -> {}", old.locked, new.locked));
+    }
+    if old.open_disputes != new.open_disputes {
+        let count = |count: Option<u64>| optional(count.map(|count| count.to_string()));
+        changes.push(format!(
+            "open_disputes {} -> {}",
+            count(old.open_disputes),
+            count(new.open_disputes)
+        ));
+    }
+    changes
+}
+
+/// Writes clients into `path`-derived parts (`out.001.csv`, ...) of at most `max_bytes` each.
+///
+/// Every part starts with the header and rows are never split, so a part only exceeds the cap
+/// when a single row doesn't fit.
+pub fn write_rotated(
+    path: &Path,
+    rows: &[ClientRow],
+    max_bytes: u64,
+    fsync: bool,
+) -> Result<Vec<PathBuf>> {
+    let finish = |(file, _): (File, u64)| if fsync { file.sync_all() } else { Ok(()) };
+    let mut parts = Vec::new();
+    let mut part: Option<(File, u64)> = None;
+    for row in rows {
+        let mut writer = Writer::from_writer(Vec::new());
+        writer.serialize(row)?;
+        let bytes = writer.into_inner()?;
+        let split = bytes.iter().position(|b| *b == b'\n').map_or(0, |i| i + 1);
+        let (header, row) = bytes.split_at(split);
+
+        let full = part.as_ref().is_some_and(|(_, size)| {
+            size + row.len() as u64 > max_bytes && *size > header.len() as u64
+        });
+        if part.is_none() || full {
+            part.take().map(finish).transpose()?;
+            let part_path = part_path(path, parts.len() + 1);
+            let mut file = File::create(&part_path)
+                .with_context(|| format!("can't create {}", part_path.display()))?;
+            file.write_all(header)?;
+            parts.push(part_path);
+            part = Some((file, header.len() as u64));
+        }
+        let (file, size) = part.as_mut().expect("part is open");
+        file.write_all(row)?;
+        *size += row.len() as u64;
+    }
+    part.map(finish).transpose()?;
+    Ok(parts)
+}
+
+fn part_path(path: &Path, index: usize) -> PathBuf {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let name = match path.extension() {
+        Some(ext) => format!("{stem}.{index:03}.{}", ext.to_string_lossy()),
+        None => format!("{stem}.{index:03}"),
+    };
+    path.with_file_name(name)
+}
+
+/// Prints, per client and overall, the value discarded by rounding balances for the output.
+pub fn print_rounding_report<'a>(
+    format: &Format,
+    clients: impl IntoIterator<Item = &'a Client>,
+) -> Result<()> {
+    let loss = |x: Money| -> Result<Money> {
+        Ok(x.checked_mul(format.convert)?
+            .checked_sub(format.value(x)?)?)
+    };
+    let mut overall = [Money::ZERO; 3];
+    let mut clients = clients.into_iter().collect::<Vec<_>>();
+    clients.sort_by_key(|c| c.id());
+    for client in clients {
+        let client_loss = [
+            loss(client.available())?,
+            loss(client.held())?,
+            loss(client.total())?,
+        ];
+        eprintln!(
+            "rounding loss client #{}: available {}, held {}, total {}",
+            client.id(),
+            client_loss[0],
+            client_loss[1],
+            client_loss[2]
+        );
+        for (sum, loss) in overall.iter_mut().zip(client_loss) {
+            *sum = sum.checked_add(loss)?;
+        }
+    }
+    eprintln!(
+        "rounding loss overall: available {}, held {}, total {}",
+        overall[0], overall[1], overall[2]
+    );
+    Ok(())
+}
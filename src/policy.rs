@@ -0,0 +1,131 @@
+use std::{
+    fmt::{self, Debug},
+    ops::Deref,
+    sync::Arc,
+};
+
+use anyhow::{anyhow, Result};
+
+use crate::{client::Client, error::EngineError, money::Money, transaction::Transaction};
+
+/// Rules deciding whether a client has enough funds for an operation.
+pub trait FundsPolicy: Debug + Send + Sync {
+    /// Checks that `amount` can leave the client's available funds.
+    fn can_withdraw(&self, client: &Client, amount: Money) -> Result<()>;
+
+    /// Checks that `amount` can be released from the client's held funds.
+    fn can_release_held(&self, client: &Client, amount: Money) -> Result<()>;
+
+    /// Checks that the client may end up with `available` funds, an operation failing it is
+    /// skipped.
+    fn can_hold(&self, _client: &Client, _available: Money) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Funds can't be reduced below zero.
+#[derive(Debug, Default)]
+pub struct StrictFunds;
+
+impl FundsPolicy for StrictFunds {
+    fn can_withdraw(&self, client: &Client, amount: Money) -> Result<()> {
+        // Held funds can't be spent, but they don't reduce what is available either.
+        if client.available() < amount {
+            Err(anyhow!("Client #{}: insufficient funds", client.id()))
+        } else {
+            Ok(())
+        }
+    }
+
+    fn can_release_held(&self, client: &Client, amount: Money) -> Result<()> {
+        if client.held() < amount {
+            debug!("held: {}, amount: {amount}", client.held());
+            Err(anyhow!("Client #{}: insufficient funds held", client.id()))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// Keeps available funds within `[floor, ceiling]`, on top of another policy.
+#[derive(Debug)]
+pub struct BalanceBounds {
+    pub floor: Option<Money>,
+    pub ceiling: Option<Money>,
+    pub inner: SharedPolicy,
+}
+
+impl FundsPolicy for BalanceBounds {
+    fn can_withdraw(&self, client: &Client, amount: Money) -> Result<()> {
+        self.inner.can_withdraw(client, amount)
+    }
+
+    fn can_release_held(&self, client: &Client, amount: Money) -> Result<()> {
+        self.inner.can_release_held(client, amount)
+    }
+
+    fn can_hold(&self, client: &Client, available: Money) -> Result<()> {
+        if let Some(floor) = self.floor.filter(|floor| available < *floor) {
+            return Err(anyhow!(
+                "Client #{}: available {available} would fall below the floor {floor}",
+                client.id()
+            ));
+        }
+        if let Some(ceiling) = self.ceiling.filter(|ceiling| available > *ceiling) {
+            return Err(anyhow!(
+                "Client #{}: available {available} would exceed the ceiling {ceiling}",
+                client.id()
+            ));
+        }
+        self.inner.can_hold(client, available)
+    }
+}
+
+/// Caller supplied check every record has to pass before it is applied, see
+/// [`crate::EngineBuilder::validate`].
+#[derive(Clone)]
+pub struct Validator(Arc<ValidateFn>);
+
+type ValidateFn = dyn Fn(&Transaction) -> Result<(), EngineError> + Send + Sync;
+
+impl Validator {
+    pub fn new(
+        f: impl Fn(&Transaction) -> Result<(), EngineError> + Send + Sync + 'static,
+    ) -> Self {
+        Self(Arc::new(f))
+    }
+
+    pub fn check(&self, transaction: &Transaction) -> Result<(), EngineError> {
+        (self.0)(transaction)
+    }
+}
+
+impl Debug for Validator {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("Validator")
+    }
+}
+
+/// Shareable handle to the configured [`FundsPolicy`], [`StrictFunds`] by default.
+#[derive(Debug, Clone)]
+pub struct SharedPolicy(Arc<dyn FundsPolicy>);
+
+impl SharedPolicy {
+    pub fn new(policy: impl FundsPolicy + 'static) -> Self {
+        Self(Arc::new(policy))
+    }
+}
+
+impl Default for SharedPolicy {
+    fn default() -> Self {
+        Self::new(StrictFunds)
+    }
+}
+
+impl Deref for SharedPolicy {
+    type Target = dyn FundsPolicy;
+
+    fn deref(&self) -> &Self::Target {
+        &*self.0
+    }
+}
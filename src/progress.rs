@@ -0,0 +1,117 @@
+use std::{
+    io::{self, IsTerminal, Write},
+    time::{Duration, Instant},
+};
+
+/// Time between two redraws, on a terminal.
+const REDRAW_INTERVAL: Duration = Duration::from_millis(200);
+/// Time between two lines, when stderr is not a terminal.
+const LINE_INTERVAL: Duration = Duration::from_secs(5);
+const SPINNER: [char; 4] = ['|', '/', '-', '\\'];
+
+/// Rows read from one input and their rate, drawn on stderr.
+///
+/// With the input length known, the share of bytes read and an ETA are shown, otherwise a
+/// spinner. On a terminal the line is redrawn in place, otherwise a line is written now and
+/// then, so logs stay readable.
+#[derive(Debug)]
+pub(crate) struct Progress {
+    name: String,
+    len: Option<u64>,
+    terminal: bool,
+    start: Instant,
+    drawn: Instant,
+    rows: u64,
+    byte: u64,
+    frame: usize,
+}
+
+impl Progress {
+    pub fn new(name: String, len: Option<u64>) -> Self {
+        let now = Instant::now();
+        Self {
+            name,
+            len: len.filter(|&len| len > 0),
+            terminal: io::stderr().is_terminal(),
+            start: now,
+            drawn: now,
+            rows: 0,
+            byte: 0,
+            frame: 0,
+        }
+    }
+
+    /// Counts a row starting at `byte` of the input.
+    pub fn update(&mut self, byte: u64) {
+        self.rows += 1;
+        self.byte = byte;
+        // Checking the clock on every row would show in the run time.
+        if !self.rows.is_multiple_of(1024) {
+            return;
+        }
+        let interval = if self.terminal {
+            REDRAW_INTERVAL
+        } else {
+            LINE_INTERVAL
+        };
+        if self.drawn.elapsed() >= interval {
+            self.draw(false);
+        }
+    }
+
+    /// Draws the final counts, ending the line.
+    pub fn finish(&mut self) {
+        self.byte = self.len.unwrap_or(self.byte);
+        self.draw(true);
+    }
+
+    fn draw(&mut self, done: bool) {
+        self.drawn = Instant::now();
+        let elapsed = self.start.elapsed().as_secs_f64();
+        let rate = if elapsed > 0.0 {
+            self.rows as f64 / elapsed
+        } else {
+            0.0
+        };
+        let mut line = format!("{}: ", self.name);
+        match self.len {
+            Some(len) => {
+                let share = self.byte.min(len) as f64 / len as f64;
+                line += &format!("{:>3.0}% ", share * 100.0);
+            }
+            None if !done => {
+                line.push(SPINNER[self.frame % SPINNER.len()]);
+                line.push(' ');
+                self.frame += 1;
+            }
+            None => {}
+        }
+        line += &format!("{} rows, {rate:.0} rows/s", self.rows);
+        if let (Some(len), false) = (self.len, done) {
+            if self.byte > 0 {
+                let left = elapsed * (len.saturating_sub(self.byte) as f64 / self.byte as f64);
+                line += &format!(", ETA {}", format_duration(left));
+            }
+        } else if done {
+            line += &format!(", {}", format_duration(elapsed));
+        }
+        let mut stderr = io::stderr().lock();
+        // Progress is best effort, a closed stderr shouldn't fail the run.
+        let _ = if self.terminal {
+            write!(stderr, "\r{line}\x1b[K{}", if done { "\n" } else { "" })
+        } else {
+            writeln!(stderr, "{line}")
+        };
+        let _ = stderr.flush();
+    }
+}
+
+/// `1h02m03s`, `2m03s` or `3s`.
+fn format_duration(seconds: f64) -> String {
+    let seconds = seconds.round() as u64;
+    match (seconds / 3600, seconds / 60 % 60, seconds % 60) {
+        (0, 0, s) => format!("{s}s"),
+        (0, m, s) => format!("{m}m{s:02}s"),
+        (h, m, s) => format!("{h}h{m:02}m{s:02}s"),
+    }
+}
@@ -0,0 +1,116 @@
+use std::{
+    cmp::Reverse,
+    collections::BinaryHeap,
+    env::temp_dir,
+    fs::{create_dir_all, remove_dir_all, File},
+    path::{Path, PathBuf},
+    process,
+};
+
+use anyhow::{anyhow, Context, Result};
+use csv::{ByteRecord, Reader, ReaderBuilder, Trim, Writer};
+
+/// Default number of records sorted in memory at once.
+pub const DEFAULT_CHUNK_SIZE: usize = 1_000_000;
+
+/// Sorts a transaction CSV by tx id with bounded memory.
+///
+/// Chunks of `chunk_size` records are sorted in memory and spilled to temporary files, which are
+/// then merged. Records sharing a tx id keep their input order, so a dispute stays after its
+/// deposit.
+pub fn sort_file(input: &Path, output: &Path, chunk_size: usize) -> Result<()> {
+    let mut reader = csv_reader(input)?;
+    let headers = reader.byte_headers()?.clone();
+    let tx_column = headers
+        .iter()
+        .position(|h| h == b"tx")
+        .ok_or_else(|| anyhow!("missing tx column in {}", input.display()))?;
+
+    let dir = temp_dir().join(format!("paygine-sort-{}", process::id()));
+    create_dir_all(&dir).with_context(|| format!("can't create {}", dir.display()))?;
+    let result = sort_chunks(&mut reader, &dir, tx_column, chunk_size)
+        .and_then(|chunks| merge_chunks(&chunks, &headers, output, tx_column));
+    remove_dir_all(&dir)?;
+    result
+}
+
+fn csv_reader(path: &Path) -> Result<Reader<File>> {
+    ReaderBuilder::new()
+        .trim(Trim::All)
+        .from_path(path)
+        .with_context(|| format!("can't initialize reader from path {}", path.display()))
+}
+
+fn tx_id(record: &ByteRecord, tx_column: usize) -> Result<u32> {
+    let raw = record.get(tx_column).unwrap_or_default();
+    std::str::from_utf8(raw)?
+        .parse()
+        .with_context(|| format!("invalid tx id at {:?}", record.position()))
+}
+
+fn sort_chunks(
+    reader: &mut Reader<File>,
+    dir: &Path,
+    tx_column: usize,
+    chunk_size: usize,
+) -> Result<Vec<PathBuf>> {
+    let mut chunks = Vec::new();
+    let mut records = Vec::with_capacity(chunk_size);
+    let mut record = ByteRecord::new();
+    loop {
+        let more = reader.read_byte_record(&mut record)?;
+        if more {
+            records.push((tx_id(&record, tx_column)?, record.clone()));
+        }
+        if records.len() == chunk_size || (!more && !records.is_empty()) {
+            records.sort_by_key(|(id, _)| *id);
+            let path = dir.join(format!("{}.csv", chunks.len()));
+            let mut writer = Writer::from_path(&path)?;
+            for (_, record) in records.drain(..) {
+                writer.write_byte_record(&record)?;
+            }
+            writer.flush()?;
+            chunks.push(path);
+        }
+        if !more {
+            return Ok(chunks);
+        }
+    }
+}
+
+fn merge_chunks(
+    chunks: &[PathBuf],
+    headers: &ByteRecord,
+    output: &Path,
+    tx_column: usize,
+) -> Result<()> {
+    let mut writer =
+        Writer::from_path(output).with_context(|| format!("can't create {}", output.display()))?;
+    writer.write_byte_record(headers)?;
+
+    let mut readers = chunks
+        .iter()
+        .map(|path| {
+            ReaderBuilder::new()
+                .has_headers(false)
+                .from_path(path)
+                .map_err(Into::into)
+        })
+        .collect::<Result<Vec<_>>>()?;
+    // Ties are broken by chunk index, which preserves input order across chunks.
+    let mut heap = BinaryHeap::new();
+    let mut heads = vec![ByteRecord::new(); readers.len()];
+    for (i, reader) in readers.iter_mut().enumerate() {
+        if reader.read_byte_record(&mut heads[i])? {
+            heap.push(Reverse((tx_id(&heads[i], tx_column)?, i)));
+        }
+    }
+    while let Some(Reverse((_, i))) = heap.pop() {
+        writer.write_byte_record(&heads[i])?;
+        if readers[i].read_byte_record(&mut heads[i])? {
+            heap.push(Reverse((tx_id(&heads[i], tx_column)?, i)));
+        }
+    }
+    writer.flush()?;
+    Ok(())
+}
@@ -0,0 +1,72 @@
+use std::{
+    fs::File,
+    io::{BufReader, BufWriter, Read, Write},
+    path::Path,
+};
+
+use anyhow::{anyhow, Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    client::Client,
+    config::TxIdScope,
+    engine::{Applied, DisputeCounts, TxKey},
+    money::Money,
+};
+
+/// Leads every state file, followed by the format version.
+const MAGIC: &[u8; 8] = b"PAYGINE\0";
+const VERSION: u32 = 1;
+
+/// What a run needs to go on from where an earlier one stopped.
+///
+/// Entries are sorted, so the same state is always saved to the same bytes.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub(crate) struct State {
+    /// Keys are only comparable under the scope they were built with.
+    pub tx_id_scope: TxIdScope,
+    pub clients: Vec<Client>,
+    pub processed: Vec<(TxKey, Applied)>,
+    pub disputed: Vec<(TxKey, Applied)>,
+    pub resolved: Vec<TxKey>,
+    pub charged_back: Vec<TxKey>,
+    pub dispute_counts: Vec<(u16, DisputeCounts)>,
+    /// `tx_id_epoch` values by their index.
+    pub epochs: Vec<Vec<u8>>,
+    pub fees_collected: Money,
+}
+
+impl State {
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let file =
+            File::create(path).with_context(|| format!("can't create {}", path.display()))?;
+        let mut writer = BufWriter::new(file);
+        writer.write_all(MAGIC)?;
+        writer.write_all(&VERSION.to_le_bytes())?;
+        bincode::serialize_into(&mut writer, self)
+            .with_context(|| format!("can't write state to {}", path.display()))?;
+        writer.flush()?;
+        Ok(())
+    }
+
+    pub fn load(path: &Path) -> Result<Self> {
+        let file = File::open(path).with_context(|| format!("can't open {}", path.display()))?;
+        let mut reader = BufReader::new(file);
+        let mut header = [0; MAGIC.len() + 4];
+        reader
+            .read_exact(&mut header)
+            .with_context(|| format!("{} is not a state file", path.display()))?;
+        if &header[..MAGIC.len()] != MAGIC {
+            return Err(anyhow!("{} is not a state file", path.display()));
+        }
+        let version = u32::from_le_bytes(header[MAGIC.len()..].try_into().expect("4 bytes"));
+        if version != VERSION {
+            return Err(anyhow!(
+                "state file {} has version {version}, expected {VERSION}",
+                path.display()
+            ));
+        }
+        bincode::deserialize_from(reader)
+            .with_context(|| format!("invalid state in {}", path.display()))
+    }
+}
@@ -0,0 +1,124 @@
+//! Applying transactions from an async [`Stream`], with the balances they change as another
+//! stream.
+
+use std::collections::VecDeque;
+
+use anyhow::Error;
+use futures_util::{stream, Stream, StreamExt};
+
+use crate::{client::ClientSnapshot, engine::Engine, transaction::Transaction};
+
+/// Balances of a client right after a transaction changed them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClientUpdate {
+    /// Position of the transaction in the input stream, from 1.
+    pub line: u64,
+    pub tx: u32,
+    pub client: u16,
+    pub balances: ClientSnapshot,
+}
+
+struct State<'a, S> {
+    engine: &'a mut Engine,
+    input: S,
+    /// The changes of the last transaction not taken yet, a transfer changes two clients.
+    updates: VecDeque<(u16, ClientSnapshot)>,
+    line: u64,
+    tx: u32,
+    started: bool,
+    ended: bool,
+}
+
+impl Engine {
+    /// Applies the transactions of `input`, yielding an update for every client a transaction
+    /// changed, in order.
+    ///
+    /// Applying is sync, so the next transaction is only polled once the updates of the last
+    /// one were taken, and a slow consumer holds the input back. Other records, like a resolve
+    /// deferred until its dispute arrives, are applied as with
+    /// [`Engine::process_receiver`], only the clients named by a transaction are reported.
+    /// An error ends the stream with it, the final balances stay in the engine as with any
+    /// input.
+    ///
+    /// ```
+    /// use futures_util::{stream, StreamExt};
+    /// use paygine::{Engine, Kind, Transaction};
+    ///
+    /// # async fn example() -> anyhow::Result<()> {
+    /// let deposit = Transaction {
+    ///     kind: Kind::Deposit,
+    ///     client: 1,
+    ///     id: 1,
+    ///     amount: Some("1.5".parse()?),
+    ///     dest: None,
+    ///     batch: None,
+    ///     epoch: 0,
+    /// };
+    /// let mut engine = Engine::builder().build()?;
+    /// let mut updates = engine.process_stream(stream::iter([deposit]));
+    /// while let Some(update) = updates.next().await {
+    ///     println!("{:?}", update?);
+    /// }
+    /// drop(updates);
+    /// assert_eq!(engine.clients()[&1].available(), "1.5".parse()?);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn process_stream<'a, S>(
+        &'a mut self,
+        input: S,
+    ) -> impl Stream<Item = Result<ClientUpdate, Error>> + Unpin + 'a
+    where
+        S: Stream<Item = Transaction> + Unpin + 'a,
+    {
+        let state = State {
+            engine: self,
+            input,
+            updates: VecDeque::new(),
+            line: 0,
+            tx: 0,
+            started: false,
+            ended: false,
+        };
+        // Boxed so callers can poll it without pinning it first.
+        Box::pin(stream::unfold(Some(state), |state| async move {
+            let mut state = state?;
+            if !state.started {
+                state.started = true;
+                if let Err(e) = state.engine.start_stream() {
+                    return Some((Err(e), None));
+                }
+            }
+            loop {
+                if let Some((client, balances)) = state.updates.pop_front() {
+                    let update = ClientUpdate {
+                        line: state.line,
+                        tx: state.tx,
+                        client,
+                        balances,
+                    };
+                    return Some((Ok(update), Some(state)));
+                }
+                if state.ended {
+                    return None;
+                }
+                let outcome = match state.input.next().await {
+                    Some(transaction) => {
+                        state.line += 1;
+                        state.tx = transaction.id;
+                        state
+                            .engine
+                            .stream_record(&transaction, state.line, &mut state.updates)
+                    }
+                    None => {
+                        state.ended = true;
+                        state.engine.finish_records()
+                    }
+                };
+                if let Err(e) = outcome {
+                    return Some((Err(e), None));
+                }
+            }
+        }))
+    }
+}
@@ -1,24 +1,76 @@
-use anyhow::{anyhow, Result};
-use serde::Deserialize;
+use std::{collections::BTreeMap, fmt, fs, path::Path, str::FromStr};
+
+use anyhow::{anyhow, Context, Result};
+use csv::ByteRecord;
+use serde::{de, Deserialize, Deserializer, Serialize};
+
+use crate::{
+    error::EngineError,
+    json,
+    money::{self, Money},
+};
 
 #[derive(Debug, Deserialize, Clone)]
 pub struct Transaction {
-    #[serde(rename = "type")]
+    #[serde(rename = "type", deserialize_with = "deserialize_kind")]
     pub kind: Kind,
     pub client: u16,
     #[serde(rename = "tx")]
     pub id: u32,
-    pub amount: Option<f64>,
+    #[serde(deserialize_with = "money::deserialize_field")]
+    pub amount: Option<Money>,
+    /// Client receiving a transfer, unused by other kinds.
+    #[serde(default)]
+    pub dest: Option<u16>,
+    /// Upstream batch the record came in, for reporting only.
+    #[serde(default)]
+    pub batch: Option<String>,
+    /// Index of the `tx_id_epoch` value of the record, assigned by the engine.
+    #[serde(skip)]
+    pub epoch: u32,
 }
 
 impl Transaction {
-    pub fn get_amount(&self) -> Result<f64> {
+    pub fn get_amount(&self) -> Result<Money> {
         self.amount
             .ok_or_else(|| anyhow!("tx #{}: missing amount field", self.id))
     }
+
+    /// Amount of a deposit or withdrawal, which has to be positive.
+    ///
+    /// A zero or negative amount skips the record with a warning, a missing one stays fatal.
+    pub fn validate_amount(&self) -> Result<Money, EngineError> {
+        let amount = self
+            .amount
+            .ok_or(EngineError::MissingAmount { tx: self.id })?;
+        if amount <= Money::ZERO {
+            warn!(
+                tx = self.id, client = self.client;
+                "tx #{}: {:?} of {amount} is not positive, skipping it",
+                self.id, self.kind
+            );
+            return Err(EngineError::InvalidAmount {
+                tx: self.id,
+                amount,
+            });
+        }
+        Ok(amount)
+    }
+
+    /// Turns a deposit with a negative amount into a withdrawal of its absolute value.
+    pub fn negative_deposit_as_withdrawal(&mut self) {
+        let Kind::Deposit = self.kind else {
+            return;
+        };
+        if let Some(amount) = self.amount.filter(|amount| *amount < Money::ZERO) {
+            debug!("tx #{}: negative deposit read as a withdrawal", self.id);
+            self.kind = Kind::Withdrawal;
+            self.amount = Some(amount.abs());
+        }
+    }
 }
 
-#[derive(Debug, Deserialize, Clone, Copy)]
+#[derive(Debug, Serialize, Deserialize, Clone, Copy)]
 #[serde(rename_all = "lowercase")]
 pub enum Kind {
     Deposit,
@@ -26,4 +78,187 @@ pub enum Kind {
     Dispute,
     Resolve,
     Chargeback,
+    /// Clears the lock of the client, see [`crate::config::Config::allow_unfreeze`].
+    Unfreeze,
+    /// Moves the amount from the client to the `dest` one.
+    Transfer,
+}
+
+impl Kind {
+    /// Built-in `type` token of the kind.
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Deposit => "deposit",
+            Self::Withdrawal => "withdrawal",
+            Self::Dispute => "dispute",
+            Self::Resolve => "resolve",
+            Self::Chargeback => "chargeback",
+            Self::Unfreeze => "unfreeze",
+            Self::Transfer => "transfer",
+        }
+    }
+}
+
+impl FromStr for Kind {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "deposit" => Ok(Self::Deposit),
+            "withdrawal" => Ok(Self::Withdrawal),
+            "dispute" => Ok(Self::Dispute),
+            "resolve" => Ok(Self::Resolve),
+            "chargeback" => Ok(Self::Chargeback),
+            "unfreeze" => Ok(Self::Unfreeze),
+            "transfer" => Ok(Self::Transfer),
+            _ => Err(anyhow!("unknown tx type: {s}")),
+        }
+    }
+}
+
+/// Operator supplied translation of custom `type` tokens into [`Kind`]s.
+///
+/// Kept ordered so the map serializes the same way on every run.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct TypeMap(BTreeMap<String, Kind>);
+
+impl TypeMap {
+    /// Loads a map from a file of `token=kind` pairs separated by commas or newlines.
+    pub fn from_path(path: &Path) -> Result<Self> {
+        let content = fs::read_to_string(path)
+            .with_context(|| format!("can't read type map {}", path.display()))?;
+        content.parse()
+    }
+
+    /// Translates a `type` token through the map, then the built-in names.
+    pub fn resolve(&self, token: &str) -> Result<Kind> {
+        self.0
+            .get(token)
+            .map_or_else(|| parse_kind(token), |kind| Ok(*kind))
+    }
+
+    /// Deserializes `record` with its `type` token translated through the map.
+    ///
+    /// Records with a built-in token are deserialized in place, only mapped ones are copied.
+    pub fn deserialize(
+        &self,
+        headers: &ByteRecord,
+        record: &ByteRecord,
+    ) -> csv::Result<Transaction> {
+        let mapped = if self.0.is_empty() {
+            None
+        } else {
+            headers.iter().position(|h| h == b"type").and_then(|index| {
+                let token = std::str::from_utf8(record.get(index)?).ok()?;
+                Some((index, self.0.get(token)?))
+            })
+        };
+        let Some((index, kind)) = mapped else {
+            return record.deserialize(Some(headers));
+        };
+        let mut translated: ByteRecord = record
+            .iter()
+            .enumerate()
+            .map(|(i, field)| {
+                if i == index {
+                    kind.name().as_bytes()
+                } else {
+                    field
+                }
+            })
+            .collect();
+        translated.set_position(record.position().cloned());
+        translated.deserialize(Some(headers))
+    }
+}
+
+impl FromStr for TypeMap {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut map = BTreeMap::new();
+        for entry in s
+            .split([',', '\n'])
+            .map(str::trim)
+            .filter(|e| !e.is_empty())
+        {
+            let (token, kind) = entry
+                .split_once('=')
+                .ok_or_else(|| anyhow!("invalid type map entry: {entry}"))?;
+            map.insert(token.trim().to_owned(), kind.trim().parse()?);
+        }
+        Ok(Self(map))
+    }
+}
+
+/// Operator supplied names of input columns, renamed to the [`Transaction`] field they hold.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct HeaderAliases(BTreeMap<String, String>);
+
+impl HeaderAliases {
+    /// Renames the aliased columns of `headers`, leaving the others as they are.
+    pub fn apply(&self, headers: &ByteRecord) -> ByteRecord {
+        headers
+            .iter()
+            .map(|header| {
+                std::str::from_utf8(header)
+                    .ok()
+                    .and_then(|header| self.0.get(header))
+                    .map_or(header, |field| field.as_bytes())
+            })
+            .collect()
+    }
+}
+
+impl FromStr for HeaderAliases {
+    type Err = anyhow::Error;
+
+    /// Parses comma separated `alias=field` pairs.
+    fn from_str(s: &str) -> Result<Self> {
+        let mut aliases = BTreeMap::new();
+        for entry in s.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+            let (alias, field) = entry
+                .split_once('=')
+                .ok_or_else(|| anyhow!("invalid header alias: {entry}"))?;
+            let field = field.trim();
+            if !json::HEADERS.contains(&field) {
+                return Err(anyhow!(
+                    "unknown field {field}, expected one of {}",
+                    json::HEADERS.join(", ")
+                ));
+            }
+            aliases.insert(alias.trim().to_owned(), field.to_owned());
+        }
+        Ok(Self(aliases))
+    }
+}
+
+fn deserialize_kind<'de, D>(deserializer: D) -> Result<Kind, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    deserializer.deserialize_str(KindVisitor)
+}
+
+/// Resolves the `type` token where it is, without copying it for every record.
+struct KindVisitor;
+
+impl de::Visitor<'_> for KindVisitor {
+    type Value = Kind;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a tx type")
+    }
+
+    fn visit_str<E: de::Error>(self, v: &str) -> Result<Kind, E> {
+        parse_kind(v).map_err(E::custom)
+    }
+}
+
+/// Parses a built-in `type` token, custom ones are translated by [`TypeMap::deserialize`].
+fn parse_kind(token: &str) -> Result<Kind> {
+    if token.trim().is_empty() {
+        return Err(anyhow!("empty tx type"));
+    }
+    token.parse()
 }
@@ -0,0 +1,220 @@
+use std::{
+    fs,
+    process::{Command, Output},
+};
+
+use tempfile::TempDir;
+
+/// Runs the binary with `args` in a directory holding `input.csv`, a single deposit of 5.25.
+fn paygine(args: &[&str]) -> Output {
+    paygine_with("type,client,tx,amount\ndeposit,1,1,5.25\n", args)
+}
+
+/// Runs the binary with `args` in a directory holding `input` as `input.csv`.
+fn paygine_with(input: &str, args: &[&str]) -> Output {
+    let dir = TempDir::new().unwrap();
+    fs::write(dir.path().join("input.csv"), input).unwrap();
+    Command::new(env!("CARGO_BIN_EXE_paygine"))
+        .args(args)
+        .current_dir(dir.path())
+        .output()
+        .unwrap()
+}
+
+fn assert_usage_error(output: &Output, message: &str) {
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert_eq!(output.status.code(), Some(1), "{stderr}");
+    assert!(stderr.contains(message), "{stderr}");
+    assert!(stderr.contains("try '--help'"), "{stderr}");
+    assert!(output.stdout.is_empty());
+}
+
+#[test]
+fn positional_input_is_processed() {
+    let output = paygine(&["--quiet", "input.csv"]);
+
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        "client,available,held,total,locked\n1,5.25,0.0,5.25,false\n"
+    );
+}
+
+#[test]
+fn unknown_option_is_a_usage_error() {
+    let output = paygine(&["--sort-outptu", "input.csv"]);
+
+    assert_usage_error(&output, "--sort-outptu");
+}
+
+#[test]
+fn invalid_value_is_a_usage_error() {
+    let output = paygine(&["--jobs", "many", "input.csv"]);
+
+    assert_usage_error(&output, "--jobs");
+}
+
+#[test]
+fn byte_range_of_gzip_input_is_a_usage_error() {
+    let output = paygine(&["--byte-range", "0..10", "input.csv.gz"]);
+
+    assert_usage_error(&output, "--byte-range requires a plain CSV file");
+}
+
+#[test]
+fn byte_range_with_jobs_is_a_usage_error() {
+    let output = paygine(&["--jobs", "2", "--byte-range", "0..10", "input.csv"]);
+
+    assert_usage_error(&output, "--jobs can't be combined with --byte-range");
+}
+
+#[test]
+fn postgres_without_query_is_a_usage_error() {
+    let output = paygine(&["--postgres", "host=localhost"]);
+
+    assert_usage_error(&output, "--postgres and --query must be given together");
+}
+
+#[test]
+fn query_without_postgres_is_a_usage_error() {
+    let output = paygine(&["--query", "SELECT * FROM records"]);
+
+    assert_usage_error(&output, "--postgres and --query must be given together");
+}
+
+#[test]
+fn two_pass_over_stdin_is_a_usage_error() {
+    let output = paygine(&["--two-pass"]);
+
+    assert_usage_error(&output, "--two-pass requires CSV input files, not stdin");
+}
+
+#[test]
+fn precision_over_the_maximum_is_a_usage_error() {
+    let output = paygine(&["--precision", "29", "input.csv"]);
+
+    assert_usage_error(&output, "--precision can't exceed 28");
+}
+
+#[test]
+fn sample_rate_out_of_range_is_a_usage_error() {
+    for rate in ["--sample-rate=-0.1", "--sample-rate=1.5"] {
+        let output = paygine(&[rate, "input.csv"]);
+
+        assert_usage_error(&output, "--sample-rate must be within 0.0..1.0");
+    }
+}
+
+#[test]
+fn max_dispute_pct_out_of_range_is_a_usage_error() {
+    for pct in ["0", "101"] {
+        let output = paygine(&["--max-dispute-pct", pct, "input.csv"]);
+
+        assert_usage_error(&output, "--max-dispute-pct must be within 1..100");
+    }
+}
+
+#[test]
+fn invalid_combination_from_config_is_a_usage_error() {
+    let dir = TempDir::new().unwrap();
+    let config = dir.path().join("paygine.toml");
+    fs::write(&config, "output-compress = \"gz\"\n").unwrap();
+
+    let output = paygine(&["--config", config.to_str().unwrap(), "input.csv"]);
+
+    assert_usage_error(&output, "--output-compress requires --output");
+}
+
+#[test]
+fn flags_override_the_config_file() {
+    let dir = TempDir::new().unwrap();
+    let config = dir.path().join("paygine.toml");
+    fs::write(&config, "precision = 4\nquiet = true\n").unwrap();
+
+    let output = paygine(&[
+        "--config",
+        config.to_str().unwrap(),
+        "--precision",
+        "1",
+        "input.csv",
+    ]);
+
+    assert!(output.status.success());
+    assert!(String::from_utf8_lossy(&output.stdout).contains("1,5.2,0.0,5.2,false"));
+}
+
+#[test]
+fn sort_output_is_accepted() {
+    let output = paygine(&["--quiet", "--sort-output", "input.csv"]);
+
+    assert!(output.status.success());
+    assert!(String::from_utf8_lossy(&output.stdout).contains("1,5.25,0.0,5.25,false"));
+}
+
+#[test]
+fn duplicate_tx_id_is_counted_and_the_run_completes() {
+    let output = paygine_with(
+        "type,client,tx,amount\ndeposit,1,7,10.0\ndeposit,1,7,5.0\n",
+        &["input.csv"],
+    );
+
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert_eq!(output.status.code(), Some(2), "{stderr}");
+    assert!(
+        stderr.contains("applied: 1, skipped: 1, rejected: 1, duplicates: 1"),
+        "{stderr}"
+    );
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        "client,available,held,total,locked\n1,10.0,0.0,10.0,false\n"
+    );
+}
+
+#[test]
+fn batch_report_totals_each_batch() {
+    let dir = TempDir::new().unwrap();
+    let report = dir.path().join("batches.csv");
+
+    let output = paygine_with(
+        "type,client,tx,amount,batch\n\
+         deposit,1,1,10.0,a\n\
+         withdrawal,1,2,3.0,a\n\
+         deposit,2,3,5.5,b\n\
+         withdrawal,2,4,9.0,b\n\
+         deposit,2,5,1.25,b\n",
+        &[
+            "--quiet",
+            "--batch-report",
+            report.to_str().unwrap(),
+            "input.csv",
+        ],
+    );
+
+    assert_eq!(output.status.code(), Some(2));
+    assert_eq!(
+        fs::read_to_string(&report).unwrap(),
+        "batch,records,applied,deposits,withdrawals\na,2,2,10.0,3.0\nb,3,2,6.75,0.0\n"
+    );
+}
+
+#[test]
+fn rejected_rows_exit_with_2_and_still_write_the_output() {
+    let output = paygine_with(
+        "type,client,tx,amount\ndeposit,1,1,10.0\nwithdrawal,1,2,15.0\ndeposit,2,3,2.5\n",
+        &["--quiet", "--sort-output", "input.csv"],
+    );
+
+    assert_eq!(output.status.code(), Some(2));
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        "client,available,held,total,locked\n1,10.0,0.0,10.0,false\n2,2.5,0.0,2.5,false\n"
+    );
+}
+
+#[test]
+fn missing_input_exits_with_1() {
+    let output = paygine(&["--quiet", "missing.csv"]);
+
+    assert_eq!(output.status.code(), Some(1));
+    assert!(output.stdout.is_empty());
+}
@@ -0,0 +1,867 @@
+use std::fs;
+
+use paygine::{
+    config::{Config, DuplicateDispute, EarlySettlement, LockedPolicy},
+    money::Money,
+    Engine, EngineBuilder, EngineError, Kind, Transaction,
+};
+use rust_decimal::Decimal;
+use tempfile::TempDir;
+
+const MAPPED: &str = "type,client,tx,amount
+cr,1,1,10.0
+cr,2,2,5.0
+db,1,3,4.0
+dsp,2,2,
+cb,2,2,
+";
+
+/// Balances of every client, ordered by id.
+fn balances(engine: &Engine) -> Vec<(u16, String, String, String, bool)> {
+    let mut rows: Vec<_> = engine
+        .clients()
+        .iter()
+        .map(|(id, client)| {
+            (
+                *id,
+                client.available().to_string(),
+                client.held().to_string(),
+                client.total().to_string(),
+                client.is_locked(),
+            )
+        })
+        .collect();
+    rows.sort();
+    rows
+}
+
+fn mapped_engine(dir: &TempDir) -> Engine {
+    let input = dir.path().join("input.csv");
+    fs::write(&input, MAPPED).unwrap();
+    Engine::builder()
+        .type_map(
+            "cr=deposit,db=withdrawal,dsp=dispute,cb=chargeback"
+                .parse()
+                .unwrap(),
+        )
+        .input(input)
+        .build()
+        .unwrap()
+}
+
+#[test]
+fn type_map_translates_tokens() {
+    let dir = TempDir::new().unwrap();
+    let mut engine = mapped_engine(&dir);
+    engine.process().unwrap();
+
+    assert!(engine.rejected().is_empty());
+    assert_eq!(
+        balances(&engine),
+        [
+            (1, "6.0".into(), "0.0".into(), "6.0".into(), false),
+            (2, "0.0".into(), "0.0".into(), "0.0".into(), true),
+        ]
+    );
+}
+
+#[test]
+fn type_map_reaches_workers() {
+    let dir = TempDir::new().unwrap();
+    let mut sequential = mapped_engine(&dir);
+    sequential.process().unwrap();
+    let mut parallel = mapped_engine(&dir);
+    parallel.process_parallel(2).unwrap();
+
+    assert!(parallel.rejected().is_empty());
+    assert_eq!(balances(&parallel), balances(&sequential));
+}
+
+#[test]
+fn controls_use_type_map() {
+    let dir = TempDir::new().unwrap();
+    let controls = dir.path().join("controls.csv");
+    fs::write(&controls, "type,client,tx,amount\ndsp,1,1,\nrsv,1,1,\n").unwrap();
+    let mut engine = Engine::builder()
+        .type_map("cr=deposit,dsp=dispute,rsv=resolve".parse().unwrap())
+        .controls(&controls)
+        .build()
+        .unwrap();
+    engine
+        .process_reader("type,client,tx,amount\ncr,1,1,10.0\n".as_bytes())
+        .unwrap();
+
+    let client = &engine.clients()[&1];
+    assert_eq!(client.held().to_string(), "0.0");
+    assert_eq!(client.available().to_string(), "10.0");
+    assert_eq!(engine.stats().skipped, 0);
+    assert!(engine.rejected().is_empty());
+}
+
+#[test]
+fn full_withdrawal_leaves_exact_zero() {
+    let mut engine = Engine::builder().build().unwrap();
+    engine
+        .process_reader(
+            "type,client,tx,amount\ndeposit,1,1,100.10\nwithdrawal,1,2,100.10\n".as_bytes(),
+        )
+        .unwrap();
+
+    let client = &engine.clients()[&1];
+    assert!(engine.rejected().is_empty());
+    assert_eq!(client.available().decimal(), Decimal::ZERO);
+    assert_eq!(client.total().decimal(), Decimal::ZERO);
+    assert_eq!(client.available().to_string(), "0.0");
+}
+
+fn run(config: Config, input: &str) -> Engine {
+    let mut engine = EngineBuilder::from(config).build().unwrap();
+    engine
+        .process_reader(format!("type,client,tx,amount\n{input}").as_bytes())
+        .unwrap();
+    engine
+}
+
+fn funds(engine: &Engine, id: u16) -> (String, String) {
+    let client = &engine.clients()[&id];
+    (client.available().to_string(), client.held().to_string())
+}
+
+#[test]
+fn held_funds_dont_block_withdrawing_available() {
+    let engine = run(
+        Config::default(),
+        "deposit,1,1,10.0\ndispute,1,1,4.0\nwithdrawal,1,2,6.0\n",
+    );
+
+    assert!(engine.rejected().is_empty());
+    let client = &engine.clients()[&1];
+    assert_eq!(client.available().decimal(), Decimal::ZERO);
+    assert_eq!(client.held().to_string(), "4.0");
+    assert_eq!(client.total().to_string(), "4.0");
+}
+
+#[test]
+fn resolve_before_dispute_is_deferred() {
+    let engine = run(
+        Config::default(),
+        "resolve,1,1,\ndeposit,1,1,10.0\ndispute,1,1,\n",
+    );
+
+    assert!(engine.rejected().is_empty());
+    assert_eq!(engine.stats().applied, 3);
+    assert_eq!(funds(&engine, 1), ("10.0".into(), "0.0".into()));
+}
+
+#[test]
+fn resolve_of_known_tx_before_dispute_is_rejected() {
+    let engine = run(
+        Config {
+            early_settlement: EarlySettlement::Defer,
+            ..Config::default()
+        },
+        "deposit,1,1,10.0\nresolve,1,1,\ndispute,1,1,\n",
+    );
+
+    let rejections = rejections(&engine);
+    assert_eq!(rejections.len(), 1);
+    assert_eq!(rejections[0].0, 3);
+    assert!(
+        rejections[0].1.contains("without an open dispute"),
+        "{rejections:?}"
+    );
+    // The dispute after it still holds, the rejection is what reports the ordering.
+    assert_eq!(engine.stats().applied, 2);
+    assert_eq!(funds(&engine, 1), ("0.0".into(), "10.0".into()));
+}
+
+#[test]
+fn chargeback_before_dispute_is_deferred() {
+    let engine = run(
+        Config::default(),
+        "chargeback,1,1,\ndeposit,1,1,10.0\ndispute,1,1,\n",
+    );
+
+    assert_eq!(engine.stats().applied, 3);
+    assert_eq!(funds(&engine, 1), ("0.0".into(), "0.0".into()));
+    assert!(engine.clients()[&1].is_locked());
+}
+
+#[test]
+fn settlement_of_undisputed_tx_is_rejected() {
+    let engine = run(
+        Config::default(),
+        "deposit,1,1,10.0\nresolve,1,1,\nchargeback,1,1,\ndispute,1,1,\n",
+    );
+
+    let stats = engine.stats();
+    assert_eq!((stats.applied, stats.skipped), (2, 2));
+    assert_eq!(engine.rejected().len(), 2);
+    assert_eq!(funds(&engine, 1), ("0.0".into(), "10.0".into()));
+}
+
+#[test]
+fn early_settlement_reject_policy() {
+    let config = Config {
+        early_settlement: EarlySettlement::Reject,
+        ..Config::default()
+    };
+    let engine = run(config, "resolve,1,1,\ndeposit,1,1,10.0\ndispute,1,1,\n");
+
+    assert_eq!(engine.rejected().len(), 1);
+    assert_eq!(engine.rejected()[0].line, 2);
+    assert_eq!(funds(&engine, 1), ("0.0".into(), "10.0".into()));
+}
+
+#[test]
+fn unmatched_deferred_settlement_is_rejected() {
+    let engine = run(Config::default(), "resolve,1,1,\ndeposit,1,1,10.0\n");
+
+    let stats = engine.stats();
+    assert_eq!((stats.records, stats.applied, stats.skipped), (2, 1, 1));
+    assert_eq!(engine.rejected().len(), 1);
+    assert_eq!(funds(&engine, 1), ("10.0".into(), "0.0".into()));
+}
+
+const FIRST_CHUNK: &str = "deposit,1,1,1.0
+deposit,1,2,2.0
+deposit,1,3,3.0
+deposit,2,4,4.0
+dispute,2,4,
+chargeback,2,4,
+dispute,1,3,
+resolve,1,3,
+dispute,1,2,
+";
+
+#[test]
+fn compacted_state_resumes() {
+    let dir = TempDir::new().unwrap();
+    let full = dir.path().join("full.state");
+    let compacted = dir.path().join("compacted.state");
+    let first = run(Config::default(), FIRST_CHUNK);
+    first.save_state(&full).unwrap();
+    let first = run(
+        Config {
+            state_retention: Some(2),
+            ..Config::default()
+        },
+        FIRST_CHUNK,
+    );
+    first.save_state(&compacted).unwrap();
+    assert!(fs::metadata(&compacted).unwrap().len() < fs::metadata(&full).unwrap().len());
+
+    let config = Config {
+        load_state: Some(compacted),
+        ..Config::default()
+    };
+    let second = run(
+        config,
+        "resolve,1,2,\ndispute,1,3,\nresolve,1,3,\ndeposit,1,4,1.0\ndispute,1,1,\n",
+    );
+
+    assert_eq!(second.stats().applied, 3);
+    let rejected: Vec<_> = second.rejected().iter().map(|r| r.line).collect();
+    // The reused id of the charged back tx, then the tx outside of the retention window.
+    assert_eq!(rejected, [5, 6]);
+    assert_eq!(funds(&second, 1), ("6.0".into(), "0.0".into()));
+    assert!(second.clients()[&2].is_locked());
+}
+
+#[test]
+fn compaction_keeps_results() {
+    let dir = TempDir::new().unwrap();
+    let state = dir.path().join("chunk.state");
+    let rest = "dispute,1,1,\nchargeback,1,1,\ndispute,2,4,\nresolve,1,3,\n";
+    run(Config::default(), FIRST_CHUNK)
+        .save_state(&state)
+        .unwrap();
+    let config = Config {
+        load_state: Some(state),
+        ..Config::default()
+    };
+    let resumed = run(config, rest);
+    let whole = run(Config::default(), &format!("{FIRST_CHUNK}{rest}"));
+
+    assert_eq!(balances(&resumed), balances(&whole));
+    assert_eq!(resumed.rejected().len(), whole.rejected().len());
+}
+
+#[test]
+fn resolved_disputes_survive_the_state() {
+    let dir = TempDir::new().unwrap();
+    let state = dir.path().join("chunk.state");
+    run(Config::default(), FIRST_CHUNK)
+        .save_state(&state)
+        .unwrap();
+    let config = Config {
+        load_state: Some(state),
+        ..Config::default()
+    };
+    let resumed = run(config, "resolve,1,3,\ndispute,1,3,\nresolve,1,3,\n");
+
+    let rejections = rejections(&resumed);
+    assert_eq!(rejections.len(), 1, "{rejections:?}");
+    assert_eq!(rejections[0].0, 2);
+    assert!(
+        rejections[0].1.contains("already resolved dispute"),
+        "{rejections:?}"
+    );
+    // Disputed again, tx 3 is settled normally.
+    assert_eq!(resumed.stats().applied, 2);
+}
+
+#[test]
+fn dispute_over_the_tx_amount_is_rejected() {
+    let engine = run(Config::default(), "deposit,1,1,10.0\ndispute,1,1,15.0\n");
+
+    assert_eq!(engine.rejected().len(), 1);
+    assert!(
+        engine.rejected()[0].reason.contains("100% of the tx"),
+        "{}",
+        engine.rejected()[0].reason
+    );
+    assert_eq!(funds(&engine, 1), ("10.0".into(), "0.0".into()));
+}
+
+#[test]
+fn max_dispute_pct_bounds_partial_disputes() {
+    let config = Config {
+        max_dispute_pct: Some(50),
+        duplicate_dispute: DuplicateDispute::Accumulate,
+        ..Config::default()
+    };
+    let engine = run(
+        config,
+        "deposit,1,1,10.0\ndispute,1,1,6.0\ndispute,1,1,4.0\ndispute,1,1,1.5\n",
+    );
+
+    let rejected: Vec<_> = engine.rejected().iter().map(|r| r.line).collect();
+    assert_eq!(rejected, [3, 5]);
+    assert_eq!(funds(&engine, 1), ("6.0".into(), "4.0".into()));
+}
+
+/// Line and reason of every rejected record.
+fn rejections(engine: &Engine) -> Vec<(u64, String)> {
+    engine
+        .rejected()
+        .iter()
+        .map(|rejection| (rejection.line, rejection.reason.clone()))
+        .collect()
+}
+
+#[test]
+fn lock_transitions() {
+    let config = Config {
+        allow_unfreeze: true,
+        ..Config::default()
+    };
+    let engine = run(
+        config,
+        "deposit,1,1,10.0
+unfreeze,1,2,
+unfreeze,9,3,
+dispute,1,1,
+chargeback,1,1,
+chargeback,1,1,
+unfreeze,1,4,
+unfreeze,1,5,
+deposit,1,6,3.0
+",
+    );
+
+    assert_eq!(
+        rejections(&engine),
+        [
+            (3, "Unfreeze not applied: Client #1: is not locked".into()),
+            (4, "Unfreeze not applied: Client #9: is not locked".into()),
+            (7, "Chargeback not applied: Client #1: is locked".into()),
+            (9, "Unfreeze not applied: Client #1: is not locked".into()),
+        ]
+    );
+    assert_eq!(funds(&engine, 1), ("3.0".into(), "0.0".into()));
+    assert!(!engine.clients()[&1].is_locked());
+    assert!(!engine.clients().contains_key(&9));
+}
+
+#[test]
+fn unfreeze_is_rejected_unless_allowed() {
+    let engine = run(
+        Config::default(),
+        "deposit,1,1,10.0\ndispute,1,1,\nchargeback,1,1,\nunfreeze,1,2,\n",
+    );
+
+    assert_eq!(
+        rejections(&engine),
+        [(
+            5,
+            "Unfreeze not applied: tx #2: unfreeze is not allowed".into()
+        )]
+    );
+    assert!(engine.clients()[&1].is_locked());
+}
+
+fn tx(kind: Kind, id: u32, amount: Option<&str>) -> Transaction {
+    Transaction {
+        kind,
+        client: 1,
+        id,
+        amount: amount.map(|amount| amount.parse().unwrap()),
+        dest: None,
+        batch: None,
+        epoch: 0,
+    }
+}
+
+/// Available, held and total of client 1 and whether it is locked.
+fn snapshot(engine: &Engine) -> (String, String, String, bool) {
+    let client = engine.client_snapshot(1).unwrap();
+    (
+        client.available.to_string(),
+        client.held.to_string(),
+        client.total.to_string(),
+        client.locked,
+    )
+}
+
+#[test]
+fn unfrozen_client_disputes_new_txs_only() {
+    let dir = TempDir::new().unwrap();
+    let state = dir.path().join("unfrozen.state");
+    let config = Config {
+        allow_unfreeze: true,
+        ..Config::default()
+    };
+    let mut engine = EngineBuilder::from(config.clone()).build().unwrap();
+    let balances = |available: &str, held: &str, total: &str, locked| {
+        (available.into(), held.into(), total.into(), locked)
+    };
+
+    engine.step(&tx(Kind::Deposit, 1, Some("10.0"))).unwrap();
+    engine.step(&tx(Kind::Deposit, 2, Some("4.0"))).unwrap();
+    engine.step(&tx(Kind::Dispute, 1, None)).unwrap();
+    assert_eq!(snapshot(&engine), balances("4.0", "10.0", "14.0", false));
+    engine.step(&tx(Kind::Chargeback, 1, None)).unwrap();
+    assert_eq!(snapshot(&engine), balances("4.0", "0.0", "4.0", true));
+
+    engine.step(&tx(Kind::Unfreeze, 3, None)).unwrap();
+    assert_eq!(snapshot(&engine), balances("4.0", "0.0", "4.0", false));
+    // The charged back tx stays settled and its id taken.
+    assert!(engine.step(&tx(Kind::Dispute, 1, None)).is_err());
+    assert!(engine.step(&tx(Kind::Deposit, 1, Some("1.0"))).is_err());
+    assert_eq!(snapshot(&engine), balances("4.0", "0.0", "4.0", false));
+
+    engine.step(&tx(Kind::Deposit, 4, Some("6.0"))).unwrap();
+    assert_eq!(snapshot(&engine), balances("10.0", "0.0", "10.0", false));
+    engine.step(&tx(Kind::Dispute, 4, None)).unwrap();
+    assert_eq!(snapshot(&engine), balances("4.0", "6.0", "10.0", false));
+    engine.step(&tx(Kind::Resolve, 4, None)).unwrap();
+    assert_eq!(snapshot(&engine), balances("10.0", "0.0", "10.0", false));
+    // A tx from before the lock is still disputable.
+    engine.step(&tx(Kind::Dispute, 2, None)).unwrap();
+    assert_eq!(snapshot(&engine), balances("6.0", "4.0", "10.0", false));
+    engine.step(&tx(Kind::Resolve, 2, None)).unwrap();
+    assert_eq!(snapshot(&engine), balances("10.0", "0.0", "10.0", false));
+
+    // The charged back set survives a saved state as well.
+    engine.save_state(&state).unwrap();
+    let mut resumed = EngineBuilder::from(Config {
+        load_state: Some(state),
+        ..config
+    })
+    .build()
+    .unwrap();
+    assert_eq!(snapshot(&resumed), balances("10.0", "0.0", "10.0", false));
+    assert!(resumed.step(&tx(Kind::Dispute, 1, None)).is_err());
+    assert!(resumed.step(&tx(Kind::Deposit, 1, Some("1.0"))).is_err());
+    resumed.step(&tx(Kind::Dispute, 4, None)).unwrap();
+    assert_eq!(snapshot(&resumed), balances("4.0", "6.0", "10.0", false));
+}
+
+#[test]
+fn duplicate_tx_id_is_skipped() {
+    let mut engine = EngineBuilder::from(Config::default()).build().unwrap();
+    let result = engine.process_reader(
+        "type,client,tx,amount\ndeposit,1,7,10.0\ndeposit,1,7,5.0\nwithdrawal,2,7,1.0\ndeposit,1,8,1.0\n"
+            .as_bytes(),
+    );
+
+    assert!(result.is_ok());
+    assert_eq!(funds(&engine, 1), ("11.0".into(), "0.0".into()));
+    assert!(!engine.clients().contains_key(&2));
+    let stats = engine.stats();
+    assert_eq!((stats.applied, stats.duplicates), (2, 2));
+    assert_eq!(
+        engine.rejected().iter().map(|r| r.line).collect::<Vec<_>>(),
+        [3, 4]
+    );
+}
+
+#[test]
+fn uncovered_withdrawal_is_skipped() {
+    let engine = run(
+        Config::default(),
+        "deposit,1,1,10\nwithdrawal,1,2,50\ndeposit,1,3,1\n",
+    );
+
+    assert_eq!(engine.stats().applied, 2);
+    assert_eq!(engine.rejected().len(), 1);
+    assert_eq!(engine.rejected()[0].line, 3);
+    assert_eq!(funds(&engine, 1), ("11.0".into(), "0.0".into()));
+}
+
+/// Runs `input` after a header with the `dest` column of transfers.
+fn run_transfers(input: &str) -> Engine {
+    let mut engine = EngineBuilder::from(Config::default()).build().unwrap();
+    engine
+        .process_reader(format!("type,client,tx,amount,dest\n{input}").as_bytes())
+        .unwrap();
+    engine
+}
+
+#[test]
+fn transfer_moves_funds() {
+    let engine = run_transfers("deposit,1,1,10.0,\ndeposit,2,2,1.0,\ntransfer,1,3,2.5,2\n");
+
+    assert!(engine.rejected().is_empty());
+    assert_eq!(funds(&engine, 1), ("7.5".into(), "0.0".into()));
+    assert_eq!(funds(&engine, 2), ("3.5".into(), "0.0".into()));
+    assert_eq!(engine.clients()[&1].total().to_string(), "7.5");
+    assert_eq!(engine.clients()[&2].total().to_string(), "3.5");
+}
+
+#[test]
+fn uncovered_transfer_changes_neither_side() {
+    let engine = run_transfers(
+        "deposit,1,1,10.0,\ndeposit,2,2,1.0,\ntransfer,1,3,12.0,2\ntransfer,5,4,1.0,2\ndeposit,1,5,1.0,\n",
+    );
+
+    let rejected: Vec<_> = engine.rejected().iter().map(|r| r.line).collect();
+    assert_eq!(rejected, [4, 5]);
+    assert_eq!(engine.stats().applied, 3);
+    assert_eq!(funds(&engine, 1), ("11.0".into(), "0.0".into()));
+    assert_eq!(funds(&engine, 2), ("1.0".into(), "0.0".into()));
+    // An unknown source isn't created on the way either.
+    assert!(!engine.clients().contains_key(&5));
+}
+
+#[test]
+fn transfer_creates_the_dest_client() {
+    let engine = run_transfers("deposit,1,1,10.0,\ntransfer,1,2,4.0,9\n");
+
+    assert!(engine.rejected().is_empty());
+    assert_eq!(funds(&engine, 1), ("6.0".into(), "0.0".into()));
+    assert_eq!(funds(&engine, 9), ("4.0".into(), "0.0".into()));
+    assert!(!engine.clients()[&9].is_locked());
+}
+
+#[test]
+fn non_positive_and_non_finite_amounts_are_rejected() {
+    let engine = run(
+        Config::default(),
+        "deposit,1,1,10.0
+deposit,1,2,-5
+deposit,1,3,0
+withdrawal,1,4,NaN
+deposit,1,5,inf
+withdrawal,1,6,-inf
+",
+    );
+
+    let rejections = rejections(&engine);
+    let lines: Vec<_> = rejections.iter().map(|(line, _)| *line).collect();
+    assert_eq!(lines, [3, 4, 5, 6, 7]);
+    assert!(rejections[0].1.contains("amount -5.0 is not positive"));
+    assert!(rejections[1].1.contains("amount 0.0 is not positive"));
+    assert!(rejections[2].1.contains("invalid amount NaN"));
+    assert!(rejections[3].1.contains("invalid amount inf"));
+    assert_eq!(engine.stats().applied, 1);
+    assert_eq!(funds(&engine, 1), ("10.0".into(), "0.0".into()));
+    assert_eq!(engine.clients()[&1].total().to_string(), "10.0");
+}
+
+/// Available, held and total of a client.
+fn balance_strings(engine: &Engine, id: u16) -> (String, String, String) {
+    let client = &engine.clients()[&id];
+    (
+        client.available().to_string(),
+        client.held().to_string(),
+        client.total().to_string(),
+    )
+}
+
+#[test]
+fn disputed_withdrawal_is_resolved() {
+    let disputed = run(
+        Config::default(),
+        "deposit,1,1,10.0\nwithdrawal,1,2,4.0\ndispute,1,2,\n",
+    );
+    assert_eq!(
+        balance_strings(&disputed, 1),
+        ("6.0".into(), "4.0".into(), "10.0".into())
+    );
+
+    let resolved = run(
+        Config::default(),
+        "deposit,1,1,10.0\nwithdrawal,1,2,4.0\ndispute,1,2,\nresolve,1,2,\n",
+    );
+
+    assert!(resolved.rejected().is_empty());
+    assert_eq!(
+        balance_strings(&resolved, 1),
+        ("6.0".into(), "0.0".into(), "6.0".into())
+    );
+    assert!(!resolved.clients()[&1].is_locked());
+}
+
+#[test]
+fn disputed_withdrawal_is_charged_back() {
+    let engine = run(
+        Config::default(),
+        "deposit,1,1,10.0\nwithdrawal,1,2,4.0\ndispute,1,2,\nchargeback,1,2,\n",
+    );
+
+    assert!(engine.rejected().is_empty());
+    assert_eq!(
+        balance_strings(&engine, 1),
+        ("10.0".into(), "0.0".into(), "10.0".into())
+    );
+    assert!(engine.clients()[&1].is_locked());
+}
+
+#[test]
+fn dispute_of_a_spent_deposit_is_rejected() {
+    let engine = run(
+        Config::default(),
+        "deposit,1,1,10.0\nwithdrawal,1,2,8.0\ndispute,1,1,\n",
+    );
+
+    let rejections = rejections(&engine);
+    assert_eq!(rejections.len(), 1);
+    assert_eq!(rejections[0].0, 4);
+    assert!(
+        rejections[0].1.contains("exceeds available funds"),
+        "{rejections:?}"
+    );
+    let client = &engine.clients()[&1];
+    assert_eq!(
+        balance_strings(&engine, 1),
+        ("2.0".into(), "0.0".into(), "2.0".into())
+    );
+    assert_eq!(
+        client.available().checked_add(client.held()).unwrap(),
+        client.total()
+    );
+    assert!(!client.is_locked());
+}
+
+#[test]
+fn sharded_result_matches_sequential() {
+    let dir = TempDir::new().unwrap();
+    let input = dir.path().join("input.csv");
+    let mut csv = String::from("type,client,tx,amount\n");
+    for tx in 1..=600u32 {
+        // Disputes and their settlements target the deposit a few rows before them.
+        let record = match tx % 6 {
+            0 => format!("withdrawal,{},{tx},{}.5", tx % 23, tx % 7),
+            4 => format!("dispute,{},{},", (tx - 3) % 23, tx - 3),
+            5 if tx % 4 == 1 => format!("chargeback,{},{},", (tx - 4) % 23, tx - 4),
+            5 => format!("resolve,{},{},", (tx - 4) % 23, tx - 4),
+            _ => format!("deposit,{},{tx},{}.25", tx % 23, tx % 11),
+        };
+        csv.push_str(&record);
+        csv.push('\n');
+    }
+    fs::write(&input, csv).unwrap();
+    let engine = || Engine::builder().input(&input).build().unwrap();
+
+    let mut sequential = engine();
+    sequential.process().unwrap();
+    let mut sharded = engine();
+    sharded.process_parallel(4).unwrap();
+
+    assert_eq!(sharded.clients().len(), 23);
+    assert_eq!(balances(&sharded), balances(&sequential));
+    assert!(sequential
+        .clients()
+        .values()
+        .any(|client| client.is_locked()));
+    assert_eq!(sharded.stats().applied, sequential.stats().applied);
+    assert_eq!(sharded.rejected().len(), sequential.rejected().len());
+}
+
+#[test]
+fn checkpoint_resumes_where_the_run_stopped() {
+    let dir = TempDir::new().unwrap();
+    let state = dir.path().join("half.state");
+    let first = "deposit,1,1,10.0
+deposit,1,2,5.0
+deposit,2,3,7.0
+dispute,2,3,
+chargeback,2,3,
+unfreeze,2,4,
+dispute,1,2,
+";
+    let second = "resolve,1,2,
+dispute,1,1,
+dispute,2,3,
+deposit,3,3,1.0
+chargeback,1,1,
+";
+    let config = Config {
+        allow_unfreeze: true,
+        ..Config::default()
+    };
+    run(config.clone(), first).save_state(&state).unwrap();
+    let resumed = run(
+        Config {
+            load_state: Some(state),
+            ..config.clone()
+        },
+        second,
+    );
+    let whole = run(config, &format!("{first}{second}"));
+
+    assert_eq!(balances(&resumed), balances(&whole));
+    assert_eq!(
+        balances(&resumed),
+        [
+            (1, "5.0".into(), "0.0".into(), "5.0".into(), true),
+            (2, "0.0".into(), "0.0".into(), "0.0".into(), false),
+        ]
+    );
+    // The charged back tx can't be disputed again and its id stays taken.
+    assert_eq!(
+        rejections(&resumed),
+        [
+            (
+                4,
+                "Dispute not applied: tx #3: dispute at 4 targets a charged back tx".into()
+            ),
+            (5, "Deposit not applied: tx #3: duplicate tx index".into()),
+        ]
+    );
+    assert_eq!(whole.rejected().len(), 2);
+}
+
+#[test]
+fn settlements_without_an_open_dispute_name_their_kind_and_line() {
+    let engine = run(
+        Config::default(),
+        "deposit,1,1,10.0\nresolve,1,1,\nchargeback,1,1,\n",
+    );
+
+    assert_eq!(
+        rejections(&engine),
+        [
+            (
+                3,
+                "Resolve not applied: tx #1: Resolve at 3 targets a tx without an open dispute"
+                    .into()
+            ),
+            (
+                4,
+                "Chargeback not applied: tx #1: Chargeback at 4 targets a tx without an open dispute"
+                    .into()
+            ),
+        ]
+    );
+    assert_eq!(
+        balance_strings(&engine, 1),
+        ("10.0".into(), "0.0".into(), "10.0".into())
+    );
+    assert!(!engine.clients()[&1].is_locked());
+}
+
+#[test]
+fn deposit_past_the_maximum_is_rejected() {
+    let mut engine = Engine::builder().build().unwrap();
+    engine
+        .step(&tx(Kind::Deposit, 1, Some("79228162514264337593543950335")))
+        .unwrap();
+
+    let outcome = engine.step(&tx(Kind::Deposit, 2, Some("79228162514264337593543950000")));
+
+    assert!(
+        matches!(outcome, Err(EngineError::Overflow(_))),
+        "{outcome:?}"
+    );
+    assert_eq!(
+        snapshot(&engine),
+        (
+            "79228162514264337593543950335".into(),
+            "0.0".into(),
+            "79228162514264337593543950335".into(),
+            false
+        )
+    );
+}
+
+#[test]
+fn resolve_of_a_partial_dispute_releases_the_disputed_amount() {
+    let engine = run(
+        Config::default(),
+        "deposit,1,1,10.0\ndispute,1,1,4.0\nresolve,1,1,\n",
+    );
+
+    assert!(engine.rejected().is_empty());
+    assert_eq!(
+        balance_strings(&engine, 1),
+        ("10.0".into(), "0.0".into(), "10.0".into())
+    );
+}
+
+#[test]
+fn chargeback_of_a_partial_dispute_removes_the_disputed_amount() {
+    let engine = run(
+        Config::default(),
+        "deposit,1,1,10.0\ndispute,1,1,4.0\nchargeback,1,1,\n",
+    );
+
+    assert!(engine.rejected().is_empty());
+    assert_eq!(
+        balance_strings(&engine, 1),
+        ("6.0".into(), "0.0".into(), "6.0".into())
+    );
+    assert!(engine.clients()[&1].is_locked());
+}
+
+#[test]
+fn queued_records_apply_in_order_once_unfrozen() {
+    let config = Config {
+        locked_policy: LockedPolicy::Queue,
+        allow_unfreeze: true,
+        ..Config::default()
+    };
+    // The withdrawal is covered only once both deposits before it applied.
+    let engine = run(
+        config,
+        "deposit,1,1,10.0\ndispute,1,1,\nchargeback,1,1,\n\
+         deposit,1,2,2.0\ndeposit,1,3,3.0\nwithdrawal,1,4,4.5\nunfreeze,1,5,\n",
+    );
+
+    assert!(engine.rejected().is_empty(), "{:?}", rejections(&engine));
+    assert_eq!(engine.stats().applied, 7);
+    assert_eq!(
+        balance_strings(&engine, 1),
+        ("0.5".into(), "0.0".into(), "0.5".into())
+    );
+    assert!(!engine.clients()[&1].is_locked());
+}
+
+#[test]
+fn chargeback_fee_may_take_available_negative() {
+    let config = Config {
+        chargeback_fee: Some("0.5".parse().unwrap()),
+        balance_floor: Some(Money::ZERO),
+        ..Config::default()
+    };
+    let engine = run(config, "deposit,1,1,10.0\ndispute,1,1,\nchargeback,1,1,\n");
+
+    assert!(engine.rejected().is_empty(), "{:?}", rejections(&engine));
+    assert_eq!(
+        balance_strings(&engine, 1),
+        ("-0.5".into(), "0.0".into(), "-0.5".into())
+    );
+    assert!(engine.clients()[&1].is_locked());
+}
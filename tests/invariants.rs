@@ -0,0 +1,94 @@
+use std::collections::HashMap;
+
+use paygine::{client::ClientSnapshot, money::Money, Engine, EngineError, Kind, Transaction};
+use proptest::prelude::*;
+use rust_decimal::Decimal;
+
+/// One generated record: kind, client, a tx among the earlier ones and an amount in 1/10^4.
+type Op = (Kind, u16, prop::sample::Index, i64);
+
+fn op() -> impl Strategy<Value = Op> {
+    let kind = prop_oneof![
+        3 => Just(Kind::Deposit),
+        2 => Just(Kind::Withdrawal),
+        2 => Just(Kind::Dispute),
+        1 => Just(Kind::Resolve),
+        1 => Just(Kind::Chargeback),
+    ];
+    (
+        kind,
+        1..=4u16,
+        any::<prop::sample::Index>(),
+        1..10_000_000i64,
+    )
+}
+
+/// Turns the ops into transactions, deposits and withdrawals getting fresh tx ids and the
+/// other kinds referencing an earlier one, of any client.
+fn transactions(ops: Vec<Op>) -> Vec<Transaction> {
+    let mut ids = Vec::new();
+    ops.into_iter()
+        .map(|(kind, client, index, units)| {
+            let (id, amount) = match kind {
+                Kind::Deposit | Kind::Withdrawal => {
+                    let id = ids.len() as u32 + 1;
+                    ids.push(id);
+                    (id, Some(Money::new(Decimal::new(units, 4))))
+                }
+                _ if ids.is_empty() => (1, None),
+                _ => (*index.get(&ids), None),
+            };
+            Transaction {
+                kind,
+                client,
+                id,
+                amount,
+                dest: None,
+                batch: None,
+                epoch: 0,
+            }
+        })
+        .collect()
+}
+
+fn snapshots(engine: &Engine) -> HashMap<u16, ClientSnapshot> {
+    engine
+        .clients()
+        .keys()
+        .map(|id| (*id, engine.client_snapshot(*id).unwrap()))
+        .collect()
+}
+
+proptest! {
+    #[test]
+    fn balances_stay_consistent(ops in prop::collection::vec(op(), 1..200)) {
+        let mut engine = Engine::builder().build().unwrap();
+        for transaction in transactions(ops) {
+            let before = snapshots(&engine);
+            // Rejected records are part of the sequence, they must leave balances valid too.
+            if let Err(e) = engine.step(&transaction) {
+                prop_assert!(
+                    e.is_recoverable(),
+                    "{:?} of tx {} failed the run: {}", transaction.kind, transaction.id, e
+                );
+                prop_assert!(
+                    !matches!(e, EngineError::Fatal(_) | EngineError::ProtocolViolation { strict: true, .. }),
+                    "{:?} of tx {} ended in {:?}", transaction.kind, transaction.id, e
+                );
+            }
+            for (id, client) in snapshots(&engine) {
+                prop_assert_eq!(client.available.checked_add(client.held).unwrap(), client.total);
+                prop_assert!(client.held >= Money::ZERO, "client {} holds {}", id, client.held);
+                if !client.locked {
+                    prop_assert!(
+                        client.available >= Money::ZERO,
+                        "client {} has {} available", id, client.available
+                    );
+                }
+                if let Some(old) = before.get(&id).filter(|old| old.locked) {
+                    prop_assert_eq!(&client, old, "locked client {} changed", id);
+                }
+            }
+        }
+    }
+}
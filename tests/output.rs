@@ -0,0 +1,162 @@
+use std::{collections::BTreeMap, fs, path::Path};
+
+use paygine::{
+    money::Money,
+    output::{self, ClientRow, Format, OutputFormat},
+    Engine,
+};
+use serde_json::value::RawValue;
+use tempfile::TempDir;
+
+/// Clients with fractional, held and large balances, one of them locked.
+const INPUT: &str = "type,client,tx,amount
+deposit,1,1,1.2345
+deposit,2,2,123456789.0001
+deposit,2,3,0.5
+dispute,2,3,
+deposit,3,4,7
+dispute,3,4,
+chargeback,3,4,
+";
+
+fn engine() -> Engine {
+    let mut engine = Engine::builder().build().unwrap();
+    engine.process_reader(INPUT.as_bytes()).unwrap();
+    engine
+}
+
+/// Writes the rows of `engine` to `path` in `format`.
+fn write(engine: &Engine, path: &Path, format: &Format) -> Vec<ClientRow> {
+    let mut rows = format.rows(engine.clients().values()).unwrap();
+    rows.sort_unstable_by_key(|row| row.client);
+    let kind = OutputFormat::from_path(path).unwrap();
+    output::sink(Some(path), kind, None, None, format, false)
+        .unwrap()
+        .write(&rows)
+        .unwrap();
+    rows
+}
+
+/// `client` and balances of every JSON row, balances read from their exact text.
+fn read_json(path: &Path) -> Vec<(u16, Money, Money, Money, bool)> {
+    let json = fs::read_to_string(path).unwrap();
+    let rows: Vec<BTreeMap<String, Box<RawValue>>> = serde_json::from_str(&json).unwrap();
+    let field = |row: &BTreeMap<String, Box<RawValue>>, name: &str| {
+        row[name].get().trim_matches('"').to_owned()
+    };
+    rows.iter()
+        .map(|row| {
+            (
+                field(row, "client").parse().unwrap(),
+                field(row, "available").parse().unwrap(),
+                field(row, "held").parse().unwrap(),
+                field(row, "total").parse().unwrap(),
+                field(row, "locked").parse().unwrap(),
+            )
+        })
+        .collect()
+}
+
+/// The JSON output of a single deposit of 5.
+fn json_output(format: &Format) -> String {
+    let mut engine = Engine::builder().build().unwrap();
+    engine
+        .process_reader("type,client,tx,amount\ndeposit,1,1,5\n".as_bytes())
+        .unwrap();
+    let rows = format.rows(engine.clients().values()).unwrap();
+    let dir = TempDir::new().unwrap();
+    let path = dir.path().join("out.json");
+    output::sink(Some(&path), OutputFormat::Json, None, None, format, false)
+        .unwrap()
+        .write(&rows)
+        .unwrap();
+    fs::read_to_string(path).unwrap()
+}
+
+#[test]
+fn json_balances_are_numbers() {
+    let json = json_output(&Format::default());
+
+    assert!(json.contains(r#""available": 5.0000,"#), "{json}");
+    assert!(json.contains(r#""held": 0.0000,"#), "{json}");
+    assert!(json.contains(r#""total": 5.0000,"#), "{json}");
+    let rows: serde_json::Value = serde_json::from_str(&json).unwrap();
+    assert!(rows[0]["available"].is_number());
+}
+
+#[test]
+fn json_balances_as_strings() {
+    let format = Format {
+        json_strings: true,
+        ..Format::default()
+    };
+    let json = json_output(&format);
+
+    assert!(json.contains(r#""available": "5.0000","#), "{json}");
+    assert!(json.contains(r#""total": "5.0000","#), "{json}");
+    let rows: serde_json::Value = serde_json::from_str(&json).unwrap();
+    assert_eq!(rows[0]["available"], "5.0000");
+}
+
+#[test]
+fn csv_output_round_trips() {
+    let engine = engine();
+    let dir = TempDir::new().unwrap();
+    let path = dir.path().join("out.csv");
+    let rows = write(&engine, &path, &Format::default());
+
+    let csv = fs::read_to_string(&path).unwrap();
+    assert!(
+        csv.starts_with("client,available,held,total,locked\n"),
+        "{csv}"
+    );
+    assert_eq!(output::verify(&rows, &path).unwrap(), Vec::<String>::new());
+    assert_eq!(output::diff(&path, &path).unwrap(), Vec::<String>::new());
+    let reopened = Engine::builder().opening_balances(&path).build().unwrap();
+    assert_eq!(reopened.clients().len(), 3);
+    for (id, client) in engine.clients() {
+        assert!(
+            client.approx_eq(&reopened.clients()[id], Money::ZERO),
+            "{client:?}"
+        );
+    }
+}
+
+#[test]
+fn json_output_round_trips() {
+    let engine = engine();
+    let dir = TempDir::new().unwrap();
+    let path = dir.path().join("out.json");
+    for json_strings in [false, true] {
+        let format = Format {
+            json_strings,
+            ..Format::default()
+        };
+        let rows = write(&engine, &path, &format);
+
+        let expected = rows
+            .iter()
+            .map(|row| (row.client, row.available, row.held, row.total, row.locked))
+            .collect::<Vec<_>>();
+        assert_eq!(read_json(&path), expected);
+        assert!(fs::read_to_string(&path)
+            .unwrap()
+            .contains(r#""client": 2,"#));
+    }
+}
+
+#[test]
+fn outputs_with_an_id_column_are_still_read() {
+    let engine = engine();
+    let dir = TempDir::new().unwrap();
+    let path = dir.path().join("out.csv");
+    let rows = write(&engine, &path, &Format::default());
+    let old = dir.path().join("old.csv");
+    let csv = fs::read_to_string(&path).unwrap();
+    fs::write(&old, csv.replacen("client,", "id,", 1)).unwrap();
+
+    assert_eq!(output::verify(&rows, &old).unwrap(), Vec::<String>::new());
+    assert_eq!(output::diff(&old, &path).unwrap(), Vec::<String>::new());
+    let reopened = Engine::builder().opening_balances(&old).build().unwrap();
+    assert_eq!(reopened.clients().len(), 3);
+}